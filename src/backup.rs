@@ -8,14 +8,17 @@ use walkdir::WalkDir;
 
 use crate::datastore::DataStore;
 use crate::sqlite_cache::AsyncCache;
-use crate::bucket::Bucket;
+use crate::bucket::{Bucket, Transfer};
 use crate::{config, upload_worker, hash_worker, encryption};
 use config::BackupConfig;
-use chrono::prelude::{Utc, SecondsFormat};
+use chrono::prelude::{DateTime, Utc, SecondsFormat};
 use rand::{distributions::Alphanumeric, Rng};
 use log::{info, error};
 
+use crate::exclude::ExcludeFilter;
 use crate::filetype;
+use crate::pack::{Packer, Placement, SealedPack};
+use crate::upload_worker::UploadRequest;
 use crate::utils::humanise_bytes;
 
 async fn init_datastores(stores: Vec<DataStore>) -> Vec<(DataStore, Bucket)> {
@@ -31,7 +34,7 @@ async fn init_datastores(stores: Vec<DataStore>) -> Vec<(DataStore, Bucket)> {
     .await
 }
 
-async fn upload_metadata(key: String, filename: &PathBuf, stores: &Vec<DataStore>, multi_progress: &MultiProgress) {
+async fn upload_metadata(key: String, filename: &PathBuf, md5: &str, stores: &[DataStore], multi_progress: &MultiProgress) {
   let style =
     ProgressStyle::with_template("{prefix:.bold.dim} {spinner:.green} [{elapsed_precise}] {msg} [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}")
       .unwrap()
@@ -42,19 +45,17 @@ async fn upload_metadata(key: String, filename: &PathBuf, stores: &Vec<DataStore
       info!("Skipping metadata upload to store {} (upload_metadata = false)", store.id);
       continue;
     }
-    let metadata_file = std::fs::File::open(&filename).unwrap();
+    let metadata_file = std::fs::File::open(filename).unwrap();
 
     let pb = multi_progress.add(ProgressBar::new(metadata_file.metadata().unwrap().len()))
       .with_finish(ProgressFinish::AndLeave);
     pb.set_style(style.clone());
-    pb.set_message(format!("{}", &key));
+    pb.set_message(key.clone());
     pb.set_prefix("[Upload] ");
 
-    let callback = move |bytes: usize| {
-        pb.inc(u64::try_from(bytes).unwrap_or(0));
-        if pb.is_finished() {
-            //pb.finish_and_clear();
-        }
+    let callback = move |event: Transfer| match event {
+        Transfer::Bytes(bytes) => pb.inc(u64::try_from(bytes).unwrap_or(0)),
+        Transfer::Retry { .. } => pb.set_position(0),
     };
 
     let combined_key = format!("{}{}", store.metadata_prefix, key);
@@ -62,12 +63,73 @@ async fn upload_metadata(key: String, filename: &PathBuf, stores: &Vec<DataStore
     store
       .init()
       .await
-      .upload_with_progress(&combined_key, metadata_file, callback)
+      .upload_with_progress(&combined_key, metadata_file, Some(md5), callback)
       .await
       .unwrap();
   }
 }
 
+/// Encrypts the object of `upload_request` and uploads it to the stores that
+/// lack it.  Returns its plaintext and ciphertext sizes, or nothing if no
+/// store needs it or another file is already uploading the same content.
+async fn store_object(upload_request: UploadRequest, config: &BackupConfig, data_stores: &[DataStore], buckets: &[(DataStore, Bucket)], cache: &AsyncCache, multi_progress: &MultiProgress, dry_run: bool) -> Option<(u64, u64)> {
+  let x = upload_request.filename.clone();
+  let filename = x.to_string_lossy();
+  let requires_upload = cache.requires_upload(&upload_request.data_hash, data_stores).await.unwrap();
+  if !requires_upload.is_empty() && cache.lock_data(&upload_request.data_hash).await { // check here if it is in the database?
+    // check here if it is encrypted on the filesystem?
+    let key = Cert::from_file(&config.encrypting_key_file).unwrap();
+    let mut filtered_buckets: Vec<&(DataStore, Bucket)> = requires_upload.iter().flat_map(|bucket_id| {
+      buckets.iter().find(|b| b.0.id == *bucket_id && b.0.upload_data)
+    }).collect();
+    let mut sizes = None;
+    if config.stream_uploads && !dry_run {
+      let length = match upload_request.range {
+        Some((_, length)) => length,
+        None => std::fs::metadata(&upload_request.filename).unwrap().len(),
+      };
+      let (streamable, rest): (Vec<_>, Vec<_>) = filtered_buckets.into_iter().partition(|b| b.1.can_stream(length));
+      filtered_buckets = rest;
+      if !streamable.is_empty() {
+        let report = upload_worker::stream_upload(upload_request.clone(), &key, config.compression, &streamable, multi_progress).await;
+        cache.set_data_in_cold_storage(report.data_hash.as_str(), report.encrypted_md5.as_deref(), &report.store_ids).await.unwrap();
+        // Stores whose stream failed are sent the object from a file.
+        filtered_buckets.extend(streamable.into_iter().filter(|b| !report.store_ids.contains(&b.0.id)));
+        sizes = report.sizes;
+      }
+    }
+    if sizes.is_none() || !filtered_buckets.is_empty() {
+      let upload_request2 = upload_worker::encryption_work(&config.data_cache, upload_request, &key, config.compression, multi_progress).await;
+      sizes = sizes.or(upload_request2.sizes);
+      if !dry_run {
+        let report = upload_worker::upload(upload_request2, &filtered_buckets, multi_progress).await;
+        cache.set_data_in_cold_storage(report.data_hash.as_str(), report.encrypted_md5.as_deref(), &report.store_ids).await.unwrap();
+        std::fs::remove_file(report.filename).unwrap();
+      } else {
+        info!("Skipping upload of {}", filename);
+        std::fs::remove_file(upload_request2.filename).unwrap();
+      }
+    }
+    sizes
+  } else {
+    None
+  }
+}
+
+/// Uploads a sealed pack as the data object named by its hash and records
+/// its contents in the cache.  Returns the placements of its files, and the
+/// sizes of the pack if it was encrypted.
+async fn upload_pack(sealed: SealedPack, config: &BackupConfig, data_stores: &[DataStore], buckets: &[(DataStore, Bucket)], cache: &AsyncCache, multi_progress: &MultiProgress, dry_run: bool) -> (Vec<Placement>, Option<(u64, u64)>) {
+  info!("Uploading pack {} of {} files", sealed.pack, sealed.placements.len());
+  let upload_request = UploadRequest { filename: sealed.path.clone(), data_hash: sealed.pack.clone(), encrypted_md5: None, range: None, sizes: None };
+  let sizes = store_object(upload_request, config, data_stores, buckets, cache, multi_progress, dry_run).await;
+  std::fs::remove_file(&sealed.path).unwrap();
+  if !dry_run {
+    cache.set_packed(&sealed.pack, &sealed.members).await.unwrap();
+  }
+  (sealed.placements, sizes)
+}
+
 pub fn generate_name() -> String{
   let datetime = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
   let random_suffix: String = rand::thread_rng()
@@ -78,6 +140,12 @@ pub fn generate_name() -> String{
   format!("backup-{}-{}", datetime, random_suffix)
 }
 
+/// The time embedded in a name made by [`generate_name`], if `name` is one.
+pub fn name_timestamp(name: &str) -> Option<DateTime<Utc>> {
+  let (datetime, _suffix) = name.strip_prefix("backup-")?.rsplit_once('-')?;
+  DateTime::parse_from_rfc3339(datetime).ok().map(|t| t.with_timezone(&Utc))
+}
+
 #[derive(Default)]
 struct Stats {
   pub files: u64,
@@ -85,10 +153,23 @@ struct Stats {
   pub links: u64,
   pub directories: u64,
   pub uploaded: u64,
-  pub size: u64
+  pub size: u64,
+  /// Plaintext bytes of the data objects encrypted.
+  pub encrypted_plaintext: u64,
+  /// Bytes of those objects once compressed and encrypted.
+  pub encrypted_size: u64,
 }
 
-pub async fn run_backup(config: BackupConfig, name: String, multi_progress: MultiProgress, force_hash: bool, dry_run: bool) {
+impl Stats {
+  fn add_encrypted(&mut self, sizes: Option<(u64, u64)>) {
+    if let Some((plaintext, encrypted)) = sizes {
+      self.encrypted_plaintext += plaintext;
+      self.encrypted_size += encrypted;
+    }
+  }
+}
+
+pub async fn run_backup(config: BackupConfig, name: String, tags: &[String], multi_progress: MultiProgress, force_hash: bool, dry_run: bool) {
 
   // Only stores with upload_data=true participate in data object upload/deduplication checks.
   // Fail fast on a real run if none exist — otherwise every file would be hashed and encrypted
@@ -99,12 +180,38 @@ pub async fn run_backup(config: BackupConfig, name: String, multi_progress: Mult
     return;
   }
 
+  let sources = match config.sources() {
+    Ok(sources) => sources,
+    Err(e) => {
+      error!("{}", e);
+      return;
+    }
+  };
+  if let Some(Err(e)) = config.chunking.as_ref().map(|c| c.check()) {
+    error!("{}", e);
+    return;
+  }
+  let mut exclude_filters = Vec::new();
+  for source in &sources {
+    match ExcludeFilter::new(source, &config.exclude, &config.include) {
+      Ok(filter) => exclude_filters.push(filter),
+      Err(e) => {
+        error!("{}", e);
+        return;
+      }
+    }
+  }
+
   let cache = AsyncCache::new().await;
   cache.init().await;
-  let buckets = init_datastores(config.stores.to_vec()).await;
+  let buckets = init_datastores(config.stores.iter().map(|store| DataStore {
+    max_uploads: store.max_uploads.or(config.concurrency.uploads_per_store),
+    ..store.clone()
+  }).collect()).await;
 
   create_dir_all(config.metadata_cache.as_path()).unwrap();
   create_dir_all(config.data_cache.as_path()).unwrap();
+  let packer = config.packing.clone().map(|packing| Packer::new(packing, &config.data_cache, &config.hmac_secret));
 
   let metadata_file = {
     let metadata_filename = format!("{}.metadata.sqlite", name);
@@ -112,64 +219,96 @@ pub async fn run_backup(config: BackupConfig, name: String, multi_progress: Mult
   };
   
   let metadata_writer = crate::metadata_file::MetadataWriter::new(metadata_file.clone()).await;
+  for source in sources.iter().filter(|s| !s.root.is_empty()) {
+    metadata_writer.write_root(&source.root, &source.path.to_string_lossy()).await;
+  }
 
   let config = &config;
   let cache = &cache;
   let multi_progress = &multi_progress;
   let buckets = &buckets;
   let data_stores = &data_stores;
+  let sources = &sources;
+  let packer = &packer;
+
+  // Each object in flight holds a chunk while it is hashed and, when
+  // streamed, a buffer for every store it is sent to.
+  let object_size = config.chunking.as_ref().map_or(0, |c| u64::from(c.max_size))
+    + if config.stream_uploads { upload_worker::STREAM_BUFFER_SIZE * data_stores.len() as u64 } else { 0 };
+  let files_at_once = config.concurrency.backup_files(object_size);
+  let chunks_at_once = config.concurrency.chunks();
 
   use futures::StreamExt;
-  let directory_stream: futures::stream::Iter<walkdir::IntoIter> = futures::stream::iter(WalkDir::new(&config.source));
+  // The sources are walked one after the other, numbering entries across all
+  // of them.  Excluded directories are pruned here, so nothing below them is
+  // visited.
+  let walk = exclude_filters.into_iter().enumerate().flat_map(|(source_index, mut exclude_filter)| {
+    WalkDir::new(&sources[source_index].path).into_iter()
+      .filter_entry(move |entry| exclude_filter.keep(entry))
+      .map(move |entry| (source_index, entry))
+  });
+  let directory_stream = futures::stream::iter(walk);
 
   let stats = directory_stream
     .enumerate()
-    .map(|(index, dir_entry)| async move {
+    .map(|(index, (source_index, dir_entry))| async move {
+    let source = &sources[source_index];
     let result = match dir_entry {
       Ok(entry) => {
-        hash_worker::hash_work(entry, index, &config.source, &cache, data_stores, &config.hmac_secret, &multi_progress, force_hash).await
+        hash_worker::hash_work(entry, index, &source.path, &source.root, cache, data_stores, &config.hmac_secret, config.chunking.as_ref(), multi_progress, force_hash).await
       },
-      Err(_) => { (None, None, false, 0) }
+      Err(_) => { (Vec::new(), None, Vec::new(), false, 0) }
     };
-    let uploaded = match result.0 {
-      Some(upload_request) => {
-        let x = upload_request.filename.clone();
-        let filename = x.to_string_lossy();
-        let requires_upload = cache.requires_upload(&upload_request.data_hash, data_stores).await.unwrap();
-        if !requires_upload.is_empty() && cache.lock_data(&upload_request.data_hash).await { // check here if it is in the database?
-          // check here if it is encrypted on the filesystem?
-          let key = Cert::from_file(&config.encrypting_key_file).unwrap();
-          let upload_request2 = upload_worker::encryption_work(&config.data_cache, upload_request, &key, multi_progress).await;
-          if !dry_run {
-            let filtered_buckets: Vec<&(DataStore, Bucket)> = requires_upload.iter().flat_map(|bucket_id| {
-              buckets.iter().find(|b| b.0.id == *bucket_id && b.0.upload_data)
-            }).collect();
-            let report = upload_worker::upload(upload_request2, &filtered_buckets, multi_progress).await;
-            cache.set_data_in_cold_storage(&report.data_hash.as_str(), "md5_hash", &report.store_ids).await.unwrap();
-            std::fs::remove_file(report.filename).unwrap();
-          } else {
-            info!("Skipping upload of {}", filename);
-            std::fs::remove_file(upload_request2.filename).unwrap();
-          }
-          true
-        } else {
-          false
+    // Small files go into the open pack instead, unless their content is in
+    // a pack that every store has already.
+    let size = result.4;
+    let (packed, whole): (Vec<_>, Vec<_>) = result.0.into_iter()
+      .partition(|r| r.range.is_none() && packer.as_ref().is_some_and(|p| p.applies_to(size)));
+    let mut placements = Vec::new();
+    let mut uploaded = false;
+    let mut encrypted = Stats::default();
+    for upload_request in packed {
+      if let Some((pack, offset, length)) = cache.packed_location(&upload_request.data_hash).await.unwrap() {
+        if cache.requires_upload(&pack, data_stores).await.unwrap().is_empty() {
+          placements.push(Placement { file_id: index as i64, pack, offset, length });
+          continue;
         }
-      },
-      _ => { false
       }
-    };
-    (result.1, result.2, result.3, uploaded)
-  }).buffered(64).fold((Stats::default(), metadata_writer), |(cur, metadata_writer), file_metadata| async move {
+      let packer = packer.as_ref().unwrap();
+      uploaded = true;
+      if let Some(sealed) = packer.add(index as i64, &upload_request.data_hash, &upload_request.filename).unwrap() {
+        let (sealed_placements, sizes) = upload_pack(sealed, config, data_stores, buckets, cache, multi_progress, dry_run).await;
+        placements.extend(sealed_placements);
+        encrypted.add_encrypted(sizes);
+      }
+    }
+    // The chunks of a file are encrypted and uploaded a few at a time.
+    let uploads: Vec<Option<(u64, u64)>> = futures::stream::iter(whole).map(|upload_request| {
+      store_object(upload_request, config, data_stores, buckets, cache, multi_progress, dry_run)
+    }).buffer_unordered(chunks_at_once).collect().await;
+    let uploaded = uploaded || uploads.iter().any(|sizes| sizes.is_some());
+    for sizes in uploads {
+      encrypted.add_encrypted(sizes);
+    }
+    (result.1, result.2, placements, result.3, size, uploaded, encrypted)
+  }).buffered(files_at_once).fold((Stats::default(), metadata_writer), |(cur, metadata_writer), file_metadata| async move {
     match file_metadata {
-      (Some(metadata), hash_cached, size, uploaded) => {
+      (Some(metadata), chunks, placements, hash_cached, size, uploaded, encrypted) => {
         metadata_writer.write(&metadata).await.unwrap();
+        if !chunks.is_empty() {
+          metadata_writer.write_chunks(metadata.uid, &chunks).await.unwrap();
+        }
+        if !placements.is_empty() {
+          metadata_writer.write_placements(&placements).await.unwrap();
+        }
         let new_stats = match metadata.ttype {
           filetype::FileType::FILE => Stats {
             files: cur.files + 1,
             unchanged_files: cur.unchanged_files + if hash_cached { 1 } else { 0 },
             uploaded: cur.uploaded + if uploaded { 1 } else { 0 },
             size: cur.size + size,
+            encrypted_plaintext: cur.encrypted_plaintext + encrypted.encrypted_plaintext,
+            encrypted_size: cur.encrypted_size + encrypted.encrypted_size,
             ..cur
           },
           filetype::FileType::SYMLINK => Stats {
@@ -188,25 +327,33 @@ pub async fn run_backup(config: BackupConfig, name: String, multi_progress: Mult
       }
     }
   }).await;
-  let (stats, metadata_writer) = stats;
+  let (mut stats, metadata_writer) = stats;
+  if let Some(sealed) = packer.as_ref().and_then(|p| p.finish()) {
+    let (placements, sizes) = upload_pack(sealed, config, data_stores, buckets, cache, multi_progress, dry_run).await;
+    metadata_writer.write_placements(&placements).await.unwrap();
+    stats.add_encrypted(sizes);
+  }
 
   metadata_writer.write_metadata("size", stats.size.to_string().as_str()).await;
+  if !tags.is_empty() {
+    metadata_writer.write_metadata("tags", tags.join(",").as_str()).await;
+  }
   metadata_writer.close().await;
 
   let metadata_filename_encrypted = format!("{}.metadata", name);
   let metadata_file_encrypted = config.metadata_cache.clone().join(metadata_filename_encrypted.clone());
   
-  {
+  let metadata_md5 = {
     let mut source = File::open(&metadata_file).unwrap();
     let mut dest = File::create(&metadata_file_encrypted).unwrap();
     let cert = Cert::from_file(config.encrypting_key_file.clone()).unwrap();
     let signing_key = config.signing_key_file.clone().map(|x| Cert::from_file(x).unwrap());
-    encryption::encrypt_file(&mut source, &mut dest, &cert, signing_key).unwrap();
-  }
+    encryption::encrypt_file(&mut source, &mut dest, &cert, signing_key, config.compression).unwrap().md5
+  };
   std::fs::remove_file(&metadata_file).unwrap();
   
   if !dry_run {
-    upload_metadata(metadata_filename_encrypted, &metadata_file_encrypted, &config.stores, multi_progress).await;
+    upload_metadata(metadata_filename_encrypted, &metadata_file_encrypted, &metadata_md5, &config.stores, multi_progress).await;
   } else {
     info!("Skipping upload of metadata")
   }
@@ -218,7 +365,17 @@ pub async fn run_backup(config: BackupConfig, name: String, multi_progress: Mult
   println!("Processed {} files ({}), {} directories and {} symlinks", stats.files, humanise_bytes(stats.size), stats.directories, stats.links);
   println!("Uploaded: {:}", stats.uploaded);
   println!("Unchanged: {:}", stats.unchanged_files);
-
-  return ()
-
+  if stats.encrypted_plaintext > 0 {
+    let saved = stats.encrypted_plaintext.saturating_sub(stats.encrypted_size);
+    println!("Stored: {} of new data as {} ({:.1}% saved by compression)",
+      humanise_bytes(stats.encrypted_plaintext), humanise_bytes(stats.encrypted_size),
+      saved as f64 * 100.0 / stats.encrypted_plaintext as f64);
+  }
+  for (store, bucket) in buckets.iter() {
+    let uploads = bucket.upload_stats();
+    if uploads.objects > 0 {
+      println!("Store {}: uploaded {} objects ({}) at {}/s", store.id, uploads.objects,
+        humanise_bytes(uploads.bytes), humanise_bytes(uploads.throughput()));
+    }
+  }
 }
\ No newline at end of file
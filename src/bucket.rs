@@ -1,6 +1,13 @@
 use std::fs::File;
-use crate::swift::SwiftBucket;
-use crate::local_bucket::LocalBucket;
+use std::io::{self, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use log::warn;
+use reqwest::header::HeaderMap;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use crate::retry::{RetryPolicy, Retryable};
+use crate::storage::{delete_each, DeleteReport, StorageBackend, StoreError, UploadStream};
 
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
@@ -10,53 +17,256 @@ pub struct ObjectEntry {
     pub bytes: i128,
     pub name: String,
     pub content_type: String,
+    /// Set for Swift Static Large Object manifests: the ETag of the assembled
+    /// object (the MD5 of the concatenated segment ETags).  `bytes` is then the
+    /// total size of all segments.
+    #[serde(default)]
+    pub slo_etag: Option<String>,
 }
 
-/// A storage bucket — either a Swift container or a local directory.
+impl ObjectEntry {
+    /// Builds an entry from the headers of a successful HEAD request, as
+    /// returned by Swift, S3 and most WebDAV servers.
+    pub(crate) fn from_headers(name: &str, headers: &HeaderMap) -> ObjectEntry {
+        let header = |h: &str| headers.get(h).and_then(|v| v.to_str().ok()).unwrap_or_default();
+        let hash = header("etag").trim_start_matches("W/").trim_matches('"').to_string();
+        let is_slo = header("x-static-large-object").eq_ignore_ascii_case("true");
+        ObjectEntry {
+            slo_etag: if is_slo { Some(hash.clone()) } else { None },
+            hash,
+            last_modified: chrono::DateTime::parse_from_rfc2822(header("last-modified"))
+                .map(|dt| dt.naive_utc().format("%Y-%m-%dT%H:%M:%S%.6f").to_string())
+                .unwrap_or_default(),
+            bytes: header("content-length").parse().unwrap_or(0),
+            name: name.to_string(),
+            content_type: match header("content-type") {
+                "" => String::from("application/octet-stream"),
+                t => t.to_string(),
+            },
+        }
+    }
+
+    /// `last_modified` as a time, if the backend reported one.
+    pub fn modified(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::NaiveDateTime::parse_from_str(&self.last_modified, "%Y-%m-%dT%H:%M:%S%.f")
+            .ok()
+            .map(|t| t.and_utc())
+    }
+}
+
+/// Progress reported by [`Bucket`] transfers.
+#[derive(Debug, Clone, Copy)]
+pub enum Transfer {
+    /// This many more bytes have been sent or received.
+    Bytes(usize),
+    /// Attempt `attempt` failed; the transfer restarts from the beginning
+    /// after `delay`.
+    Retry { attempt: u32, max_attempts: u32, delay: Duration },
+}
+
+/// A storage bucket — a Swift container, an S3 bucket, a directory on an
+/// SFTP server, a WebDAV collection, a local directory or any backend
+/// registered with [`register_backend`](crate::storage::register_backend).
 /// All callers work with this type; the underlying implementation is
 /// selected when the [`DataStore`](crate::datastore::DataStore) is initialised.
-pub enum Bucket {
-    Swift(SwiftBucket),
-    Local(LocalBucket),
+///
+/// Every operation is retried according to the store's [`RetryPolicy`].
+pub struct Bucket {
+    store_id: i32,
+    backend: Box<dyn StorageBackend>,
+    retry: RetryPolicy,
+    upload_slots: Semaphore,
+    uploads: Mutex<UploadTracker>,
+}
+
+/// The uploads made through a [`Bucket`], for reporting its throughput.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadStats {
+    pub objects: u64,
+    pub bytes: u64,
+    /// Time during which at least one upload was running.
+    pub busy: Duration,
+}
+
+impl UploadStats {
+    /// Bytes per second while busy.
+    pub fn throughput(&self) -> u64 {
+        match self.busy.as_secs_f64() {
+            secs if secs > 0.0 => (self.bytes as f64 / secs) as u64,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct UploadTracker {
+    stats: UploadStats,
+    active: usize,
+    busy_since: Option<Instant>,
+}
+
+/// Holds one of a bucket's upload slots, and keeps the bucket counted as
+/// busy, until dropped.
+pub struct UploadSlot<'a> {
+    bucket: &'a Bucket,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Drop for UploadSlot<'_> {
+    fn drop(&mut self) {
+        let mut tracker = self.bucket.uploads.lock().unwrap();
+        tracker.active -= 1;
+        if tracker.active == 0 {
+            if let Some(since) = tracker.busy_since.take() {
+                tracker.stats.busy += since.elapsed();
+            }
+        }
+    }
+}
+
+/// Returns a handle on `file` positioned at `start`, with anything after
+/// `start` discarded if `truncate` is set, so that an attempt can be
+/// repeated from scratch.
+fn rewind(file: &File, start: u64, truncate: bool) -> io::Result<File> {
+    let mut file = file.try_clone()?;
+    if truncate {
+        file.set_len(start)?;
+    }
+    file.seek(SeekFrom::Start(start))?;
+    Ok(file)
 }
 
 impl Bucket {
+    pub fn new(store_id: i32, backend: Box<dyn StorageBackend>, retry: RetryPolicy) -> Bucket {
+        Bucket {
+            store_id,
+            backend,
+            retry,
+            upload_slots: Semaphore::new(Semaphore::MAX_PERMITS),
+            uploads: Mutex::new(UploadTracker::default()),
+        }
+    }
+
+    /// Limits the number of uploads to this bucket that run at once; others
+    /// wait for a slot.  Uploads are not limited by default.
+    pub fn with_upload_limit(mut self, limit: usize) -> Bucket {
+        self.upload_slots = Semaphore::new(limit.clamp(1, Semaphore::MAX_PERMITS));
+        self
+    }
+
+    /// The uploads made so far.
+    pub fn upload_stats(&self) -> UploadStats {
+        let tracker = self.uploads.lock().unwrap();
+        let mut stats = tracker.stats;
+        if let Some(since) = tracker.busy_since {
+            stats.busy += since.elapsed();
+        }
+        stats
+    }
+
+    /// Waits for one of the bucket's upload slots.  A streamed upload needs
+    /// its slot before encryption starts, since the encryptor waits for every
+    /// store it feeds.
+    pub async fn upload_slot(&self) -> UploadSlot<'_> {
+        let permit = self.upload_slots.acquire().await.expect("upload slots are never closed");
+        let mut tracker = self.uploads.lock().unwrap();
+        if tracker.active == 0 {
+            tracker.busy_since = Some(Instant::now());
+        }
+        tracker.active += 1;
+        UploadSlot { bucket: self, _permit: permit }
+    }
+
+    fn record_upload(&self, bytes: u64) {
+        let mut tracker = self.uploads.lock().unwrap();
+        tracker.stats.objects += 1;
+        tracker.stats.bytes += bytes;
+    }
+
     pub async fn upload_with_progress(
         &self,
         key: &str,
-        source: File,
-        callback: impl Fn(usize) + Sync + Send + 'static,
+        mut source: File,
+        md5: Option<&str>,
+        callback: impl Fn(Transfer) + Sync + Send + 'static,
     ) -> Result<(), String> {
-        match self {
-            Bucket::Swift(b) => b.upload_with_progress(key, source, callback).await,
-            Bucket::Local(b) => b.upload_with_progress(key, source, callback).await,
-        }
+        let start = source.stream_position().map_err(|e| e.to_string())?;
+        let length = source.metadata().map_err(|e| e.to_string())?.len().saturating_sub(start);
+        let callback = Arc::new(callback);
+        let description = format!("Upload of {} to store {}", key, self.store_id);
+        let _slot = self.upload_slot().await;
+        self.retry.run(&description, || {
+            let source = rewind(&source, start, false);
+            let callback = Arc::clone(&callback);
+            async move {
+                let source = source.map_err(|e| StoreError::permanent(format!("Failed to rewind upload source: {}", e)))?;
+                self.backend.upload(key, source, md5, Box::new(move |n| callback(Transfer::Bytes(n)))).await
+            }
+        }, |attempt, delay, _| {
+            callback(Transfer::Retry { attempt, max_attempts: self.retry.max_attempts, delay })
+        }).await.map_err(|e| e.to_string())?;
+        self.record_upload(length);
+        Ok(())
+    }
+
+    pub fn can_stream(&self, length: u64) -> bool {
+        self.backend.can_stream(length)
+    }
+
+    /// Uploads the bytes of `source` as they arrive, in `slot`, which must
+    /// have been taken from this bucket with
+    /// [`upload_slot`](Bucket::upload_slot).  A stream cannot be replayed, so
+    /// unlike [`upload_with_progress`](Bucket::upload_with_progress) this is
+    /// attempted only once; callers upload from a file instead if it fails.
+    pub async fn upload_stream(
+        &self,
+        key: &str,
+        source: UploadStream,
+        slot: UploadSlot<'_>,
+        callback: impl Fn(Transfer) + Sync + Send + 'static,
+    ) -> Result<(), String> {
+        assert!(std::ptr::eq(slot.bucket, self), "upload slot taken from another bucket");
+        let sent = Arc::new(AtomicU64::new(0));
+        let sent_callback = Arc::clone(&sent);
+        let _slot = slot;
+        self.backend.upload_stream(key, source, Box::new(move |n| {
+            sent_callback.fetch_add(n as u64, Ordering::Relaxed);
+            callback(Transfer::Bytes(n))
+        })).await
+            .map_err(|e| format!("Streaming upload of {} to store {} failed: {}", key, self.store_id, e))?;
+        self.record_upload(sent.load(Ordering::Relaxed));
+        Ok(())
     }
 
     pub async fn exists(&self, key: &str) -> Result<bool, String> {
-        match self {
-            Bucket::Swift(b) => b.exists(key).await,
-            Bucket::Local(b) => b.exists(key).await,
-        }
+        let description = format!("Existence check of {} on store {}", key, self.store_id);
+        self.retry.run(&description, || self.backend.exists(key), |_, _, _| {})
+            .await
+            .map_err(|e| e.to_string())
     }
 
     pub async fn download_with_progress(
         &self,
         key: &str,
-        dest: File,
-        callback: impl Fn(usize) + Sync + Send + 'static,
+        mut dest: File,
+        callback: impl Fn(Transfer) + Sync + Send + 'static,
     ) -> std::io::Result<u64> {
-        match self {
-            Bucket::Swift(b) => b.download_with_progress(key, dest, callback).await,
-            Bucket::Local(b) => b.download_with_progress(key, dest, callback).await,
-        }
+        let start = dest.stream_position()?;
+        let callback = Arc::new(callback);
+        let description = format!("Download of {} from store {}", key, self.store_id);
+        self.retry.run(&description, || {
+            let dest = rewind(&dest, start, true);
+            let callback = Arc::clone(&callback);
+            async move {
+                self.backend.download(key, dest?, Box::new(move |n| callback(Transfer::Bytes(n)))).await
+            }
+        }, |attempt, delay, _| {
+            callback(Transfer::Retry { attempt, max_attempts: self.retry.max_attempts, delay })
+        }).await
     }
 
     pub async fn download(&self, key: &str, dest: File) -> std::io::Result<u64> {
-        match self {
-            Bucket::Swift(b) => b.download(key, dest).await,
-            Bucket::Local(b) => b.download(key, dest).await,
-        }
+        self.download_with_progress(key, dest, |_| {}).await
     }
 
     pub async fn list(
@@ -64,9 +274,64 @@ impl Bucket {
         prefix: Option<&str>,
         marker: Option<&str>,
     ) -> std::io::Result<Vec<ObjectEntry>> {
-        match self {
-            Bucket::Swift(b) => b.list(prefix, marker).await,
-            Bucket::Local(b) => b.list(prefix, marker).await,
+        let description = format!("Listing of store {}", self.store_id);
+        self.retry.run(&description, || self.backend.list(prefix, marker), |_, _, _| {}).await
+    }
+
+    /// Lists every object under `prefix`, following the pagination of
+    /// [`list`](Bucket::list).
+    pub async fn list_all(&self, prefix: &str) -> std::io::Result<Vec<ObjectEntry>> {
+        let mut entries = Vec::new();
+        let mut marker: Option<String> = None;
+        loop {
+            let page = self.list(Some(prefix), marker.as_deref()).await?;
+            match page.last() {
+                Some(last) => marker = Some(last.name.clone()),
+                None => return Ok(entries),
+            }
+            entries.extend(page);
         }
     }
+
+    pub async fn delete(&self, key: &str) -> Result<(), String> {
+        let description = format!("Deletion of {} from store {}", key, self.store_id);
+        self.retry.run(&description, || self.backend.delete(key), |_, _, _| {})
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Deletes `keys`, retrying only the keys whose deletion failed with a
+    /// retryable error.  Retries delete those keys one at a time, so that
+    /// the preparation a backend's bulk delete needs (such as Swift's walk
+    /// over its segments) happens once per call.
+    pub async fn delete_many(&self, keys: &[String]) -> DeleteReport {
+        let mut report = self.backend.delete_many(keys).await;
+        let mut attempt = 1;
+        while attempt < self.retry.max_attempts && report.errors.iter().any(|(_, e)| e.is_retryable(&self.retry)) {
+            let (retry, permanent): (Vec<_>, Vec<_>) = report.errors.into_iter()
+                .partition(|(_, e)| e.is_retryable(&self.retry));
+            let delay = self.retry.delay(attempt);
+            warn!("Deletion of {} object(s) from store {} failed (attempt {}/{}), retrying in {:.1}s",
+                retry.len(), self.store_id, attempt, self.retry.max_attempts, delay.as_secs_f64());
+            tokio::time::sleep(delay).await;
+            let keys: Vec<String> = retry.into_iter().map(|(key, _)| key).collect();
+            let again = delete_each(self.backend.as_ref(), &keys).await;
+            report = DeleteReport { deleted: report.deleted, errors: permanent };
+            report.merge(again);
+            attempt += 1;
+        }
+        report
+    }
+
+    /// See [`StorageBackend::hash_is_md5`].
+    pub fn hash_is_md5(&self, entry: &ObjectEntry) -> bool {
+        self.backend.hash_is_md5(entry)
+    }
+
+    pub async fn stat(&self, key: &str) -> Result<Option<ObjectEntry>, String> {
+        let description = format!("Stat of {} on store {}", key, self.store_id);
+        self.retry.run(&description, || self.backend.stat(key), |_, _, _| {})
+            .await
+            .map_err(|e| e.to_string())
+    }
 }
@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use fastcdc::v2020::{StreamCDC, AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN};
+
+use crate::hash::DataHasher;
+
+/// Content-defined chunking, configured as `[chunking]`.  Files larger than
+/// `max_size` are split with FastCDC into chunks of `min_size` to `max_size`
+/// bytes, around `avg_size` on average, and each chunk is stored as its own
+/// data object.  Smaller files are stored whole.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChunkingConfig {
+  #[serde(default = "default_min_size")]
+  pub min_size: u32,
+  #[serde(default = "default_avg_size")]
+  pub avg_size: u32,
+  #[serde(default = "default_max_size")]
+  pub max_size: u32,
+}
+
+fn default_min_size() -> u32 { 512 * 1024 }
+fn default_avg_size() -> u32 { 1024 * 1024 }
+fn default_max_size() -> u32 { 4 * 1024 * 1024 }
+
+/// A chunk of a file: its content hash and where it lies in the file.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+  pub hash: String,
+  pub offset: u64,
+  pub length: u64,
+}
+
+impl ChunkingConfig {
+  pub fn check(&self) -> Result<(), String> {
+    let in_range = |name: &str, value: u32, min: u32, max: u32| {
+      if value < min || value > max {
+        Err(format!("chunking {} must be between {} and {} bytes, not {}", name, min, max, value))
+      } else {
+        Ok(())
+      }
+    };
+    in_range("min_size", self.min_size, MINIMUM_MIN, MINIMUM_MAX)?;
+    in_range("avg_size", self.avg_size, AVERAGE_MIN, AVERAGE_MAX)?;
+    in_range("max_size", self.max_size, MAXIMUM_MIN, MAXIMUM_MAX)?;
+    if self.min_size > self.avg_size || self.avg_size > self.max_size {
+      return Err("chunking sizes must satisfy min_size <= avg_size <= max_size".to_string());
+    }
+    Ok(())
+  }
+
+  /// Whether a file of `len` bytes is split into chunks.
+  pub fn applies_to(&self, len: u64) -> bool {
+    len > u64::from(self.max_size)
+  }
+
+  /// Reads `path` once, returning the content hash of the whole file and its
+  /// chunks in order.  Each chunk is hashed like a whole file, so a chunk and
+  /// a file with the same content share a data object.
+  pub fn chunk_file(&self, path: &Path, hmac_secret: &str) -> io::Result<(String, Vec<Chunk>)> {
+    let file = File::open(path)?;
+    let mut file_hasher = DataHasher::new(hmac_secret);
+    let mut chunks = Vec::new();
+    for chunk in StreamCDC::new(file, self.min_size, self.avg_size, self.max_size) {
+      let chunk = chunk?;
+      file_hasher.write_all(&chunk.data)?;
+      let mut chunk_hasher = DataHasher::new(hmac_secret);
+      chunk_hasher.write_all(&chunk.data)?;
+      chunks.push(Chunk { hash: chunk_hasher.finish(), offset: chunk.offset, length: chunk.length as u64 });
+    }
+    Ok((file_hasher.finish(), chunks))
+  }
+}
@@ -0,0 +1,50 @@
+/// Compression of data objects and metadata files before encryption,
+/// configured as `[compression]`.  zlib and bzip2 are OpenPGP compression
+/// packets, which any OpenPGP implementation can read; zstd is applied to
+/// the plaintext before it is encrypted, and marked in the literal data
+/// packet so that it is undone on decryption.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum Compression {
+  #[default]
+  None,
+  Zstd {
+    #[serde(default = "default_zstd_level")]
+    level: i32,
+  },
+  Zlib,
+  Bzip2,
+}
+
+fn default_zstd_level() -> i32 { 3 }
+
+/// Bytes read from the start of a file to decide whether it is compressed.
+pub const PROBE_SIZE: usize = 64 * 1024;
+
+/// Above this many bits per byte, content is taken to be compressed (or
+/// encrypted) already.
+const ENTROPY_THRESHOLD: f64 = 7.5;
+
+/// Probes shorter than this are too short to judge and are compressed.
+const MIN_PROBE_SIZE: usize = 512;
+
+/// Whether `probe`, the start of some content, looks like it would not
+/// compress, judged by its Shannon entropy per byte.
+pub fn looks_compressed(probe: &[u8]) -> bool {
+  if probe.len() < MIN_PROBE_SIZE {
+    return false;
+  }
+  let mut counts = [0u64; 256];
+  for byte in probe {
+    counts[*byte as usize] += 1;
+  }
+  let len = probe.len() as f64;
+  let entropy: f64 = counts.iter()
+    .filter(|count| **count > 0)
+    .map(|count| {
+      let p = *count as f64 / len;
+      -p * p.log2()
+    })
+    .sum();
+  entropy > ENTROPY_THRESHOLD
+}
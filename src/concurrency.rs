@@ -0,0 +1,69 @@
+use log::{info, warn};
+
+/// How much of each stage runs at once, configured as `[concurrency]`; the
+/// command line overrides individual settings.  Omitted settings keep the
+/// defaults, which suit a machine with a few cores and fast storage.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ConcurrencyConfig {
+  /// Threads hashing, compressing and encrypting (default: one per core).
+  pub threads: Option<usize>,
+  /// Files hashed and uploaded at once by `backup` (default: 64).
+  pub files: Option<usize>,
+  /// Chunks of one file encrypted and uploaded at once (default: 4).
+  pub chunks: Option<usize>,
+  /// Uploads at once by `backup` to each store without its own
+  /// `max_uploads` (default: no limit other than `files` and `chunks`).
+  pub uploads_per_store: Option<usize>,
+  /// Files restored at once by `restore` (default: 4).
+  pub restore: Option<usize>,
+  /// Files checked at once by `validate` (default: 16).
+  pub validate: Option<usize>,
+  /// Rough limit in bytes on the memory taken by objects in flight during
+  /// `backup`; `files` is lowered to stay within it.
+  pub memory_budget: Option<u64>,
+}
+
+/// Bytes taken by an object in flight besides any chunk or stream buffers:
+/// the compression probe and the encryption and hashing buffers.
+const OBJECT_OVERHEAD: u64 = 1024 * 1024;
+
+impl ConcurrencyConfig {
+  pub fn files(&self) -> usize { self.files.unwrap_or(64).max(1) }
+  pub fn chunks(&self) -> usize { self.chunks.unwrap_or(4).max(1) }
+  pub fn restore(&self) -> usize { self.restore.unwrap_or(4).max(1) }
+  pub fn validate(&self) -> usize { self.validate.unwrap_or(16).max(1) }
+
+  /// Sizes rayon's global pool, which hashing and encryption run on.  Must
+  /// be called before anything is spawned on it.
+  pub fn init_threads(&self) -> Result<(), String> {
+    match self.threads {
+      Some(0) => Err("concurrency.threads must be at least 1".to_string()),
+      Some(threads) => rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .map_err(|e| format!("Failed to start {} worker threads: {}", threads, e)),
+      None => Ok(()),
+    }
+  }
+
+  /// Files to back up at once when each object in flight needs about
+  /// `object_size` bytes besides its fixed overhead: `files`, lowered as far
+  /// as `memory_budget` requires but never below one.
+  pub fn backup_files(&self, object_size: u64) -> usize {
+    let files = self.files();
+    let Some(budget) = self.memory_budget else {
+      return files;
+    };
+    let per_file = (object_size + OBJECT_OVERHEAD) * self.chunks() as u64;
+    let within = (budget / per_file) as usize;
+    if within == 0 {
+      warn!("A memory budget of {} bytes is below what one file in flight needs ({} bytes)", budget, per_file);
+      1
+    } else if within < files {
+      info!("Backing up {} files at once to stay within the memory budget", within);
+      within
+    } else {
+      files
+    }
+  }
+}
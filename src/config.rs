@@ -1,15 +1,101 @@
+use crate::chunking::ChunkingConfig;
+use crate::compression::Compression;
+use crate::concurrency::ConcurrencyConfig;
 use crate::datastore;
+use crate::pack::PackingConfig;
+use crate::prune::RetentionPolicy;
+use crate::throttle::BandwidthLimit;
 
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
 use datastore::DataStore;
 
 #[derive(Deserialize)]
 pub struct BackupConfig {
-    pub source: PathBuf,
+    /// A single directory to back up, restored directly into the destination.
+    pub source: Option<PathBuf>,
+    /// Directories to back up, each restored into a subdirectory named after
+    /// its root.  Cannot be combined with `source`.
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    /// `one_file_system` for `source`.
+    #[serde(default)]
+    pub one_file_system: bool,
+    /// `include_mounts` for `source`.
+    #[serde(default)]
+    pub include_mounts: Vec<PathBuf>,
+    /// Gitignore-style patterns, relative to `source`, for what not to back up.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Patterns re-including what `exclude` matched.
+    #[serde(default)]
+    pub include: Vec<String>,
     pub data_cache: PathBuf,
     pub metadata_cache: PathBuf,
     pub stores: Vec<DataStore>,
     pub hmac_secret: String,
     pub encrypting_key_file: PathBuf,
     pub signing_key_file: Option<PathBuf>,
+    /// Rate limits shared by all stores, on top of each store's own.
+    pub bandwidth: Option<BandwidthLimit>,
+    /// Default retention policy for `prune`.
+    pub retention: Option<RetentionPolicy>,
+    /// Split large files into content-defined chunks.
+    pub chunking: Option<ChunkingConfig>,
+    /// Collect small files into packs.
+    pub packing: Option<PackingConfig>,
+    /// Compress data objects and metadata files before encrypting them.
+    #[serde(default)]
+    pub compression: Compression,
+    /// Upload data objects as they are encrypted, to the stores that can
+    /// take a stream, instead of encrypting them to `data_cache` first.
+    #[serde(default)]
+    pub stream_uploads: bool,
+    /// How much of each stage runs at once.
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
+}
+
+/// A directory backed up under a named root, configured as `[[sources]]`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Source {
+    pub root: String,
+    pub path: PathBuf,
+    /// Do not descend into other filesystems mounted below `path`.
+    #[serde(default)]
+    pub one_file_system: bool,
+    /// Mount points walked into despite `one_file_system`.
+    #[serde(default)]
+    pub include_mounts: Vec<PathBuf>,
+}
+
+impl BackupConfig {
+    /// The directories to back up.  A plain `source` is returned as a source
+    /// with an empty root name, which keeps the layout of older backups.
+    pub fn sources(&self) -> Result<Vec<Source>, String> {
+        match (&self.source, self.sources.is_empty()) {
+            (Some(_), false) => Err("Set either source or [[sources]], not both".to_string()),
+            (None, true) => Err("No source or [[sources]] configured".to_string()),
+            (Some(path), true) => Ok(vec![Source {
+                root: String::new(),
+                path: path.clone(),
+                one_file_system: self.one_file_system,
+                include_mounts: self.include_mounts.clone(),
+            }]),
+            (None, false) => {
+                let mut roots = HashSet::new();
+                for source in &self.sources {
+                    let mut components = Path::new(&source.root).components();
+                    let single = matches!((components.next(), components.next()), (Some(Component::Normal(_)), None));
+                    if !single || source.root.contains('/') {
+                        return Err(format!("Invalid root name {:?}: must be a single path component", source.root));
+                    }
+                    if !roots.insert(source.root.as_str()) {
+                        return Err(format!("Root name {:?} is used by more than one source", source.root));
+                    }
+                }
+                Ok(self.sources.clone())
+            }
+        }
+    }
 }
\ No newline at end of file
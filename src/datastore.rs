@@ -1,6 +1,11 @@
-use crate::swift;
-use crate::local_bucket::LocalBucket;
+use std::collections::HashMap;
+use crate::s3_bucket::S3Config;
+use crate::sftp_bucket::SftpConfig;
+use crate::webdav_bucket::WebDavConfig;
 use crate::bucket::Bucket;
+use crate::retry::RetryPolicy;
+use crate::throttle::BandwidthLimit;
+use crate::storage;
 use log::trace;
 use osauth::CloudConfig;
 
@@ -9,15 +14,66 @@ fn default_true() -> bool { true }
 #[derive(Deserialize)]
 pub struct DataStore {
   pub id: i32,
-  /// Swift container name. Required when `local_path` is not set.
+  /// Storage backend: `swift`, `local`, `s3`, `sftp`, `webdav` or the name
+  /// of a backend registered with [`storage::register_backend`].  When
+  /// omitted it is inferred from which of `local_path`, `s3`, `sftp` and
+  /// `webdav` is set, falling back to `swift`.
+  #[serde(rename = "type")]
+  pub backend_type: Option<String>,
+  /// Swift container name. Required when none of `local_path`, `s3`, `sftp`
+  /// or `webdav` is set.
   pub container: Option<String>,
   pub data_prefix: String,
   pub metadata_prefix: String,
   pub cloud_config: Option<CloudConfig>,
+  /// Swift only: objects larger than this many bytes are uploaded as Static
+  /// Large Objects in segments of this size (default: 1 GiB).
+  pub segment_size: Option<u64>,
+  /// Swift only: key prefix under which SLO segments are stored (default:
+  /// `segments/`).
+  pub segment_prefix: Option<String>,
+  /// Swift only: create the container when the store is initialised if it
+  /// does not exist yet (default: false).
+  #[serde(default)]
+  pub create_container: bool,
+  /// Swift only: `X-Storage-Policy` for a container created by
+  /// `create_container`.  Defaults to the cluster's default policy.
+  pub storage_policy: Option<String>,
+  /// Swift only: metadata for a container created by `create_container`,
+  /// each entry sent as `X-Container-Meta-<name>` (e.g. `Quota-Bytes`).
+  pub container_metadata: Option<HashMap<String, String>>,
+  /// Swift only: enable object versioning on a container created by
+  /// `create_container` (default: false).
+  #[serde(default)]
+  pub versioning: bool,
   /// When set, this store reads and writes to a local directory instead of
   /// OpenStack Swift.  The path is used as the container root.
   /// `container` and `cloud_config` are ignored when this is present.
   pub local_path: Option<String>,
+  /// When set, this store reads and writes to an S3-compatible bucket instead
+  /// of OpenStack Swift.  `container` and `cloud_config` are ignored.
+  pub s3: Option<S3Config>,
+  /// When set, this store reads and writes to a directory on an SFTP server
+  /// instead of OpenStack Swift.  `container` and `cloud_config` are ignored.
+  pub sftp: Option<SftpConfig>,
+  /// When set, this store reads and writes to a WebDAV collection instead of
+  /// OpenStack Swift.  `container` and `cloud_config` are ignored.
+  pub webdav: Option<WebDavConfig>,
+  /// Free-form settings for backends registered outside this crate.
+  pub options: Option<toml::Value>,
+  /// How failed operations on this store are retried (`[stores.retry]`).
+  #[serde(default)]
+  pub retry: RetryPolicy,
+  /// Upload and download rate limits for this store (`[stores.bandwidth]`).
+  pub bandwidth: Option<BandwidthLimit>,
+  /// How many uploads to this store may run at once (default: no limit;
+  /// `backup` falls back to `concurrency.uploads_per_store`).
+  pub max_uploads: Option<usize>,
+  /// Order in which `restore` tries stores when no `--store-id` is given:
+  /// lower values first (default: 0; stores with equal priority keep their
+  /// order in the config file).
+  #[serde(default)]
+  pub priority: i32,
   /// Whether data objects should be uploaded to this store (default: true).
   #[serde(default = "default_true")]
   pub upload_data: bool,
@@ -30,11 +86,26 @@ impl Clone for DataStore {
   fn clone(&self) -> DataStore {
       DataStore {
         id: self.id,
+        backend_type: self.backend_type.clone(),
         container: self.container.clone(),
         data_prefix: self.data_prefix.clone(),
         metadata_prefix: self.metadata_prefix.clone(),
         cloud_config: self.cloud_config.clone(),
+        segment_size: self.segment_size,
+        segment_prefix: self.segment_prefix.clone(),
+        create_container: self.create_container,
+        storage_policy: self.storage_policy.clone(),
+        container_metadata: self.container_metadata.clone(),
+        versioning: self.versioning,
         local_path: self.local_path.clone(),
+        s3: self.s3.clone(),
+        sftp: self.sftp.clone(),
+        webdav: self.webdav.clone(),
+        options: self.options.clone(),
+        retry: self.retry.clone(),
+        bandwidth: self.bandwidth.clone(),
+        max_uploads: self.max_uploads,
+        priority: self.priority,
         upload_data: self.upload_data,
         upload_metadata: self.upload_metadata,
       }
@@ -42,17 +113,32 @@ impl Clone for DataStore {
 }
 
 impl DataStore {
+  /// The configured `type`, or the one implied by the backend sections present.
+  pub fn backend_type(&self) -> &str {
+    match self.backend_type {
+      Some(ref t) => t,
+      None if self.local_path.is_some() => "local",
+      None if self.s3.is_some() => "s3",
+      None if self.sftp.is_some() => "sftp",
+      None if self.webdav.is_some() => "webdav",
+      None => "swift",
+    }
+  }
+
   pub async fn init(&self) -> Bucket {
+    self.try_init().await.unwrap_or_else(|e| panic!("{}", e))
+  }
+
+  /// Like [`init`](DataStore::init), but reports a store that cannot be
+  /// reached instead of panicking.
+  pub async fn try_init(&self) -> Result<Bucket, String> {
     trace!("datastore::init");
-    if let Some(ref path) = self.local_path {
-      return Bucket::Local(LocalBucket::new(path));
-    }
-    let session = match self.cloud_config.clone() {
-      Some(config) => config.create_session().await,
-      None =>  osauth::Session::from_env().await
-    }.expect("Failed to create an identity provider");
-    let container = self.container.as_deref()
-      .unwrap_or_else(|| panic!("Store {} has no container configured and no local_path set", self.id));
-    Bucket::Swift(swift::SwiftBucket::new(session, container))
+    let backend = storage::create_backend(self).await
+      .map_err(|e| format!("Failed to initialise store {}: {}", self.id, e))?;
+    let bucket = Bucket::new(self.id, backend, self.retry.clone());
+    Ok(match self.max_uploads {
+      Some(limit) => bucket.with_upload_limit(limit),
+      None => bucket,
+    })
   }
 }
@@ -3,7 +3,11 @@ use std::io::{self, Read, Write};
 extern crate sequoia_openpgp as openpgp;
 extern crate anyhow;
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use openpgp::crypto::SessionKey;
+use openpgp::packet::Packet;
+use openpgp::parse::PacketParser;
 use openpgp::types::SymmetricAlgorithm;
 use openpgp::parse::{Parse, stream::*};
 use openpgp::policy::Policy;
@@ -11,11 +15,15 @@ use openpgp::policy::StandardPolicy as P;
 use std::fs::File;
 use openpgp::Cert;
 use log::trace;
+use crate::encryption::ZSTD_LITERAL_NAME;
 
 pub struct Decryption {
     policy: Box<dyn Policy>,
     recipient: openpgp::Cert,
     valid_signers: Option<openpgp::Cert>,
+    /// Whether the literal data is compressed with zstd, as its file name
+    /// says; known once the decryptor has been built.
+    zstd: AtomicBool,
 }
 
 impl Decryption {
@@ -24,16 +32,15 @@ impl Decryption {
             policy: Box::new(P::new()),
             recipient,
             valid_signers,
+            zstd: AtomicBool::new(false),
         }
     }
 
     pub fn decrypt<'a>(&'a self, ciphertext: &'a mut (dyn Read + Send + Sync))
-        -> Decryptor<'a, &'a Decryption> {
+        -> openpgp::Result<Decryptor<'a, &'a Decryption>> {
     
-        DecryptorBuilder::from_reader(ciphertext)
-            .unwrap()
+        DecryptorBuilder::from_reader(ciphertext)?
             .with_policy(self.policy.as_ref(), None, self)
-            .unwrap()
     }
 }
 
@@ -43,15 +50,28 @@ pub fn decrypt_file(source: &mut File, dest: &mut File, key: &Cert, valid_signer
     Ok(())
   }
 
+/// Like [`decrypt_file`], but writes the plaintext to `sink`, so that it can
+/// be checked without being stored.
+pub fn decrypt_to_writer(source: &mut File, sink: &mut (dyn Write + Send + Sync), key: &Cert, valid_signers: Option<openpgp::Cert>) -> openpgp::Result<()> {
+    decrypt(source, sink, key, valid_signers)
+}
+
 fn decrypt(source: &mut (dyn Read + Send + Sync), sink: &mut (dyn Write + Send + Sync),
   recipient: &openpgp::Cert, signing_cert: Option<openpgp::Cert>) -> openpgp::Result<()> {
 
     let decryption = Decryption::new(recipient.clone(), signing_cert);
 
-    let mut decrypted = decryption.decrypt(source);
+    let mut decrypted = decryption.decrypt(source)?;
 
-    // Decrypt the data.
-    io::copy(&mut decrypted, sink).unwrap();
+    // Decrypt the data.  Corrupted or truncated ciphertext surfaces here as
+    // a read error.
+    if decryption.zstd.load(Ordering::Relaxed) {
+        let mut decoder = zstd::stream::write::Decoder::new(sink)?;
+        io::copy(&mut decrypted, &mut decoder)?;
+        decoder.flush()?;
+    } else {
+        io::copy(&mut decrypted, sink)?;
+    }
 
     Ok(())
 }
@@ -79,6 +99,14 @@ impl VerificationHelper for &Decryption {
         Ok(certs)
     }
 
+    fn inspect(&mut self, pp: &PacketParser) -> openpgp::Result<()> {
+        if let Packet::Literal(literal) = &pp.packet {
+            let zstd = literal.filename() == Some(ZSTD_LITERAL_NAME.as_bytes());
+            self.zstd.store(zstd, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
     fn check(&mut self, structure: MessageStructure)
              -> openpgp::Result<()> {
         for layer in structure.iter() {
@@ -10,19 +10,58 @@ use openpgp::policy::StandardPolicy as P;
 use openpgp::types::Timestamp;
 use openpgp::Cert;
 use log::trace;
+use crate::compression::{self, Compression};
+use crate::hash::Md5Writer;
+
+/// The file name of the literal data packet of plaintext compressed with
+/// zstd; other messages use [`LITERAL_NAME`].
+pub const ZSTD_LITERAL_NAME: &str = "zstd";
+const LITERAL_NAME: &str = "foo";
+
+/// What [`encrypt_file`] wrote.
+pub struct Encrypted {
+  /// The hex MD5 of the ciphertext, so that stores can be asked to verify
+  /// the upload.
+  pub md5: String,
+  /// Bytes of plaintext read.
+  pub plaintext_size: u64,
+  /// Bytes of ciphertext written.
+  pub encrypted_size: u64,
+}
+
+/// Encrypts `source` into `dest`, compressed with `compression` unless the
+/// start of `source` looks compressed already.
+pub fn encrypt_file(source: &mut dyn Read, dest: &mut File, key: &Cert, signing_cert: Option<openpgp::Cert>, compression: Compression) -> openpgp::Result<Encrypted> {
+  encrypt_to_writer(source, dest, key, signing_cert, compression)
+}
 
-pub fn encrypt_file(source: &mut File, dest: &mut File, key: &Cert, signing_cert: Option<openpgp::Cert>) -> openpgp::Result<()> {
+/// Like [`encrypt_file`], but writes the ciphertext to `sink`, so that it
+/// can be uploaded as it is produced.
+pub fn encrypt_to_writer(source: &mut dyn Read, sink: &mut (dyn Write + Send + Sync), key: &Cert, signing_cert: Option<openpgp::Cert>, compression: Compression) -> openpgp::Result<Encrypted> {
   let p = &P::new();
 
-  encrypt(p, source, dest, &key, signing_cert)?;
+  let mut sink = Md5Writer::new(sink);
+  let plaintext_size = encrypt(p, source, &mut sink, &key, signing_cert, compression)?;
+  let encrypted_size = sink.written();
 
-  Ok(())
+  Ok(Encrypted { md5: sink.finish(), plaintext_size, encrypted_size })
 }
 
 fn encrypt(p: &dyn Policy, source: &mut dyn Read, sink: &mut (dyn Write + Send + Sync),
-          recipient: &openpgp::Cert, signing_cert: Option<openpgp::Cert>)
-    -> openpgp::Result<()>
+          recipient: &openpgp::Cert, signing_cert: Option<openpgp::Cert>, compression: Compression)
+    -> openpgp::Result<u64>
 {
+    // Only the start of the source is probed, then read again from memory.
+    let mut probe = Vec::with_capacity(compression::PROBE_SIZE);
+    (&mut *source).take(compression::PROBE_SIZE as u64).read_to_end(&mut probe)?;
+    let compression = if compression != Compression::None && compression::looks_compressed(&probe) {
+        trace!("Not compressing content that looks compressed");
+        Compression::None
+    } else {
+        compression
+    };
+    let mut source = io::Cursor::new(probe).chain(source);
+
     let recipients =
         recipient.keys().with_policy(p, None).supported().alive().revoked(false)
         .for_transport_encryption();
@@ -34,8 +73,13 @@ fn encrypt(p: &dyn Policy, source: &mut dyn Read, sink: &mut (dyn Write + Send +
     message = Encryptor2::for_recipients(message, recipients)
         .build()?;
 
+    let algo = match compression {
+        Compression::Zlib => CompressionAlgorithm::Zlib,
+        Compression::Bzip2 => CompressionAlgorithm::BZip2,
+        Compression::None | Compression::Zstd { .. } => CompressionAlgorithm::Uncompressed,
+    };
     message = Compressor::new(message)
-      .algo(CompressionAlgorithm::Uncompressed) // todo: 13.5 seconds down to under 8 seconds ?? is it worth it
+      .algo(algo)
       .build()?;
 
 
@@ -60,17 +104,29 @@ fn encrypt(p: &dyn Policy, source: &mut dyn Read, sink: &mut (dyn Write + Send +
     }
 
     // Emit a literal data packet.
+    let literal_name = match compression {
+        Compression::Zstd { .. } => ZSTD_LITERAL_NAME,
+        _ => LITERAL_NAME,
+    };
     message = LiteralWriter::new(message)
-      .filename("foo")?
+      .filename(literal_name)?
       .date(Timestamp::from(1585925313))?
       .build()?;
 
     // Encrypt the data.
-    io::copy(source, &mut message).unwrap();
+    let plaintext_size = match compression {
+        Compression::Zstd { level } => {
+            let mut encoder = zstd::stream::write::Encoder::new(message, level)?;
+            let copied = io::copy(&mut source, &mut encoder)?;
+            message = encoder.finish()?;
+            copied
+        }
+        _ => io::copy(&mut source, &mut message)?,
+    };
 
     // Finalize the OpenPGP message to make sure that all data is
     // written.
     message.finalize()?;
 
-    Ok(())
+    Ok(plaintext_size)
 }
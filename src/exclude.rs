@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::{debug, info, warn};
+use walkdir::DirEntry;
+
+use crate::config::Source;
+
+/// Per-directory pattern file, with the same syntax as `.gitignore`.
+pub const IGNORE_FILE: &str = ".backupignore";
+
+/// The start of a `CACHEDIR.TAG` file, as given by
+/// <https://bford.info/cachedir/>.
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Decides which entries of the walk of a source are backed up.
+///
+/// `exclude` and `include` are gitignore-style patterns relative to `source`;
+/// an `include` pattern re-includes what an `exclude` pattern matched, as a
+/// `!pattern` line would.  A `.backupignore` file applies to its directory and
+/// everything below it, and overrides the configured patterns and the
+/// `.backupignore` files of parent directories.  Directories containing a
+/// valid `CACHEDIR.TAG` are skipped.  With `one_file_system`, mount points
+/// are skipped unless listed in `include_mounts`.
+pub struct ExcludeFilter {
+  patterns: Gitignore,
+  /// The `.backupignore` files of the directories above the current entry,
+  /// outermost first, with the depth of their directory.
+  ignore_files: Vec<(usize, Gitignore)>,
+  /// With `one_file_system`, the mount points that may be walked into.
+  mounts: Option<Vec<PathBuf>>,
+  /// The devices of the directories above the current entry, by depth.
+  devices: Vec<Option<u64>>,
+}
+
+impl ExcludeFilter {
+  pub fn new(source: &Source, exclude: &[String], include: &[String]) -> Result<ExcludeFilter, String> {
+    let mut builder = GitignoreBuilder::new(&source.path);
+    for pattern in exclude {
+      builder.add_line(None, pattern).map_err(|e| format!("Invalid exclude pattern {:?}: {}", pattern, e))?;
+    }
+    for pattern in include {
+      builder.add_line(None, &format!("!{}", pattern)).map_err(|e| format!("Invalid include pattern {:?}: {}", pattern, e))?;
+    }
+    let patterns = builder.build().map_err(|e| format!("Invalid exclude patterns: {}", e))?;
+    Ok(ExcludeFilter {
+      patterns,
+      ignore_files: Vec::new(),
+      mounts: source.one_file_system.then(|| source.include_mounts.clone()),
+      devices: Vec::new(),
+    })
+  }
+
+  /// Returns whether `entry` is backed up.  Returning false for a directory
+  /// prunes its whole subtree, so this must see entries in walk order, as
+  /// `WalkDir::filter_entry` calls it.
+  pub fn keep(&mut self, entry: &DirEntry) -> bool {
+    let depth = entry.depth();
+    while self.ignore_files.last().is_some_and(|(d, _)| *d >= depth) {
+      self.ignore_files.pop();
+    }
+    self.devices.truncate(depth);
+
+    let path = entry.path();
+    let is_dir = entry.file_type().is_dir();
+    // The source itself is always walked.
+    if depth > 0 {
+      if self.is_excluded(path, is_dir) {
+        debug!("Excluding {:?}", path);
+        return false;
+      }
+      if is_dir && is_cache_dir(path) {
+        debug!("Excluding cache directory {:?}", path);
+        return false;
+      }
+    }
+
+    if let Some(mounts) = &self.mounts {
+      // Anything on another device than its parent directory is a mount
+      // point; entries whose metadata cannot be read are left to the walk.
+      let device = entry.metadata().map(|m| m.dev()).ok();
+      let parent = self.devices.last().copied().flatten();
+      if let (Some(device), Some(parent)) = (device, parent) {
+        if device != parent && !mounts.iter().any(|m| m == path) {
+          info!("Skipping mount point {:?} (one_file_system)", path);
+          return false;
+        }
+      }
+      if is_dir {
+        self.devices.push(device.or(parent));
+      }
+    }
+
+    if is_dir {
+      let ignore_file = path.join(IGNORE_FILE);
+      if ignore_file.is_file() {
+        let (gitignore, e) = Gitignore::new(&ignore_file);
+        if let Some(e) = e {
+          warn!("Problem reading {:?}: {}", ignore_file, e);
+        }
+        self.ignore_files.push((depth, gitignore));
+      }
+    }
+    true
+  }
+
+  fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+    for (_, gitignore) in self.ignore_files.iter().rev() {
+      match gitignore.matched(path, is_dir) {
+        Match::Ignore(_) => return true,
+        Match::Whitelist(_) => return false,
+        Match::None => {}
+      }
+    }
+    self.patterns.matched(path, is_dir).is_ignore()
+  }
+}
+
+fn is_cache_dir(path: &Path) -> bool {
+  let mut signature = [0u8; CACHEDIR_TAG_SIGNATURE.len()];
+  File::open(path.join("CACHEDIR.TAG"))
+    .and_then(|mut f| f.read_exact(&mut signature))
+    .is_ok_and(|()| signature == CACHEDIR_TAG_SIGNATURE)
+}
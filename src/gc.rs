@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{error, info, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use sequoia_openpgp::Cert;
+use sequoia_openpgp::parse::Parse;
+
+use crate::config::BackupConfig;
+use crate::datastore::DataStore;
+use crate::list::backup_names;
+use crate::metadata_file::MetadataReader;
+use crate::restore::fetch_metadata;
+use crate::sqlite_cache::AsyncCache;
+
+/// Builds the set of data hashes referenced by every backup whose metadata
+/// file is on any of `stores`.  Fails if any metadata file cannot be read,
+/// since objects referenced only by that backup would look unreferenced.
+async fn referenced_hashes(config: &BackupConfig, stores: &[DataStore]) -> Result<HashSet<String>, String> {
+  let key = Cert::from_file(&config.encrypting_key_file).unwrap();
+  let tmp_suffix: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(8)
+    .map(char::from)
+    .collect();
+  let tmp_dir = std::env::temp_dir().join(format!("backup-gc-{}", tmp_suffix));
+  create_dir_all(&tmp_dir).unwrap();
+
+  let result = async {
+    let mut referenced = HashSet::new();
+    let mut read: HashSet<String> = HashSet::new();
+    for store in stores.iter().filter(|s| s.upload_metadata) {
+      let bucket = store.try_init().await?;
+      let names = backup_names(store, &bucket).await
+        .map_err(|e| format!("Failed to list backups on store {}: {}", store.id, e))?;
+      // Mirrors normally hold the same backups; each is read only once.
+      for name in names {
+        if read.contains(&name) {
+          continue;
+        }
+        let encrypted = tmp_dir.join(format!("{}.metadata", name));
+        let decrypted = tmp_dir.join(format!("{}.sqlite", name));
+        fetch_metadata(&bucket, &store.metadata_prefix, &name, &encrypted, &decrypted, &key, &config.signing_key_file).await
+          .map_err(|e| format!("Failed to read the metadata of {} from store {}: {}", name, store.id, e))?;
+        referenced.extend(MetadataReader::new(decrypted.clone()).await.data_hashes().await);
+        std::fs::remove_file(&encrypted).unwrap();
+        std::fs::remove_file(&decrypted).unwrap();
+        read.insert(name);
+      }
+    }
+    info!("{} backup(s) reference {} data object(s)", read.len(), referenced.len());
+    Ok(referenced)
+  }.await;
+
+  remove_dir_all(&tmp_dir).unwrap();
+  result
+}
+
+/// Deletes the data objects on `stores` that no backup references and that
+/// were last modified more than `grace` ago, then brings `uploaded_objects`
+/// in the local cache in line with what is left on each store.
+///
+/// References are collected from the metadata files on every configured
+/// store, not only `stores`, so that data-only stores are handled.  The grace
+/// period protects objects uploaded by a backup still in progress, whose
+/// metadata file does not exist yet.  Returns false if anything failed.
+pub async fn gc(config: &BackupConfig, stores: &[DataStore], grace: Duration, dry_run: bool) -> bool {
+  let referenced = match referenced_hashes(config, &config.stores).await {
+    Ok(referenced) => referenced,
+    Err(e) => {
+      error!("{}; not deleting anything", e);
+      return false;
+    }
+  };
+  let cutoff = Utc::now() - chrono::Duration::from_std(grace).unwrap_or(chrono::Duration::MAX);
+
+  let cache = AsyncCache::new().await;
+  let mut ok = true;
+  for store in stores.iter().filter(|s| s.upload_data) {
+    let bucket = store.init().await;
+    let prefix = store.data_prefix.as_str();
+    let objects = match bucket.list_all(prefix).await {
+      Ok(objects) => objects,
+      Err(e) => {
+        error!("Failed to list data on store {}: {}", store.id, e);
+        ok = false;
+        continue;
+      }
+    };
+
+    let mut orphans: Vec<String> = Vec::new();
+    let mut recent = 0;
+    let mut remaining: Vec<(String, Option<String>)> = Vec::new();
+    for object in &objects {
+      let Some(hash) = object.name.strip_prefix(prefix) else { continue };
+      let md5 = Some(object.hash.clone()).filter(|_| bucket.hash_is_md5(object));
+      if referenced.contains(hash) {
+        remaining.push((hash.to_string(), md5));
+      } else if object.modified().is_some_and(|t| t < cutoff) {
+        orphans.push(object.name.clone());
+      } else {
+        // Unknown modification times are treated as recent.
+        recent += 1;
+        remaining.push((hash.to_string(), md5));
+      }
+    }
+    info!("Store {}: {} object(s), {} unreferenced, {} unreferenced but within the grace period",
+      store.id, objects.len(), orphans.len(), recent);
+
+    if dry_run {
+      for key in &orphans {
+        println!("would delete  store={}  {}", store.id, key);
+      }
+      continue;
+    }
+
+    let report = bucket.delete_many(&orphans).await;
+    let failed: HashSet<&str> = report.errors.iter().map(|(key, _)| key.as_str()).collect();
+    for (key, e) in &report.errors {
+      error!("Failed to delete {} from store {}: {}", key, store.id, e);
+      ok = false;
+    }
+    remaining.extend(orphans.iter()
+      .filter(|key| failed.contains(key.as_str()))
+      .map(|key| (key[prefix.len()..].to_string(), None)));
+    info!("Store {}: deleted {} object(s)", store.id, report.deleted);
+
+    // Reconcile the cache with the store: forget what is no longer there and
+    // record what is there but unknown (e.g. uploaded from another machine).
+    let recorded: HashSet<String> = cache.cold_storage_hashes(store.id).await.unwrap().into_iter().collect();
+    let present: HashSet<&str> = remaining.iter().map(|(hash, _)| hash.as_str()).collect();
+    let stale: Vec<String> = recorded.iter().filter(|h| !present.contains(h.as_str())).cloned().collect();
+    cache.remove_data_from_cold_storage(&stale, store.id).await.unwrap();
+    let mut added = 0;
+    for (hash, md5) in remaining.iter().filter(|(hash, _)| !recorded.contains(hash)) {
+      cache.set_data_in_cold_storage(hash, md5.as_deref(), &vec![store.id]).await.unwrap();
+      added += 1;
+    }
+    if !stale.is_empty() || added > 0 {
+      warn!("Store {}: local cache had {} stale and {} missing entries, now corrected", store.id, stale.len(), added);
+    }
+  }
+  cache.close().await;
+  ok
+}
@@ -1,6 +1,7 @@
-use std::io;
+use std::io::{self, Write};
 use std::fs;
 use std::path::Path;
+use md5::Md5;
 use sha2::{Sha512, Digest};
 use hmac::{Hmac, Mac};
 use std::os::unix::ffi::OsStrExt;
@@ -14,11 +15,73 @@ pub fn metadata(len: u64, mtime: i64, path: &Path) -> String {
 }
 
 pub fn data(path: &Path, hmac_secret: &str) -> String {
-  type HmacSha512 = Hmac<Sha512>;
-  let mut hasher = HmacSha512::new_from_slice(hmac_secret.as_bytes())
-      .expect("HMAC can take key of any size");
+  let mut hasher = DataHasher::new(hmac_secret);
   let mut file = fs::File::open(path).unwrap();
   io::copy(&mut file, &mut hasher).unwrap();
-  let digest = hasher.finalize().into_bytes();
-  return format!("{:X}", digest);
-}
\ No newline at end of file
+  hasher.finish()
+}
+
+/// Computes the same content hash as [`data`] over everything written to it.
+pub struct DataHasher {
+  hasher: Hmac<Sha512>,
+}
+
+impl DataHasher {
+  pub fn new(hmac_secret: &str) -> DataHasher {
+    DataHasher {
+      hasher: Hmac::new_from_slice(hmac_secret.as_bytes()).expect("HMAC can take key of any size"),
+    }
+  }
+
+  pub fn finish(self) -> String {
+    format!("{:X}", self.hasher.finalize().into_bytes())
+  }
+}
+
+impl Write for DataHasher {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.hasher.update(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Passes writes through to `inner` while computing the MD5 of everything
+/// written, which is what Swift and S3 report as an object's ETag.
+pub struct Md5Writer<W> {
+  inner: W,
+  hasher: Md5,
+  written: u64,
+}
+
+impl<W: Write> Md5Writer<W> {
+  pub fn new(inner: W) -> Md5Writer<W> {
+    Md5Writer { inner, hasher: Md5::new(), written: 0 }
+  }
+
+  /// The number of bytes written so far.
+  pub fn written(&self) -> u64 {
+    self.written
+  }
+
+  /// Returns the lower-case hex MD5 of the data written so far.
+  pub fn finish(self) -> String {
+    format!("{:x}", self.hasher.finalize())
+  }
+}
+
+impl<W: Write> Write for Md5Writer<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = self.inner.write(buf)?;
+    self.hasher.update(&buf[..n]);
+    self.written += n as u64;
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
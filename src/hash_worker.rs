@@ -1,5 +1,6 @@
 use std::{os::unix::prelude::MetadataExt, fs::Metadata};
 use crate::{metadata_file::{self, FileMetadata}, upload_worker, datastore, sqlite_cache::AsyncCache, filetype, hash};
+use crate::chunking::{Chunk, ChunkingConfig};
 use datastore::DataStore;
 use indicatif::{MultiProgress, ProgressStyle, ProgressBar};
 use log::trace;
@@ -7,8 +8,11 @@ use log::warn;
 use filetype::FileType;
 use upload_worker::UploadRequest;
 
-async fn generate_hash(dir_entry: &walkdir::DirEntry, cache: &AsyncCache, hmac_secret: &String, mp: &MultiProgress, metadata: &Metadata) -> String {
-    let hms = hmac_secret.clone();
+/// Hashes the file, and with `chunking` also splits it into chunks, recording
+/// both in the cache.
+async fn generate_hash(dir_entry: &walkdir::DirEntry, cache: &AsyncCache, hmac_secret: &str, chunking: Option<&ChunkingConfig>, mp: &MultiProgress, metadata: &Metadata) -> (String, Vec<Chunk>) {
+    let hms = hmac_secret.to_owned();
+    let chunking = chunking.cloned();
     let de = dir_entry.path().to_owned().clone();
     let (send, recv) = tokio::sync::oneshot::channel();
     let spinner_style = ProgressStyle::with_template("{prefix:.bold.dim} {spinner} {wide_msg}")
@@ -20,62 +24,93 @@ async fn generate_hash(dir_entry: &walkdir::DirEntry, cache: &AsyncCache, hmac_s
     rayon::spawn(move || {
         let pb = mp.add(ProgressBar::new_spinner());
         pb.set_style(spinner_style.clone());
-        pb.set_prefix(format!("[Hash]"));
+        pb.set_prefix("[Hash]");
         pb.inc(1);
-        pb.set_message(format!("{}", filename));
-        let res = hash::data(&de, &hms);
+        pb.set_message(filename);
+        let res = match chunking {
+            Some(chunking) => chunking.chunk_file(&de, &hms).unwrap(),
+            None => (hash::data(&de, &hms), Vec::new()),
+        };
         pb.finish_and_clear();
         let _ = send.send(res);
     });
     let res = recv.await.expect("Panic in rayon::spawn");
 
     let metadata_hash = hash::metadata(metadata.len(), metadata.mtime(), dir_entry.path());
-    cache.set_data_hash(&metadata_hash, &res).await.unwrap();
+    cache.set_data_hash(&metadata_hash, &res.0).await.unwrap();
+    if !res.1.is_empty() {
+        cache.set_chunks(&res.0, &res.1).await.unwrap();
+    }
     res
 }
 
-pub async fn hash_work(dir_entry: walkdir::DirEntry, id: usize, source: &std::path::Path, cache: &AsyncCache, stores: &Vec<DataStore>, hmac_secret: &String, mp: &MultiProgress, force_hash: bool) -> (Option<UploadRequest>, Option<FileMetadata>, bool, u64) {
+/// Returns the uploads the entry needs, its metadata, the data objects of its
+/// chunks (empty unless chunked), whether its hash came from the cache, and
+/// its size.
+#[allow(clippy::too_many_arguments)]
+pub async fn hash_work(dir_entry: walkdir::DirEntry, id: usize, source: &std::path::Path, root: &str, cache: &AsyncCache, stores: &[DataStore], hmac_secret: &str, chunking: Option<&ChunkingConfig>, mp: &MultiProgress, force_hash: bool) -> (Vec<UploadRequest>, Option<FileMetadata>, Vec<String>, bool, u64) {
     let file_type: Option<FileType> = FileType::from(dir_entry.file_type());
     let mut destination: Option<String> = None;
     let mut data_hash: Option<String> = None;
+    let mut chunks: Vec<String> = Vec::new();
     let metadata = dir_entry.metadata().unwrap();
-    let mut upload_request: Option<UploadRequest> = None;
+    let mut upload_requests: Vec<UploadRequest> = Vec::new();
     let mut hash_cached = false;
     match file_type {
-        Some(FileType::FILE) => {
-            // For empty file: no content to hash or upload; data_hash stays None.
-            if metadata.len() != 0 {
-                let cached_d_hash = cache.try_get_hash(dir_entry.path(), &metadata).await.unwrap();
-                let d_hash = match cached_d_hash {
-                    Some(h) => {
-                        hash_cached = true;
-                        if force_hash {
-                            let generated_hash = generate_hash(&dir_entry, cache, hmac_secret, mp, &metadata).await;
-                            if generated_hash != h {
-                                warn!("Hash in cache does not match expected value for {:?}. Updated DB to match filesystem", dir_entry.file_name());
-                            }
-                            generated_hash
-                        } else {
-                            h
+        // For empty file: no content to hash or upload; data_hash stays None.
+        Some(FileType::FILE) if metadata.len() != 0 => {
+            let chunking = chunking.filter(|c| c.applies_to(metadata.len()));
+            let cached_d_hash = cache.try_get_hash(dir_entry.path(), &metadata).await.unwrap();
+            // A file to be chunked is only taken from the cache if its chunks are known too.
+            let cached = match (cached_d_hash, chunking) {
+                (Some(h), Some(_)) => {
+                    let cached_chunks = cache.get_chunks(&h).await.unwrap();
+                    (!cached_chunks.is_empty()).then_some((h, cached_chunks))
+                }
+                (Some(h), None) => Some((h, Vec::new())),
+                (None, _) => None,
+            };
+            let (d_hash, file_chunks) = match cached {
+                Some(h) => {
+                    hash_cached = true;
+                    if force_hash {
+                        let generated_hash = generate_hash(&dir_entry, cache, hmac_secret, chunking, mp, &metadata).await;
+                        if generated_hash.0 != h.0 {
+                            warn!("Hash in cache does not match expected value for {:?}. Updated DB to match filesystem", dir_entry.file_name());
                         }
-                    },
-                    None => {
-                        generate_hash(&dir_entry, cache, hmac_secret, mp, &metadata).await
+                        generated_hash
+                    } else {
+                        h
                     }
-                };
-                
-                let requires_upload = cache.requires_upload(&d_hash, &stores).await.unwrap();
+                },
+                None => {
+                    generate_hash(&dir_entry, cache, hmac_secret, chunking, mp, &metadata).await
+                }
+            };
+
+            // A whole file is one data object, a chunked file one per chunk.
+            let objects: Vec<(String, Option<(u64, u64)>)> = if file_chunks.is_empty() {
+                vec![(d_hash.clone(), None)]
+            } else {
+                file_chunks.iter().map(|c| (c.hash.clone(), Some((c.offset, c.length)))).collect()
+            };
+            for (object_hash, range) in objects {
+                let requires_upload = cache.requires_upload(&object_hash, stores).await.unwrap();
                 if !requires_upload.is_empty() {
-                    trace!("Sending {:?} to upload queue\n", dir_entry.file_name());
-                    upload_request = Some(UploadRequest {
-                        filename: dir_entry.path().to_path_buf(),  
-                        data_hash: d_hash.clone(),
+                    trace!("Sending {:?} ({:?}) to upload queue\n", dir_entry.file_name(), range);
+                    upload_requests.push(UploadRequest {
+                        filename: dir_entry.path().to_path_buf(),
+                        data_hash: object_hash,
+                        encrypted_md5: None,
+                        range,
+                        sizes: None,
                     });
                 } else {
-                    trace!("Skipping {:?} ({:?} already uploaded)\n", dir_entry.file_name(), d_hash);
+                    trace!("Skipping {:?} ({:?} already uploaded)\n", dir_entry.file_name(), object_hash);
                 }
-                data_hash = Some(d_hash);
             }
+            chunks = file_chunks.into_iter().map(|c| c.hash).collect();
+            data_hash = Some(d_hash);
         }
         Some(FileType::SYMLINK) => {
             destination = Some(std::fs::read_link(dir_entry.path()).unwrap().to_string_lossy().to_string());
@@ -89,15 +124,21 @@ pub async fn hash_work(dir_entry: walkdir::DirEntry, id: usize, source: &std::pa
             .unwrap_or(dir_entry.path())
             .to_string_lossy()
             .to_string();
+        // Entries of a named root are stored under it.
+        let rel_name = match (root.is_empty(), rel_name.is_empty()) {
+            (true, _) => rel_name,
+            (false, true) => root.to_string(),
+            (false, false) => format!("{}/{}", root, rel_name),
+        };
         metadata_file::FileMetadata {
             uid: id as i64,
             name: rel_name,
             mtime: metadata.mtime(),
             mode: metadata.mode(),
-            ttype: ttype,
+            ttype,
             destination,
             data_hash,
         }
     });
-    (upload_request, file_metadata, hash_cached, metadata.len())
+    (upload_requests, file_metadata, chunks, hash_cached, metadata.len())
 }
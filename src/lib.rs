@@ -0,0 +1,42 @@
+//! Encrypted, deduplicating backups to OpenStack Swift and other object
+//! stores.  The `backup-tool` binary is a thin CLI over this library; other
+//! crates can depend on it to add their own storage backends with
+//! [`storage::register_backend`] before running a backup or restore.
+
+pub mod encryption;
+pub mod decryption;
+pub mod swift;
+pub mod local_bucket;
+pub mod s3_bucket;
+pub mod sftp_bucket;
+pub mod webdav_bucket;
+pub mod bucket;
+pub mod storage;
+pub mod retry;
+pub mod throttle;
+pub mod datastore;
+pub mod metadata_file;
+pub mod sqlite_cache;
+pub mod hash;
+pub mod chunking;
+pub mod pack;
+pub mod compression;
+pub mod concurrency;
+pub mod filetype;
+pub mod exclude;
+pub mod config;
+pub mod upload_worker;
+pub mod hash_worker;
+pub mod backup;
+pub mod restore;
+pub mod list;
+pub mod query;
+pub mod rebuild_cache;
+pub mod replicate;
+pub mod prune;
+pub mod gc;
+pub mod utils;
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
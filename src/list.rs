@@ -1,41 +1,32 @@
 use std::collections::{BTreeMap, BTreeSet};
 use log::error;
+use crate::bucket::Bucket;
 use crate::datastore;
 use datastore::DataStore;
 
+/// Names of the backups whose metadata file is in `store`, in order.
+pub async fn backup_names(store: &DataStore, bucket: &Bucket) -> std::io::Result<BTreeSet<String>> {
+  let objects = bucket.list_all(store.metadata_prefix.as_str()).await?;
+  Ok(objects.iter()
+    .filter_map(|obj| obj.name
+      .strip_prefix(store.metadata_prefix.as_str())
+      .and_then(|n| n.strip_suffix(".metadata")))
+    .map(|name| name.to_string())
+    .collect())
+}
+
 pub async fn list_backups(stores: &[DataStore]) {
   // Map each backup name to the set of store ids that hold it.
   let mut presence: BTreeMap<String, BTreeSet<i32>> = BTreeMap::new();
   for store in stores {
     let bucket = store.init().await;
-    let mut marker: Option<String> = None;
-
-    loop {
-      let objects = match bucket
-        .list(Some(store.metadata_prefix.as_str()), marker.as_deref())
-        .await
-      {
-        Ok(objs) => objs,
-        Err(e) => {
-          error!("Failed to list store {}: {}", store.id, e);
-          break;
+    match backup_names(store, &bucket).await {
+      Ok(names) => {
+        for name in names {
+          presence.entry(name).or_default().insert(store.id);
         }
-      };
-
-      if objects.is_empty() {
-        break;
       }
-
-      for obj in &objects {
-        if let Some(name) = obj.name
-          .strip_prefix(store.metadata_prefix.as_str())
-          .and_then(|n| n.strip_suffix(".metadata"))
-        {
-          presence.entry(name.to_string()).or_default().insert(store.id);
-        }
-      }
-
-      marker = objects.last().map(|obj| obj.name.clone());
+      Err(e) => error!("Failed to list store {}: {}", store.id, e),
     }
   }
 
@@ -1,18 +1,29 @@
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use futures::StreamExt;
 use crate::bucket::ObjectEntry;
+use crate::storage::{ProgressCallback, StorageBackend, StoreError, UploadStream};
+use crate::throttle::{Direction, Throttle};
 
-/// A `Bucket` implementation backed by the local filesystem.  The
+/// A [`StorageBackend`] implementation backed by the local filesystem.  The
 /// `root` directory acts as the container; object keys are mapped to
 /// paths beneath it (parent directories are created as needed).
 pub struct LocalBucket {
     root: PathBuf,
+    throttle: Throttle,
 }
 
 impl LocalBucket {
     pub fn new(root: impl Into<PathBuf>) -> Self {
-        LocalBucket { root: root.into() }
+        LocalBucket { root: root.into(), throttle: Throttle::default() }
+    }
+
+    /// Limits the bandwidth used by uploads and downloads.
+    pub fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
     }
 
     fn key_path(&self, key: &str) -> PathBuf {
@@ -20,13 +31,17 @@ impl LocalBucket {
         // safely inside root.
         self.root.join(key.trim_start_matches('/'))
     }
+}
 
-    pub async fn upload_with_progress(
+#[async_trait]
+impl StorageBackend for LocalBucket {
+    async fn upload(
         &self,
         key: &str,
         mut source: File,
-        callback: impl Fn(usize) + Sync + Send + 'static,
-    ) -> Result<(), String> {
+        _md5: Option<&str>,
+        callback: ProgressCallback,
+    ) -> Result<(), StoreError> {
         let dest_path = self.key_path(key);
         if let Some(parent) = dest_path.parent() {
             fs::create_dir_all(parent)
@@ -40,21 +55,63 @@ impl LocalBucket {
             if n == 0 {
                 break;
             }
+            self.throttle.consume(Direction::Upload, n).await;
             dest.write_all(&buf[..n]).map_err(|e| format!("Write error: {e}"))?;
             callback(n);
         }
         Ok(())
     }
 
-    pub async fn exists(&self, key: &str) -> Result<bool, String> {
+    fn can_stream(&self, _length: u64) -> bool {
+        true
+    }
+
+    /// Writes to a `.partial` file beside the object, renamed into place
+    /// once the stream has ended without error.
+    async fn upload_stream(
+        &self,
+        key: &str,
+        mut source: UploadStream,
+        callback: ProgressCallback,
+    ) -> Result<(), StoreError> {
+        let dest_path = self.key_path(key);
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create directories for {key}: {e}"))?;
+        }
+        let mut partial_name = dest_path.file_name().unwrap_or_default().to_os_string();
+        partial_name.push(".partial");
+        let partial_path = dest_path.with_file_name(partial_name);
+        let mut dest = File::create(&partial_path)
+            .map_err(|e| format!("Failed to create {}: {e}", partial_path.display()))?;
+        let written: Result<(), StoreError> = async {
+            while let Some(bytes) = source.next().await {
+                let bytes = bytes.map_err(|e| format!("Upload stream failed: {e}"))?;
+                self.throttle.consume(Direction::Upload, bytes.len()).await;
+                dest.write_all(&bytes).map_err(|e| format!("Write error: {e}"))?;
+                callback(bytes.len());
+            }
+            Ok(())
+        }.await;
+        match written {
+            Ok(()) => fs::rename(&partial_path, &dest_path)
+                .map_err(|e| StoreError::from(format!("Failed to rename {}: {e}", partial_path.display()))),
+            Err(e) => {
+                let _ = fs::remove_file(&partial_path);
+                Err(e)
+            }
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
         Ok(self.key_path(key).exists())
     }
 
-    pub async fn download_with_progress(
+    async fn download(
         &self,
         key: &str,
         mut dest: File,
-        callback: impl Fn(usize) + Sync + Send + 'static,
+        callback: ProgressCallback,
     ) -> io::Result<u64> {
         let mut source = File::open(self.key_path(key))?;
         let mut buf = vec![0u8; 64 * 1024];
@@ -64,6 +121,7 @@ impl LocalBucket {
             if n == 0 {
                 break;
             }
+            self.throttle.consume(Direction::Download, n).await;
             dest.write_all(&buf[..n])?;
             callback(n);
             total += n as u64;
@@ -71,15 +129,9 @@ impl LocalBucket {
         Ok(total)
     }
 
-    pub async fn download(&self, key: &str, dest: File) -> io::Result<u64> {
-        let mut source = File::open(self.key_path(key))?;
-        let mut dest = dest;
-        io::copy(&mut source, &mut dest)
-    }
-
     /// Returns up to 100 entries whose key begins with `prefix`, with
     /// keys strictly greater than `marker` (mimicking Swift's pagination).
-    pub async fn list(
+    async fn list(
         &self,
         prefix: Option<&str>,
         marker: Option<&str>,
@@ -93,6 +145,43 @@ impl LocalBucket {
         entries.truncate(100);
         Ok(entries)
     }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        match fs::remove_file(self.key_path(key)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Failed to delete {key}: {e}").into()),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<ObjectEntry>, StoreError> {
+        match fs::metadata(self.key_path(key)) {
+            Ok(meta) => Ok(Some(entry_for(key.to_string(), &meta))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Failed to stat {key}: {e}").into()),
+        }
+    }
+}
+
+fn entry_for(name: String, meta: &fs::Metadata) -> ObjectEntry {
+    let last_modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| {
+            chrono::DateTime::from_timestamp(d.as_secs() as i64, 0)
+                .map(|dt| dt.format("%Y-%m-%dT%H:%M:%S%.6f").to_string())
+                .unwrap_or_default()
+        })
+        .unwrap_or_default();
+    ObjectEntry {
+        hash: String::new(),
+        last_modified,
+        bytes: meta.len() as i128,
+        name,
+        content_type: String::from("application/octet-stream"),
+        slo_etag: None,
+    }
 }
 
 fn collect_entries(
@@ -114,25 +203,13 @@ fn collect_entries(
                     continue;
                 }
             }
+            // Skip streamed uploads that are still in flight (or were
+            // interrupted).
+            if name.ends_with(".partial") {
+                continue;
+            }
             let meta = fs::metadata(&path)?;
-            let bytes = meta.len() as i128;
-            let last_modified = meta
-                .modified()
-                .ok()
-                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-                .map(|d| {
-                    chrono::DateTime::from_timestamp(d.as_secs() as i64, 0)
-                        .map(|dt| dt.format("%Y-%m-%dT%H:%M:%S%.6f").to_string())
-                        .unwrap_or_default()
-                })
-                .unwrap_or_default();
-            result.push(ObjectEntry {
-                hash: String::new(),
-                last_modified,
-                bytes,
-                name,
-                content_type: String::from("application/octet-stream"),
-            });
+            result.push(entry_for(name, &meta));
         }
     }
     Ok(())
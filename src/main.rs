@@ -1,40 +1,19 @@
-pub mod encryption;
-pub mod decryption;
-pub mod swift;
-pub mod local_bucket;
-pub mod bucket;
-pub mod datastore;
-pub mod metadata_file;
-pub mod sqlite_cache;
-pub mod hash;
-pub mod filetype;
-pub mod config;
-pub mod upload_worker;
-pub mod hash_worker;
-pub mod backup;
-pub mod restore;
-pub mod list;
-pub mod query;
-pub mod rebuild_cache;
-pub mod utils;
-
 use std::path::PathBuf;
 
-use config::BackupConfig;
+use backup_tool::{backup, config::BackupConfig, datastore::DataStore, gc, list, prune, rebuild_cache, replicate, restore, throttle};
 
 use clap::{Parser, Subcommand};
 use indicatif::MultiProgress;
 use indicatif_log_bridge::LogWrapper;
 
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-
 #[derive(Parser)]
 #[command(version = env!("APP_VERSION"))]
 struct Cli {
     #[arg(short, long, default_value = "backup.toml")]
     config: PathBuf,
+    /// Threads for hashing, compression and encryption; overrides `concurrency.threads`.
+    #[arg(long, global = true)]
+    threads: Option<usize>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -49,16 +28,41 @@ enum Commands {
         /// Restrict to these store ids (comma-separated or repeated). Omit to use all stores.
         #[arg(short, long, value_delimiter = ',', num_args = 0..)]
         limit: Vec<i32>,
+        /// Tags to record with the backup (comma-separated or repeated), for `prune --keep-tag`.
+        #[arg(long, value_delimiter = ',', num_args = 0..)]
+        tag: Vec<String>,
+        /// Gitignore-style patterns to exclude (repeatable); when given, `exclude` in the config is ignored.
+        #[arg(long)]
+        exclude: Vec<String>,
+        /// Files to hash and upload at once; overrides `concurrency.files`.
+        #[arg(long)]
+        files: Option<usize>,
+        /// Chunks of a file to upload at once; overrides `concurrency.chunks`.
+        #[arg(long)]
+        chunks: Option<usize>,
+        /// Uploads at once to each store without `max_uploads`; overrides `concurrency.uploads_per_store`.
+        #[arg(long)]
+        uploads_per_store: Option<usize>,
+        /// Rough limit in bytes on memory for objects in flight; overrides `concurrency.memory_budget`.
+        #[arg(long)]
+        memory_budget: Option<u64>,
     },
     Restore {
         name: String,
         destination: String,
-        /// Store to fetch data objects from.
-        #[arg(short, long, default_value_t = 1)]
-        store_id: i32,
-        /// Store to fetch the metadata file from. Defaults to --store-id if not specified.
+        /// Stores to fetch data objects from, tried in this order (comma-separated or repeated).
+        /// Omit to try every store by priority.
+        #[arg(short, long, value_delimiter = ',', num_args = 0..)]
+        store_id: Vec<i32>,
+        /// Store to fetch the metadata file from. Defaults to trying the data stores in order.
         #[arg(long)]
         metadata_store_id: Option<i32>,
+        /// Restore only this root of a backup with several sources, directly into DESTINATION.
+        #[arg(long)]
+        root: Option<String>,
+        /// Files to restore at once; overrides `concurrency.restore`.
+        #[arg(long)]
+        parallel: Option<usize>,
     },
     List {
         /// Restrict to these store ids (comma-separated or repeated). Omit to use all stores.
@@ -70,6 +74,54 @@ enum Commands {
         /// Restrict to these store ids (comma-separated or repeated). Omit to use all stores.
         #[arg(short, long, value_delimiter = ',', num_args = 0..)]
         limit: Vec<i32>,
+        /// Also download, decrypt and hash every data object.
+        #[arg(long, default_value_t = false)]
+        read_data: bool,
+        /// Like --read-data for a subset of objects: a percentage ("5%") or group n of m ("2/7").
+        #[arg(long, conflicts_with = "read_data")]
+        read_data_subset: Option<restore::DataSample>,
+        /// Files to check at once; overrides `concurrency.validate`.
+        #[arg(long)]
+        parallel: Option<usize>,
+    },
+    /// Copy a backup's data and metadata objects from one store to another.
+    Replicate {
+        /// Backup to copy. Required unless --all is given.
+        #[arg(required_unless_present = "all")]
+        name: Option<String>,
+        /// Store to copy from.
+        #[arg(long)]
+        from: i32,
+        /// Store to copy to.
+        #[arg(long)]
+        to: i32,
+        /// Copy every backup on the source store.
+        #[arg(long, default_value_t = false, conflicts_with = "name")]
+        all: bool,
+    },
+    /// Delete backups that the retention policy does not keep.
+    Prune {
+        /// Restrict to these store ids (comma-separated or repeated). Omit to use all stores.
+        #[arg(short, long, value_delimiter = ',', num_args = 0..)]
+        limit: Vec<i32>,
+        /// Only show what would be kept and removed.
+        #[arg(short, long, default_value_t = false)]
+        dry_run: bool,
+        /// Retention rules; when any is given, [retention] in the config is ignored.
+        #[command(flatten)]
+        policy: prune::RetentionPolicy,
+    },
+    /// Delete data objects that no remaining backup references.
+    Gc {
+        /// Restrict to these store ids (comma-separated or repeated). Omit to use all stores.
+        #[arg(short, long, value_delimiter = ',', num_args = 0..)]
+        limit: Vec<i32>,
+        /// Only show what would be deleted.
+        #[arg(short, long, default_value_t = false)]
+        dry_run: bool,
+        /// Leave unreferenced objects modified more recently than this (e.g. "12h", "2days").
+        #[arg(long, default_value = "1day", value_parser = humantime::parse_duration)]
+        grace_period: std::time::Duration,
     },
     RebuildCache {
         /// Restrict to these store ids (comma-separated or repeated). Omit to use all stores.
@@ -98,7 +150,12 @@ async fn main() {
     console_subscriber::init();
     let cli = Cli::parse();
     let content = std::fs::read_to_string(&cli.config).unwrap();
-    let config: BackupConfig = toml::from_str(&content).unwrap();
+    let mut config: BackupConfig = toml::from_str(&content).unwrap();
+    if let Some(limit) = &config.bandwidth {
+        throttle::set_global_limit(limit).unwrap();
+    }
+    config.concurrency.threads = cli.threads.or(config.concurrency.threads);
+    config.concurrency.init_threads().unwrap();
 
 
     let orig_hook = std::panic::take_hook();
@@ -109,7 +166,7 @@ async fn main() {
         std::process::exit(1);
     }));
 
-    let filter_stores = |stores: Vec<crate::datastore::DataStore>, limit: &Vec<i32>| {
+    let filter_stores = |stores: Vec<DataStore>, limit: &Vec<i32>| {
         if limit.is_empty() {
             stores
         } else {
@@ -118,45 +175,92 @@ async fn main() {
     };
 
     match &cli.command {
-        Commands::Backup { force_hash, dry_run, limit } => {
+        Commands::Backup { force_hash, dry_run, limit, tag, exclude, files, chunks, uploads_per_store, memory_budget } => {
             let mut filtered_config = config;
             filtered_config.stores = filter_stores(filtered_config.stores, limit);
-            backup::run_backup(filtered_config, backup::generate_name(), multi_progress, !!force_hash, !!dry_run).await
+            if !exclude.is_empty() {
+                filtered_config.exclude = exclude.clone();
+            }
+            let concurrency = &mut filtered_config.concurrency;
+            concurrency.files = files.or(concurrency.files);
+            concurrency.chunks = chunks.or(concurrency.chunks);
+            concurrency.uploads_per_store = uploads_per_store.or(concurrency.uploads_per_store);
+            concurrency.memory_budget = memory_budget.or(concurrency.memory_budget);
+            backup::run_backup(filtered_config, backup::generate_name(), tag, multi_progress, !!force_hash, !!dry_run).await
         }
-        Commands::Restore { name, destination, store_id, metadata_store_id } => {
-            let data_store = config.stores.iter().find(|s| s.id == *store_id)
-                .unwrap_or_else(|| panic!("No store with id {}", store_id));
-            let meta_id = metadata_store_id.unwrap_or(*store_id);
-            let metadata_store = config.stores.iter().find(|s| s.id == meta_id)
-                .unwrap_or_else(|| panic!("No store with id {}", meta_id));
-            restore::restore_backup(
+        Commands::Restore { name, destination, store_id, metadata_store_id, root, parallel } => {
+            config.concurrency.restore = parallel.or(config.concurrency.restore);
+            let find_store = |id: &i32| config.stores.iter().find(|s| s.id == *id).cloned()
+                .unwrap_or_else(|| panic!("No store with id {}", id));
+            let candidates: Vec<DataStore> = if store_id.is_empty() {
+                let mut stores = config.stores.clone();
+                stores.sort_by_key(|s| s.priority);
+                stores
+            } else {
+                store_id.iter().map(find_store).collect()
+            };
+            let metadata_stores: Vec<DataStore> = match metadata_store_id {
+                Some(id) => vec![find_store(id)],
+                None => candidates.iter().filter(|s| s.upload_metadata).cloned().collect(),
+            };
+            let data_stores: Vec<DataStore> = candidates.into_iter().filter(|s| s.upload_data).collect();
+            let restored = restore::restore_backup(
                 PathBuf::from(destination),
                 name,
-                metadata_store,
-                data_store,
+                root.as_deref(),
+                &metadata_stores,
+                &data_stores,
                 config.encrypting_key_file,
                 &config.hmac_secret,
                 &config.signing_key_file,
+                config.concurrency.restore(),
                 multi_progress,
-            ).await
+            ).await;
+            if !restored {
+                std::process::exit(1);
+            }
         }
         Commands::List { limit } => {
             let stores = filter_stores(config.stores, limit);
             list::list_backups(&stores).await
         }
-        Commands::Validate { name, limit } => {
+        Commands::Validate { name, limit, read_data, read_data_subset, parallel } => {
+            config.concurrency.validate = parallel.or(config.concurrency.validate);
             let stores = filter_stores(config.stores, limit);
+            let sample = if *read_data { Some(restore::DataSample::All) } else { *read_data_subset };
             let passed = restore::validate_backup(
                 name,
                 &stores,
                 config.encrypting_key_file,
                 &config.signing_key_file,
+                &config.hmac_secret,
+                sample,
+                config.concurrency.validate(),
                 multi_progress,
             ).await;
             if !passed {
                 std::process::exit(1);
             }
         }
+        Commands::Replicate { name, from, to, all: _ } => {
+            let replicated = replicate::replicate(&config, name.as_deref(), *from, *to, multi_progress).await;
+            if !replicated {
+                std::process::exit(1);
+            }
+        }
+        Commands::Prune { limit, dry_run, policy } => {
+            let policy = if policy.is_empty() { config.retention.clone().unwrap_or_default() } else { policy.clone() };
+            let stores = filter_stores(config.stores.clone(), limit);
+            if !prune::prune(&config, &stores, &policy, *dry_run).await {
+                std::process::exit(1);
+            }
+        }
+        Commands::Gc { limit, dry_run, grace_period } => {
+            let stores = filter_stores(config.stores.clone(), limit);
+            if !gc::gc(&config, &stores, *grace_period, *dry_run).await {
+                std::process::exit(1);
+            }
+        }
         Commands::RebuildCache { limit } => {
             rebuild_cache::rebuild_cache(config, limit).await
         }
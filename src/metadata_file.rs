@@ -1,4 +1,7 @@
+use std::collections::HashMap;
+
 use crate::filetype;
+use crate::pack::Placement;
 use filetype::FileType;
 use sqlx::Executor;
 use sqlx::SqlitePool;
@@ -21,26 +24,55 @@ pub struct MetadataWriter {
 }
 
 pub struct MetadataReader {
-  pool: SqlitePool
+  pool: SqlitePool,
+  version: u32,
 }
 
+/// Where the content of a file is stored.
+#[derive(Debug, Clone)]
+pub enum Content {
+  /// In the data object named by its `data_hash`.
+  Object,
+  /// In these data objects, joined in order.
+  Chunks(Vec<String>),
+  /// In part of the data object `pack`.
+  Packed { pack: String, offset: u64, length: u64 },
+}
+
+/// Version 1 added the `roots` table; entries of a named root are stored
+/// under `<root>/`, and the root itself as `<root>`.  Version 2 added the
+/// `chunks` table, listing in order the data objects of each chunked file;
+/// the `data_hash` of a chunked file is that of its whole content and has no
+/// data object of its own.  Version 3 added the `packed` table, placing
+/// packed files in their pack, whose `data_hash` likewise names no object.
+const VERSION: u32 = 3;
+
 use std::path::PathBuf;
 
 impl MetadataReader {
-  pub async fn new<'b>(filename: PathBuf) -> MetadataReader {
+  pub async fn new(filename: PathBuf) -> MetadataReader {
     let options = sqlx::sqlite::SqliteConnectOptions::new()
       .journal_mode(sqlx::sqlite::SqliteJournalMode::Delete)
       .read_only(true)
       .filename(filename);
-    let reader = MetadataReader {
-      pool: SqlitePool::connect_with(options).await.unwrap()
-    };
-    let result = reader.pool.fetch_one(sqlx::query("SELECT value FROM metadata where key = 'version';")).await.unwrap();
+    let pool = SqlitePool::connect_with(options).await.unwrap();
+    let result = pool.fetch_one(sqlx::query("SELECT value FROM metadata where key = 'version';")).await.unwrap();
     let version: String = result.get(0);
-    if version != "0" {
-      panic!("Version is not supported")
+    let version = match version.parse::<u32>() {
+      Ok(version) if version <= VERSION => version,
+      _ => panic!("Version is not supported"),
+    };
+    MetadataReader { pool, version }
+  }
+
+  /// The roots of the backup as (name, original path), or nothing for
+  /// backups of a single unnamed source.
+  pub async fn roots(&self) -> Vec<(String, String)> {
+    if self.version < 1 {
+      return Vec::new();
     }
-    reader
+    let rows = self.pool.fetch_all(sqlx::query("SELECT name, path FROM roots ORDER BY name;")).await.unwrap();
+    rows.iter().map(|row| (row.get(0), row.get(1))).collect()
   }
 
   pub async fn read_metadata(&self, key: &str) -> String {
@@ -51,6 +83,60 @@ impl MetadataReader {
     result.get(0)
   }
 
+  /// Like [`read_metadata`](MetadataReader::read_metadata), for keys that
+  /// older backups may not have.
+  pub async fn try_read_metadata(&self, key: &str) -> Option<String> {
+    let result = self.pool.fetch_optional(
+      sqlx::query("SELECT value FROM metadata where key = ?;")
+        .bind(key)
+    ).await.unwrap();
+    result.map(|row| row.get(0))
+  }
+
+  /// The distinct data objects referenced by the backup: the data hashes of
+  /// whole files, the chunks of chunked files and the packs of packed files.
+  pub async fn data_hashes(&self) -> Vec<String> {
+    let query = match self.version {
+      3.. => sqlx::query("SELECT data_hash FROM files WHERE data_hash IS NOT NULL AND id NOT IN (SELECT file_id FROM chunks) AND id NOT IN (SELECT file_id FROM packed) UNION SELECT data_hash FROM chunks UNION SELECT pack FROM packed;"),
+      2 => sqlx::query("SELECT data_hash FROM files WHERE data_hash IS NOT NULL AND id NOT IN (SELECT file_id FROM chunks) UNION SELECT data_hash FROM chunks;"),
+      _ => sqlx::query("SELECT DISTINCT data_hash FROM files WHERE data_hash IS NOT NULL;"),
+    };
+    let rows = self.pool.fetch_all(query).await.unwrap();
+    rows.iter().map(|row| row.get(0)).collect()
+  }
+
+  /// Where the content of file `file_id` is stored.
+  pub async fn content(&self, file_id: i64) -> Content {
+    if self.version >= 3 {
+      let row = self.pool.fetch_optional(
+        sqlx::query("SELECT pack, pack_offset, length FROM packed WHERE file_id = ?;")
+          .bind(file_id)
+      ).await.unwrap();
+      if let Some(row) = row {
+        return Content::Packed { pack: row.get(0), offset: row.get::<i64, _>(1) as u64, length: row.get::<i64, _>(2) as u64 };
+      }
+    }
+    if self.version >= 2 {
+      let rows = self.pool.fetch_all(
+        sqlx::query("SELECT data_hash FROM chunks WHERE file_id = ? ORDER BY seq;")
+          .bind(file_id)
+      ).await.unwrap();
+      if !rows.is_empty() {
+        return Content::Chunks(rows.iter().map(|row| row.get(0)).collect());
+      }
+    }
+    Content::Object
+  }
+
+  /// The number of files in each pack.
+  pub async fn pack_members(&self) -> HashMap<String, u64> {
+    if self.version < 3 {
+      return HashMap::new();
+    }
+    let rows = self.pool.fetch_all(sqlx::query("SELECT pack, COUNT(*) FROM packed GROUP BY pack;")).await.unwrap();
+    rows.iter().map(|row| (row.get(0), row.get::<i64, _>(1) as u64)).collect()
+  }
+
   pub async fn read(&self, reversed: bool) -> futures_core::stream::BoxStream<'_, FileMetadata> {
     use futures::StreamExt;
     let query = if reversed {
@@ -74,7 +160,7 @@ impl MetadataReader {
 }
 
 impl MetadataWriter {
-  pub async fn new<'b>(filename: PathBuf) -> MetadataWriter {
+  pub async fn new(filename: PathBuf) -> MetadataWriter {
     let options = sqlx::sqlite::SqliteConnectOptions::new()
       .journal_mode(sqlx::sqlite::SqliteJournalMode::Delete)
       .create_if_missing(true)
@@ -84,10 +170,21 @@ impl MetadataWriter {
     };
     metadata_file.pool.execute(sqlx::query("CREATE TABLE files (id INTEGER PRIMARY KEY, name TEXT, mtime INTEGER, mode INTEGER, ttype STRING, destination STRING NULL, data_hash STRING NULL);")).await.unwrap();
     metadata_file.pool.execute(sqlx::query("CREATE TABLE metadata (key TEXT, value TEXT);")).await.unwrap();
-    metadata_file.pool.execute(sqlx::query("INSERT INTO metadata (key, value) VALUES('version', '0');")).await.unwrap();
+    metadata_file.pool.execute(sqlx::query("CREATE TABLE roots (name TEXT PRIMARY KEY, path TEXT);")).await.unwrap();
+    metadata_file.pool.execute(sqlx::query("CREATE TABLE chunks (file_id INTEGER, seq INTEGER, data_hash TEXT, PRIMARY KEY (file_id, seq));")).await.unwrap();
+    metadata_file.pool.execute(sqlx::query("CREATE TABLE packed (file_id INTEGER PRIMARY KEY, pack TEXT, pack_offset INTEGER, length INTEGER);")).await.unwrap();
+    metadata_file.pool.execute(sqlx::query("INSERT INTO metadata (key, value) VALUES('version', ?);").bind(VERSION.to_string())).await.unwrap();
     metadata_file
   }
 
+  pub async fn write_root(&self, name: &str, path: &str) {
+    self.pool.execute(
+      sqlx::query("INSERT INTO roots (name, path) VALUES(?, ?);")
+        .bind(name)
+        .bind(path)
+    ).await.unwrap();
+  }
+
   pub async fn write(&self, entry: &FileMetadata) -> Result<i64, sqlx::Error> {
     let query = sqlx::query("INSERT INTO files (id, name, mtime, mode, ttype, destination, data_hash) VALUES(?, ?, ?, ?, ?, ?, ?);")
       .bind(entry.uid)
@@ -101,6 +198,33 @@ impl MetadataWriter {
     Ok(id)
   }
 
+  pub async fn write_chunks(&self, file_id: i64, chunks: &[String]) -> Result<(), sqlx::Error> {
+    let mut transaction = self.pool.begin().await?;
+    for (seq, data_hash) in chunks.iter().enumerate() {
+      sqlx::query("INSERT INTO chunks (file_id, seq, data_hash) VALUES(?, ?, ?);")
+        .bind(file_id)
+        .bind(seq as i64)
+        .bind(data_hash)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await
+  }
+
+  pub async fn write_placements(&self, placements: &[Placement]) -> Result<(), sqlx::Error> {
+    let mut transaction = self.pool.begin().await?;
+    for placement in placements {
+      sqlx::query("INSERT INTO packed (file_id, pack, pack_offset, length) VALUES(?, ?, ?, ?);")
+        .bind(placement.file_id)
+        .bind(&placement.pack)
+        .bind(placement.offset as i64)
+        .bind(placement.length as i64)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await
+  }
+
   pub async fn write_metadata(&self, key: &str, value: &str) {
     self.pool.execute(
       sqlx::query("INSERT INTO metadata (key, value) VALUES(?, ?);")
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::hash::DataHasher;
+
+/// Packing of small files, configured as `[packing]`.  Files of up to
+/// `max_file_size` bytes are collected into packs of about `pack_size` bytes,
+/// each stored as one data object instead of one object per file.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PackingConfig {
+  #[serde(default = "default_max_file_size")]
+  pub max_file_size: u64,
+  #[serde(default = "default_pack_size")]
+  pub pack_size: u64,
+}
+
+fn default_max_file_size() -> u64 { 64 * 1024 }
+fn default_pack_size() -> u64 { 8 * 1024 * 1024 }
+
+/// Where the content of file `file_id` lies: `length` bytes at `offset` in
+/// the plaintext of data object `pack`.
+#[derive(Debug, Clone)]
+pub struct Placement {
+  pub file_id: i64,
+  pub pack: String,
+  pub offset: u64,
+  pub length: u64,
+}
+
+/// A full pack, ready to be encrypted and uploaded.
+pub struct SealedPack {
+  /// The plaintext of the pack.
+  pub path: PathBuf,
+  /// The content hash of the plaintext, which is the pack's data object key.
+  pub pack: String,
+  /// The distinct contents in the pack as (data hash, offset, length).
+  pub members: Vec<(String, u64, u64)>,
+  pub placements: Vec<Placement>,
+}
+
+struct OpenPack {
+  path: PathBuf,
+  file: File,
+  hasher: DataHasher,
+  size: u64,
+  /// Offset and length of each content already in the pack.
+  members: HashMap<String, (u64, u64)>,
+  files: Vec<(i64, String)>,
+}
+
+/// Collects small files into packs, one open pack at a time.
+pub struct Packer {
+  config: PackingConfig,
+  dir: PathBuf,
+  hmac_secret: String,
+  open: Mutex<Option<OpenPack>>,
+}
+
+impl Packer {
+  /// Packs are written to `dir` until they are uploaded.
+  pub fn new(config: PackingConfig, dir: &Path, hmac_secret: &str) -> Packer {
+    Packer { config, dir: dir.to_path_buf(), hmac_secret: hmac_secret.to_string(), open: Mutex::new(None) }
+  }
+
+  /// Whether a file of `len` bytes is packed.
+  pub fn applies_to(&self, len: u64) -> bool {
+    len <= self.config.max_file_size
+  }
+
+  /// Adds the file at `path`, with content hash `data_hash`, to the open pack
+  /// unless that content is in it already.  Returns the pack once it has
+  /// reached `pack_size`.
+  pub fn add(&self, file_id: i64, data_hash: &str, path: &Path) -> io::Result<Option<SealedPack>> {
+    let mut open = self.open.lock().unwrap();
+    if open.is_none() {
+      let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+      let pack_path = self.dir.join(format!("pack-{}.plain", suffix));
+      *open = Some(OpenPack {
+        file: File::create(&pack_path)?,
+        path: pack_path,
+        hasher: DataHasher::new(&self.hmac_secret),
+        size: 0,
+        members: HashMap::new(),
+        files: Vec::new(),
+      });
+    }
+    let pack = open.as_mut().unwrap();
+    if !pack.members.contains_key(data_hash) {
+      let content = std::fs::read(path)?;
+      pack.file.write_all(&content)?;
+      pack.hasher.write_all(&content)?;
+      pack.members.insert(data_hash.to_string(), (pack.size, content.len() as u64));
+      pack.size += content.len() as u64;
+    }
+    pack.files.push((file_id, data_hash.to_string()));
+    if pack.size >= self.config.pack_size {
+      Ok(open.take().map(seal))
+    } else {
+      Ok(None)
+    }
+  }
+
+  /// Returns the open pack, if any, however small.
+  pub fn finish(&self) -> Option<SealedPack> {
+    self.open.lock().unwrap().take().map(seal)
+  }
+}
+
+fn seal(open: OpenPack) -> SealedPack {
+  let pack = open.hasher.finish();
+  let placements = open.files.into_iter().map(|(file_id, data_hash)| {
+    let (offset, length) = open.members[&data_hash];
+    Placement { file_id, pack: pack.clone(), offset, length }
+  }).collect();
+  SealedPack {
+    path: open.path,
+    members: open.members.into_iter().map(|(data_hash, (offset, length))| (data_hash, offset, length)).collect(),
+    pack,
+    placements,
+  }
+}
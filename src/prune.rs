@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, Utc};
+use log::{error, info, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use sequoia_openpgp::Cert;
+use sequoia_openpgp::parse::Parse;
+
+use crate::backup::name_timestamp;
+use crate::bucket::Bucket;
+use crate::config::BackupConfig;
+use crate::datastore::DataStore;
+use crate::list::backup_names;
+use crate::metadata_file::MetadataReader;
+use crate::restore::fetch_metadata;
+
+/// Which backups `prune` keeps, configured as `[retention]` or on the
+/// command line.  A backup is kept if any rule selects it.
+///
+/// The `keep_hourly` … `keep_yearly` rules keep the newest backup of each of
+/// the most recent N hours, days, ISO weeks, months or years (in local time)
+/// that have a backup.
+#[derive(Deserialize, clap::Args, Clone, Debug, Default)]
+pub struct RetentionPolicy {
+  /// Keep the N most recent backups.
+  #[arg(long, default_value_t = 0)]
+  #[serde(default)]
+  pub keep_last: usize,
+  /// Keep the newest backup of each of the last N hours.
+  #[arg(long, default_value_t = 0)]
+  #[serde(default)]
+  pub keep_hourly: usize,
+  /// Keep the newest backup of each of the last N days.
+  #[arg(long, default_value_t = 0)]
+  #[serde(default)]
+  pub keep_daily: usize,
+  /// Keep the newest backup of each of the last N weeks.
+  #[arg(long, default_value_t = 0)]
+  #[serde(default)]
+  pub keep_weekly: usize,
+  /// Keep the newest backup of each of the last N months.
+  #[arg(long, default_value_t = 0)]
+  #[serde(default)]
+  pub keep_monthly: usize,
+  /// Keep the newest backup of each of the last N years.
+  #[arg(long, default_value_t = 0)]
+  #[serde(default)]
+  pub keep_yearly: usize,
+  /// Keep every backup taken within this long (e.g. "30days", "1y 6months")
+  /// of the most recent one.
+  #[arg(long)]
+  pub keep_within: Option<String>,
+  /// Keep every backup with any of these tags (comma-separated or repeated).
+  #[arg(long = "keep-tag", value_delimiter = ',', num_args = 0..)]
+  #[serde(default)]
+  pub keep_tags: Vec<String>,
+}
+
+/// A backup considered by [`RetentionPolicy::plan`].
+pub struct Candidate {
+  pub name: String,
+  pub tags: Vec<String>,
+}
+
+impl RetentionPolicy {
+  pub fn is_empty(&self) -> bool {
+    self.keep_last == 0 && self.keep_hourly == 0 && self.keep_daily == 0 && self.keep_weekly == 0
+      && self.keep_monthly == 0 && self.keep_yearly == 0 && self.keep_within.is_none() && self.keep_tags.is_empty()
+  }
+
+  /// Returns each of `backups`, newest first, with the reasons it is kept;
+  /// backups without reasons are to be removed.  Backups whose name carries
+  /// no timestamp are always kept.
+  pub fn plan(&self, backups: Vec<Candidate>) -> Result<Vec<(Candidate, Vec<String>)>, String> {
+    let within = match self.keep_within.as_deref() {
+      Some(d) => {
+        let parsed = humantime::parse_duration(d).map_err(|e| format!("Invalid keep_within {:?}: {}", d, e))?;
+        Some(chrono::Duration::from_std(parsed).map_err(|e| format!("Invalid keep_within {:?}: {}", d, e))?)
+      }
+      None => None,
+    };
+
+    let mut backups: Vec<(Option<DateTime<Utc>>, Candidate)> = backups.into_iter()
+      .map(|b| (name_timestamp(&b.name), b))
+      .collect();
+    backups.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| b.1.name.cmp(&a.1.name)));
+    let newest = backups.iter().filter_map(|(time, _)| *time).max();
+
+    let periods: [(&str, usize, &str); 5] = [
+      ("hourly", self.keep_hourly, "%Y-%m-%d %H:00"),
+      ("daily", self.keep_daily, "%Y-%m-%d"),
+      ("weekly", self.keep_weekly, "%G-W%V"),
+      ("monthly", self.keep_monthly, "%Y-%m"),
+      ("yearly", self.keep_yearly, "%Y"),
+    ];
+    let mut last_period: [Option<String>; 5] = Default::default();
+    let mut kept_periods = [0usize; 5];
+    let mut position = 0;
+
+    let mut plan = Vec::new();
+    for (time, backup) in backups {
+      let mut reasons = Vec::new();
+      let Some(time) = time else {
+        reasons.push("name has no timestamp".to_string());
+        plan.push((backup, reasons));
+        continue;
+      };
+      position += 1;
+      if position <= self.keep_last {
+        reasons.push(format!("last {}", position));
+      }
+      let local = time.with_timezone(&Local);
+      for (i, (rule, count, format)) in periods.iter().enumerate() {
+        let period = local.format(format).to_string();
+        if kept_periods[i] < *count && last_period[i].as_ref() != Some(&period) {
+          reasons.push(format!("{} {}", rule, period));
+          kept_periods[i] += 1;
+          last_period[i] = Some(period);
+        }
+      }
+      if let (Some(within), Some(newest)) = (within, newest) {
+        if time >= newest - within {
+          reasons.push(format!("within {}", self.keep_within.as_deref().unwrap_or_default()));
+        }
+      }
+      for tag in backup.tags.iter().filter(|t| self.keep_tags.contains(t)) {
+        reasons.push(format!("tag {}", tag));
+      }
+      plan.push((backup, reasons));
+    }
+    Ok(plan)
+  }
+}
+
+/// Reads the tags recorded in the metadata file of `name`.
+async fn backup_tags(bucket: &Bucket, store: &DataStore, name: &str, tmp_dir: &Path, key: &Cert, signing_key_file: &Option<PathBuf>) -> Result<Vec<String>, String> {
+  let encrypted = tmp_dir.join(format!("{}.metadata", name));
+  let decrypted = tmp_dir.join(format!("{}.sqlite", name));
+  fetch_metadata(bucket, &store.metadata_prefix, name, &encrypted, &decrypted, key, signing_key_file).await?;
+  let tags = MetadataReader::new(decrypted.clone()).await.try_read_metadata("tags").await;
+  let _ = std::fs::remove_file(&encrypted);
+  let _ = std::fs::remove_file(&decrypted);
+  Ok(tags.map(|t| t.split(',').filter(|t| !t.is_empty()).map(|t| t.to_string()).collect()).unwrap_or_default())
+}
+
+/// Applies `policy` to the backups on each of `stores`, printing what is kept
+/// and removed and why, and deletes the metadata files of the removed ones
+/// unless `dry_run` is set.  Data objects are left for `gc`.  Returns false
+/// if anything could not be deleted.
+pub async fn prune(config: &BackupConfig, stores: &[DataStore], policy: &RetentionPolicy, dry_run: bool) -> bool {
+  if policy.is_empty() {
+    error!("No retention policy given; set [retention] in the config or pass --keep-* options");
+    return false;
+  }
+
+  // Tags are only known from the metadata files, so they are read only when
+  // the policy keeps tagged backups, and once per backup across stores.
+  let key = if policy.keep_tags.is_empty() {
+    None
+  } else {
+    Some(Cert::from_file(&config.encrypting_key_file).unwrap())
+  };
+  let tmp_suffix: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(8)
+    .map(char::from)
+    .collect();
+  let tmp_dir = std::env::temp_dir().join(format!("backup-prune-{}", tmp_suffix));
+  create_dir_all(&tmp_dir).unwrap();
+  let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+
+  let mut ok = true;
+  for store in stores.iter().filter(|s| s.upload_metadata) {
+    let bucket = store.init().await;
+    let names = match backup_names(store, &bucket).await {
+      Ok(names) => names,
+      Err(e) => {
+        error!("Failed to list backups on store {}: {}", store.id, e);
+        ok = false;
+        continue;
+      }
+    };
+
+    let mut candidates = Vec::new();
+    for name in names {
+      let candidate_tags = match (&key, tags.get(&name)) {
+        (None, _) => Vec::new(),
+        (Some(_), Some(known)) => known.clone(),
+        (Some(key), None) => match backup_tags(&bucket, store, &name, &tmp_dir, key, &config.signing_key_file).await {
+          Ok(read) => {
+            tags.insert(name.clone(), read.clone());
+            read
+          }
+          Err(e) => {
+            // Without its tags the backup might be removed wrongly, so keep it.
+            warn!("Keeping {} on store {}: could not read its tags: {}", name, store.id, e);
+            continue;
+          }
+        },
+      };
+      candidates.push(Candidate { name, tags: candidate_tags });
+    }
+
+    let plan = match policy.plan(candidates) {
+      Ok(plan) => plan,
+      Err(e) => {
+        error!("{}", e);
+        remove_dir_all(&tmp_dir).unwrap();
+        return false;
+      }
+    };
+
+    println!("Store {}:", store.id);
+    let mut removed = 0;
+    for (backup, reasons) in &plan {
+      if reasons.is_empty() {
+        println!("  remove  {}", backup.name);
+      } else {
+        println!("  keep    {}  ({})", backup.name, reasons.join(", "));
+      }
+    }
+    for (backup, _) in plan.iter().filter(|(_, reasons)| reasons.is_empty()) {
+      if dry_run {
+        continue;
+      }
+      let object_key = format!("{}{}.metadata", store.metadata_prefix, backup.name);
+      match bucket.delete(&object_key).await {
+        Ok(()) => removed += 1,
+        Err(e) => {
+          error!("Failed to delete {} from store {}: {}", object_key, store.id, e);
+          ok = false;
+        }
+      }
+    }
+    let to_remove = plan.iter().filter(|(_, reasons)| reasons.is_empty()).count();
+    if dry_run {
+      info!("Store {}: would keep {} and remove {} backup(s)", store.id, plan.len() - to_remove, to_remove);
+    } else {
+      info!("Store {}: kept {} and removed {} backup(s)", store.id, plan.len() - to_remove, removed);
+    }
+  }
+
+  remove_dir_all(&tmp_dir).unwrap();
+  ok
+}
@@ -14,6 +14,12 @@ impl fmt::Debug for Query {
     }
 }
 
+impl Default for Query {
+    fn default() -> Query {
+        Query::new()
+    }
+}
+
 impl Query {
     /// Empty query.
     pub fn new() -> Query {
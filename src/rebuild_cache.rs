@@ -17,17 +17,23 @@ pub async fn rebuild_cache(config: BackupConfig, limit: &[i32]) {
     let bucket = store.init().await;
     let prefix_len = store.data_prefix.as_str().len();
     let mut count = 0;
+    let mut large_objects = 0;
     let mut no_more = false;
     let mut marker: Option<String> = None;
     while !no_more {
       let objects = bucket.list(Some(store.data_prefix.as_str()), marker.as_deref()).await.unwrap();
       count += objects.len();
+      large_objects += objects.iter().filter(|o| o.slo_etag.is_some()).count();
       no_more = objects.is_empty();
       marker = objects.last().map(|m| m.name.to_owned());
       for object in objects {
-        cache.set_data_in_cold_storage(&object.name[prefix_len..], &object.hash, &vec![store.id]).await.unwrap();
+        // Only keep hashes that are real MD5s of the encrypted object; SLO
+        // manifests, multipart S3 uploads and most WebDAV servers report
+        // something else.
+        let md5 = Some(object.hash.as_str()).filter(|_| bucket.hash_is_md5(&object));
+        cache.set_data_in_cold_storage(&object.name[prefix_len..], md5, &vec![store.id]).await.unwrap();
       }
     }
-    info!("Added {} files from store {}", count, store.id);
+    info!("Added {} files ({} segmented) from store {}", count, large_objects, store.id);
   }
 }
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::collections::HashSet;
+use std::fs::{create_dir_all, remove_dir_all, File};
+use std::path::Path;
+use std::time::Duration;
+
+use futures::StreamExt;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{error, info, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use sequoia_openpgp::Cert;
+use sequoia_openpgp::parse::Parse;
+
+use crate::bucket::Bucket;
+use crate::config::BackupConfig;
+use crate::list::backup_names;
+use crate::metadata_file::MetadataReader;
+use crate::restore::fetch_metadata;
+use crate::sqlite_cache::AsyncCache;
+
+/// Copies an encrypted object from `source` to `target` as is, through the
+/// temporary file `temp`.
+async fn copy_object(source: &Bucket, source_key: &str, target: &Bucket, target_key: &str, md5: Option<&str>, temp: &Path) -> Result<(), String> {
+  let result = async {
+    let dest = File::create(temp).map_err(|e| format!("failed to create {:?}: {}", temp, e))?;
+    source.download(source_key, dest).await.map_err(|e| format!("download failed: {}", e))?;
+    let file = File::open(temp).map_err(|e| format!("failed to open {:?}: {}", temp, e))?;
+    target.upload_with_progress(target_key, file, md5, |_| {}).await.map_err(|e| format!("upload failed: {}", e))
+  }.await;
+  let _ = std::fs::remove_file(temp);
+  result
+}
+
+/// Copies backup `name` — or every backup on the source store when `name` is
+/// `None` — from store `from` to store `to`.
+///
+/// The metadata file is decrypted to find the data objects the backup needs;
+/// those missing from the target (according to a listing of its data prefix)
+/// are copied without being decrypted, followed by the metadata file itself.
+/// The metadata file is only copied once all of its data is on the target.
+/// Copied objects are recorded in the local cache.  Returns false if anything
+/// could not be copied.
+pub async fn replicate(config: &BackupConfig, name: Option<&str>, from: i32, to: i32, mp: MultiProgress) -> bool {
+  let find_store = |id: i32| config.stores.iter().find(|s| s.id == id)
+    .unwrap_or_else(|| panic!("No store with id {}", id));
+  if from == to {
+    error!("The source and target store must differ");
+    return false;
+  }
+  let (source_store, target_store) = (find_store(from), find_store(to));
+  if !source_store.upload_metadata || !source_store.upload_data {
+    error!("Store {} does not hold both data and metadata and cannot be replicated from", from);
+    return false;
+  }
+  let source = source_store.init().await;
+  let target = target_store.init().await;
+  let key = Cert::from_file(&config.encrypting_key_file).unwrap();
+
+  let names: Vec<String> = match name {
+    Some(name) => vec![name.to_string()],
+    None => match backup_names(source_store, &source).await {
+      Ok(names) => names.into_iter().collect(),
+      Err(e) => {
+        error!("Failed to list backups on store {}: {}", from, e);
+        return false;
+      }
+    },
+  };
+
+  // One listing of each target prefix instead of an existence check per object.
+  let mut target_data: HashSet<String> = HashSet::new();
+  if target_store.upload_data {
+    match target.list_all(&target_store.data_prefix).await {
+      Ok(objects) => target_data.extend(objects.into_iter()
+        .filter_map(|o| o.name.strip_prefix(target_store.data_prefix.as_str()).map(|h| h.to_string()))),
+      Err(e) => {
+        error!("Failed to list data on store {}: {}", to, e);
+        return false;
+      }
+    }
+  }
+  let target_backups = match backup_names(target_store, &target).await {
+    Ok(names) => names,
+    Err(e) => {
+      error!("Failed to list backups on store {}: {}", to, e);
+      return false;
+    }
+  };
+
+  let tmp_suffix: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(8)
+    .map(char::from)
+    .collect();
+  let tmp_dir = std::env::temp_dir().join(format!("backup-replicate-{}", tmp_suffix));
+  create_dir_all(&tmp_dir).unwrap();
+
+  let cache = AsyncCache::new().await;
+  let pb = mp.add(ProgressBar::new_spinner());
+  pb.set_style(
+    ProgressStyle::with_template("{prefix:.bold.dim} {spinner:.green} [{elapsed_precise}] {pos} objects copied {msg}")
+      .unwrap()
+      .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "),
+  );
+  pb.set_prefix("[Replicate]");
+  pb.enable_steady_tick(Duration::from_millis(80));
+
+  let mut backups_copied = 0u64;
+  let mut failures = 0u64;
+  for name in &names {
+    pb.set_message(name.clone());
+    let encrypted_metadata = tmp_dir.join(format!("{}.metadata", name));
+    let metadata_file = tmp_dir.join(format!("{}.sqlite", name));
+    if let Err(e) = fetch_metadata(&source, &source_store.metadata_prefix, name, &encrypted_metadata, &metadata_file, &key, &config.signing_key_file).await {
+      error!("Failed to read the metadata of {} from store {}: {}", name, from, e);
+      failures += 1;
+      continue;
+    }
+    let hashes = MetadataReader::new(metadata_file.clone()).await.data_hashes().await;
+    std::fs::remove_file(&metadata_file).unwrap();
+
+    let mut data_complete = true;
+    if target_store.upload_data {
+      let missing: Vec<String> = hashes.into_iter().filter(|h| !target_data.contains(h)).collect();
+      let copies: Vec<(String, Option<String>, Result<(), String>)> = futures::stream::iter(missing)
+        .map(|hash| {
+          let (source, target, cache, tmp_dir) = (&source, &target, &cache, &tmp_dir);
+          async move {
+            let md5 = cache.encrypted_md5(&hash, from).await.unwrap_or(None);
+            let source_key = format!("{}{}", source_store.data_prefix, hash);
+            let target_key = format!("{}{}", target_store.data_prefix, hash);
+            let result = copy_object(source, &source_key, target, &target_key, md5.as_deref(), &tmp_dir.join(&hash)).await;
+            (hash, md5, result)
+          }
+        })
+        .buffer_unordered(4)
+        .collect()
+        .await;
+      for (hash, md5, result) in copies {
+        match result {
+          Ok(()) => {
+            cache.set_data_in_cold_storage(&hash, md5.as_deref(), &vec![to]).await.unwrap();
+            target_data.insert(hash);
+            pb.inc(1);
+          }
+          Err(e) => {
+            error!("Failed to copy {} from store {} to store {}: {}", &hash[..16], from, to, e);
+            data_complete = false;
+            failures += 1;
+          }
+        }
+      }
+    }
+
+    if target_store.upload_metadata && !target_backups.contains(name) {
+      if !data_complete {
+        warn!("Not copying the metadata of {} to store {} because some of its data is missing there", name, to);
+      } else {
+        let target_key = format!("{}{}.metadata", target_store.metadata_prefix, name);
+        let uploaded = match File::open(&encrypted_metadata) {
+          Ok(file) => target.upload_with_progress(&target_key, file, None, |_| {}).await,
+          Err(e) => Err(e.to_string()),
+        };
+        match uploaded {
+          Ok(()) => {
+            info!("Copied {} to store {}", name, to);
+            backups_copied += 1;
+            pb.inc(1);
+          }
+          Err(e) => {
+            error!("Failed to copy the metadata of {} to store {}: {}", name, to, e);
+            failures += 1;
+          }
+        }
+      }
+    }
+    std::fs::remove_file(&encrypted_metadata).unwrap();
+  }
+
+  cache.close().await;
+  remove_dir_all(&tmp_dir).unwrap();
+
+  if failures == 0 {
+    pb.finish_with_message(format!("— done, {} of {} backup(s) newly copied", backups_copied, names.len()));
+    true
+  } else {
+    pb.finish_with_message(format!("— FAILED: {} object(s) could not be copied", failures));
+    false
+  }
+}
@@ -1,22 +1,26 @@
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::os::unix::prelude::PermissionsExt;
 use std::time::Duration;
 use std::path::{Component, PathBuf, Path};
+use std::io::{Read, Seek, SeekFrom};
 
 use futures::StreamExt;
-use log::{trace, error, info};
+use log::{trace, error, info, warn};
 use sha2::{Sha256, Digest};
 use sequoia_openpgp::Cert;
 use sequoia_openpgp::parse::Parse;
 use crate::{datastore, hash};
 use datastore::DataStore;
-use crate::metadata_file::FileMetadata;
+use crate::metadata_file::{Content, FileMetadata};
 use crate::decryption;
 use std::fs::{File, set_permissions, create_dir_all, remove_dir_all};
 use std::os::unix::fs::symlink;
 use crate::filetype;
 use filetype::FileType;
-use crate::bucket::Bucket;
+use crate::bucket::{Bucket, Transfer};
+use crate::sqlite_cache::AsyncCache;
 use crate::utils::humanise_bytes;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use filetime::{set_file_mtime, set_symlink_file_times, FileTime};
@@ -53,15 +57,30 @@ fn safe_relative_path(name: &str) -> Option<PathBuf> {
     Some(result)
 }
 
+/// Makes `entry` relative to the named `root`, or returns `None` if it is
+/// outside it.  Without a root every entry is kept as is.
+fn within_root(mut entry: FileMetadata, root: Option<&str>) -> Option<FileMetadata> {
+  let Some(root) = root else { return Some(entry) };
+  if entry.name == root {
+    entry.name = String::new();
+  } else {
+    entry.name = entry.name.strip_prefix(root)?.strip_prefix('/')?.to_string();
+  }
+  Some(entry)
+}
+
 /// SHA-256 of the file at `path`, returned as a lowercase hex string.
-fn sha256_file(path: &PathBuf) -> String {
+fn sha256_file(path: &Path) -> String {
   let mut file = File::open(path).unwrap();
   let mut hasher = Sha256::new();
   std::io::copy(&mut file, &mut hasher).unwrap();
   format!("{:x}", hasher.finalize())
 }
 
-async fn download_file(data_hash: &str, destination: PathBuf, bucket: &Bucket, data_prefix: &str, cert: &Cert, cache: &PathBuf, hmac_secret: &String, mp: &MultiProgress) {
+/// Downloads, decrypts and verifies one data object from one store.  The
+/// destination is only created if the object passes the hash check.
+#[allow(clippy::too_many_arguments)]
+async fn download_file(data_hash: &str, destination: &Path, bucket: &Bucket, data_prefix: &str, cert: &Cert, cache: &Path, hmac_secret: &str, mp: &MultiProgress) -> Result<(), String> {
   // todo: avoid repeating downloads
 
   // Use a short random suffix so that concurrent tasks downloading the same
@@ -87,34 +106,196 @@ async fn download_file(data_hash: &str, destination: PathBuf, bucket: &Bucket, d
   let encrypted_file = File::create(&encrypted_temp).unwrap();
   let key = format!("{}{}", data_prefix, data_hash);
   let pb_cb = pb.clone();
-  bucket.download_with_progress(key.as_str(), encrypted_file, move |bytes| {
-    pb_cb.inc(bytes as u64);
-  }).await.unwrap();
+  let short_hash = data_hash[..16].to_string();
+  let downloaded = bucket.download_with_progress(key.as_str(), encrypted_file, move |event| match event {
+    Transfer::Bytes(bytes) => pb_cb.inc(bytes as u64),
+    Transfer::Retry { attempt, max_attempts, .. } => {
+      pb_cb.set_position(0);
+      pb_cb.set_message(format!("{} (retry {}/{})", short_hash, attempt + 1, max_attempts));
+    }
+  }).await;
   pb.finish_and_clear();
+  if let Err(e) = downloaded {
+    std::fs::remove_file(&encrypted_temp).unwrap();
+    return Err(format!("download of {} failed: {}", key, e));
+  }
   trace!("downloaded {:?}", encrypted_temp);
 
   // Decrypt into a temp file so the final path only appears once the hash
   // check has passed.
-  {
+  let decrypted = {
     let mut source = File::open(&encrypted_temp).unwrap();
     let mut dest = File::create(&decrypted_temp).unwrap();
-    decryption::decrypt_file(&mut source, &mut dest, cert, None).unwrap();
-  }
+    decryption::decrypt_file(&mut source, &mut dest, cert, None)
+  };
   std::fs::remove_file(&encrypted_temp).unwrap();
+  if let Err(e) = decrypted {
+    std::fs::remove_file(&decrypted_temp).unwrap();
+    return Err(format!("decryption of {} failed: {}", key, e));
+  }
 
   if hash::data(&decrypted_temp, hmac_secret) != data_hash {
     std::fs::remove_file(&decrypted_temp).unwrap();
-    panic!("Data hash did not match for {:?}", destination);
+    return Err(format!("data hash did not match for {}", key));
   }
 
-  std::fs::rename(&decrypted_temp, &destination).unwrap();
+  std::fs::rename(&decrypted_temp, destination).unwrap();
   trace!("restored {:?}", destination);
+  Ok(())
+}
+
+/// Restores `data_hash` to `destination` from the first of `sources` that
+/// holds an intact copy, returning that store's id.  A store whose copy is
+/// missing, unreachable or corrupt is skipped.
+async fn download_with_failover(data_hash: &str, destination: &Path, sources: &[(i32, String, Bucket)], cert: &Cert, cache: &Path, hmac_secret: &str, mp: &MultiProgress) -> Result<i32, String> {
+  let mut failures: Vec<String> = Vec::new();
+  for (store_id, data_prefix, bucket) in sources {
+    match download_file(data_hash, destination, bucket, data_prefix, cert, cache, hmac_secret, mp).await {
+      Ok(()) => return Ok(*store_id),
+      Err(e) => {
+        warn!("Store {} could not provide {}: {}", store_id, &data_hash[..16], e);
+        failures.push(format!("store {}: {}", store_id, e));
+      }
+    }
+  }
+  Err(failures.join("; "))
 }
 
-pub async fn process_file(entry: &FileMetadata, destination: PathBuf, data_bucket: &Bucket, data_prefix: &str, data_cache: &PathBuf, key: &Cert, hmac_secret: &String, mp: &MultiProgress) -> i64 {
+/// Restores a chunked file to `destination` by fetching each of `chunks` from
+/// the first of `sources` holding an intact copy and joining them; the result
+/// must hash to `data_hash`.  Returns the id of the store that served the
+/// chunks, or of a fallback store if any chunk needed one.
+#[allow(clippy::too_many_arguments)]
+async fn download_chunks(data_hash: &str, chunks: &[String], destination: &Path, sources: &[(i32, String, Bucket)], cert: &Cert, cache: &Path, hmac_secret: &str, mp: &MultiProgress) -> Result<i32, String> {
+  let random_suffix: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(4)
+    .map(char::from)
+    .collect();
+  let joined_temp = cache.join(format!("{}{}.joined", data_hash, random_suffix));
+  let chunk_temp = cache.join(format!("{}{}.chunk", data_hash, random_suffix));
+
+  let result = async {
+    let mut joined = File::create(&joined_temp).unwrap();
+    let mut store_used = sources[0].0;
+    for chunk in chunks {
+      let store_id = download_with_failover(chunk, &chunk_temp, sources, cert, cache, hmac_secret, mp).await
+        .map_err(|e| format!("chunk {}: {}", &chunk[..16], e))?;
+      if store_id != sources[0].0 {
+        store_used = store_id;
+      }
+      std::io::copy(&mut File::open(&chunk_temp).unwrap(), &mut joined).unwrap();
+      std::fs::remove_file(&chunk_temp).unwrap();
+    }
+    if hash::data(&joined_temp, hmac_secret) != data_hash {
+      return Err(format!("data hash did not match after joining {} chunks", chunks.len()));
+    }
+    Ok(store_used)
+  }.await;
+
+  match result {
+    Ok(_) => std::fs::rename(&joined_temp, destination).unwrap(),
+    Err(_) => std::fs::remove_file(&joined_temp).unwrap(),
+  }
+  result
+}
+
+/// The packs fetched during a restore.  A pack's data object is encrypted as
+/// a whole and cannot be read at an offset, so each pack is downloaded once,
+/// kept decrypted in the temporary directory while its files are restored,
+/// and deleted after the last of them.
+pub struct PackCache<'a> {
+  sources: &'a [(i32, String, Bucket)],
+  cert: &'a Cert,
+  cache: &'a PathBuf,
+  hmac_secret: &'a String,
+  mp: &'a MultiProgress,
+  /// The files of each pack still to be restored.
+  remaining: Mutex<HashMap<String, u64>>,
+  fetched: Mutex<HashMap<String, Arc<FetchedPack>>>,
+}
+
+/// A pack's decrypted plaintext and the store it came from, once fetched.
+type FetchedPack = tokio::sync::OnceCell<Result<(PathBuf, i32), String>>;
+
+impl<'a> PackCache<'a> {
+  /// `members` counts the files of each pack, as
+  /// [`pack_members`](crate::metadata_file::MetadataReader::pack_members)
+  /// does.
+  pub fn new(members: HashMap<String, u64>, sources: &'a [(i32, String, Bucket)], cert: &'a Cert, cache: &'a PathBuf, hmac_secret: &'a String, mp: &'a MultiProgress) -> PackCache<'a> {
+    PackCache { sources, cert, cache, hmac_secret, mp, remaining: Mutex::new(members), fetched: Mutex::new(HashMap::new()) }
+  }
+
+  /// Restores the `length` bytes at `offset` in `pack` to `destination`; they
+  /// must hash to `data_hash`.  Returns the id of the store that served the
+  /// pack.
+  async fn extract(&self, pack: &str, offset: u64, length: u64, data_hash: &str, destination: &Path) -> Result<i32, String> {
+    let cell = Arc::clone(self.fetched.lock().unwrap().entry(pack.to_string()).or_default());
+    let fetched = cell.get_or_init(|| async {
+      let path = self.cache.join(format!("{}.pack", pack));
+      download_with_failover(pack, &path, self.sources, self.cert, self.cache, self.hmac_secret, self.mp).await
+        .map(|store_id| (path, store_id))
+    }).await;
+
+    let result = match fetched {
+      Ok((path, store_id)) => {
+        let random_suffix: String = rand::thread_rng()
+          .sample_iter(&Alphanumeric)
+          .take(4)
+          .map(char::from)
+          .collect();
+        let extracted_temp = self.cache.join(format!("{}{}.plain", data_hash, random_suffix));
+        let mut source = File::open(path).unwrap();
+        source.seek(SeekFrom::Start(offset)).unwrap();
+        std::io::copy(&mut source.take(length), &mut File::create(&extracted_temp).unwrap()).unwrap();
+        if hash::data(&extracted_temp, self.hmac_secret) == data_hash {
+          std::fs::rename(&extracted_temp, destination).unwrap();
+          Ok(*store_id)
+        } else {
+          std::fs::remove_file(&extracted_temp).unwrap();
+          Err(format!("data hash did not match at offset {} of pack {}", offset, &pack[..16]))
+        }
+      }
+      Err(e) => Err(format!("pack {}: {}", &pack[..16], e)),
+    };
+
+    let mut remaining = self.remaining.lock().unwrap();
+    if let Some(count) = remaining.get_mut(pack) {
+      *count -= 1;
+      if *count == 0 {
+        remaining.remove(pack);
+        if let Ok((path, _)) = fetched {
+          std::fs::remove_file(path).unwrap();
+        }
+      }
+    }
+    result
+  }
+}
+
+/// What [`process_file`] did with a metadata entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restored {
+  /// The entry was rejected and nothing was written.
+  Skipped,
+  /// Created without downloading anything: an empty file, a symlink, or a
+  /// directory (left to the second pass).
+  Local,
+  /// The file's contents were downloaded from this store.
+  FromStore(i32),
+  /// No store could provide the file's contents.
+  Failed,
+}
+
+/// Restores one metadata entry under `destination`, fetching file contents
+/// from the first of `data_sources` (store id, data prefix, bucket) that can
+/// provide them.  `content` says where the file's contents are stored; those
+/// of packed files are taken from `packs`.
+#[allow(clippy::too_many_arguments)]
+pub async fn process_file(entry: &FileMetadata, content: &Content, packs: &PackCache<'_>, destination: PathBuf, data_sources: &[(i32, String, Bucket)], data_cache: &Path, key: &Cert, hmac_secret: &str, mp: &MultiProgress) -> Restored {
   let rel = match safe_relative_path(entry.name.as_str()) {
     Some(p) => p,
-    None => return 0,
+    None => return Restored::Skipped,
   };
   let path = destination.join(&rel);
   match entry.ttype {
@@ -131,22 +312,27 @@ pub async fn process_file(entry: &FileMetadata, destination: PathBuf, data_bucke
             File::create(&path).unwrap();
             set_file_mtime(&path, mtime).unwrap();
             set_permissions(&path, permissions).unwrap();
+            Restored::Local
           }
-          Some(data_hash) => {            download_file(
-              data_hash.as_str(),
-              path.clone(),
-              &data_bucket,
-              data_prefix,
-              &key,
-              &data_cache,
-              hmac_secret,
-              mp,
-            ).await;
-            set_file_mtime(&path, mtime).unwrap();
-            set_permissions(&path, permissions).unwrap();
+          Some(data_hash) => {
+            let downloaded = match content {
+              Content::Object => download_with_failover(data_hash.as_str(), &path, data_sources, key, data_cache, hmac_secret, mp).await,
+              Content::Chunks(chunks) => download_chunks(data_hash.as_str(), chunks, &path, data_sources, key, data_cache, hmac_secret, mp).await,
+              Content::Packed { pack, offset, length } => packs.extract(pack, *offset, *length, data_hash.as_str(), &path).await,
+            };
+            match downloaded {
+              Ok(store_id) => {
+                set_file_mtime(&path, mtime).unwrap();
+                set_permissions(&path, permissions).unwrap();
+                Restored::FromStore(store_id)
+              }
+              Err(e) => {
+                error!("FAILED  file={}  hash={} (no store could provide it: {})", entry.name, &data_hash[..16], e);
+                Restored::Failed
+              }
+            }
           }
         }
-        1
       }
       FileType::SYMLINK => {
         trace!("Creating symlink {:?} -> {:?}", &path, entry.destination);
@@ -159,19 +345,144 @@ pub async fn process_file(entry: &FileMetadata, destination: PathBuf, data_bucke
         // and cannot be set via std::fs::set_permissions.
         let mtime = FileTime::from_unix_time(entry.mtime, 0);
         set_symlink_file_times(&path, mtime, mtime).unwrap();
-        1
+        Restored::Local
       }
       FileType::DIRECTORY => {
         // Directories that contain files or symlinks are created as needed with default mtime and permissions, 
         // then updated in a second pass after all content is in place. This avoids issues with mtimes 
         // being updated as files are written into the directory. Empty directories are created with 
         // the correct mtime and permissions in the second pass.
-        1
+        Restored::Local
+      }
+    }
+}
+
+/// Which data objects `validate` downloads and decrypts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataSample {
+  /// Every object.
+  All,
+  /// Each object with this probability (`5%`).
+  Percent(f64),
+  /// Group `n` of `m` (`n/m`): objects are split into `m` groups by hash, so
+  /// running with `1/m` … `m/m` in turn reads everything once.
+  Group { n: u64, m: u64 },
+}
+
+impl FromStr for DataSample {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<DataSample, String> {
+    if let Some(percent) = s.strip_suffix('%') {
+      match percent.trim().parse::<f64>() {
+        Ok(p) if p > 0.0 && p <= 100.0 => Ok(DataSample::Percent(p)),
+        _ => Err(format!("Invalid percentage {:?} (expected e.g. 5%)", s)),
+      }
+    } else if let Some((n, m)) = s.split_once('/') {
+      match (n.trim().parse::<u64>(), m.trim().parse::<u64>()) {
+        (Ok(n), Ok(m)) if n >= 1 && n <= m => Ok(DataSample::Group { n, m }),
+        _ => Err(format!("Invalid subset {:?} (expected n/m with 1 <= n <= m)", s)),
+      }
+    } else {
+      Err(format!("Invalid subset {:?} (expected a percentage such as 5% or n/m)", s))
+    }
+  }
+}
+
+impl DataSample {
+  fn includes(&self, data_hash: &str) -> bool {
+    match self {
+      DataSample::All => true,
+      DataSample::Percent(p) => rand::thread_rng().gen_bool(p / 100.0),
+      DataSample::Group { n, m } => {
+        let bucket = data_hash.get(..15).and_then(|h| u64::from_str_radix(h, 16).ok()).unwrap_or(0);
+        bucket % m == n - 1
+      }
+    }
+  }
+}
+
+/// Result of reading back one data object.
+enum DataCheck {
+  Intact,
+  /// The object was read but did not decrypt to the expected content.
+  Corrupt(String),
+  /// The object could not be read.
+  Failed(String),
+}
+
+/// Downloads the object `key`, decrypts it and checks that the plaintext
+/// hashes to `data_hash`.  Only the ciphertext is written to `tmp_dir`; the
+/// plaintext is hashed as it is decrypted.
+async fn read_data(bucket: &Bucket, key: &str, data_hash: &str, cert: &Cert, hmac_secret: &str, tmp_dir: &Path) -> DataCheck {
+  let random_suffix: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(4)
+    .map(char::from)
+    .collect();
+  let encrypted_temp = tmp_dir.join(format!("{}{}.gpg", data_hash, random_suffix));
+  let encrypted_file = File::create(&encrypted_temp).unwrap();
+  let check = match bucket.download(key, encrypted_file).await {
+    Err(e) => DataCheck::Failed(format!("download failed: {}", e)),
+    Ok(_) => {
+      let (path, cert, hmac_secret) = (encrypted_temp.clone(), cert.clone(), hmac_secret.to_string());
+      let decrypted = tokio::task::spawn_blocking(move || {
+        let mut source = File::open(&path).unwrap();
+        let mut hasher = hash::DataHasher::new(&hmac_secret);
+        decryption::decrypt_to_writer(&mut source, &mut hasher, &cert, None).map(|_| hasher.finish())
+      }).await.expect("Panic while decrypting");
+      match decrypted {
+        Err(e) => DataCheck::Corrupt(format!("decryption failed: {}", e)),
+        Ok(actual) if actual != data_hash => DataCheck::Corrupt(format!("content hashes to {:.16}", actual)),
+        Ok(_) => DataCheck::Intact,
       }
     }
+  };
+  std::fs::remove_file(&encrypted_temp).unwrap();
+  check
 }
 
-pub async fn validate_backup(backup: &str, stores: &[DataStore], key_file: PathBuf, signing_key_file: &Option<PathBuf>, mp: MultiProgress) -> bool {
+/// Per-file results of `validate`, summed over the backup.
+#[derive(Default)]
+struct ValidationCounts {
+  files: u64,
+  /// Data objects of the files: one per whole file, one per chunk.
+  objects: u64,
+  /// Definitive 404 responses.
+  missing: u64,
+  /// Objects whose stored checksum differs from the MD5 recorded at upload,
+  /// or that did not decrypt to their content hash.
+  corrupt: u64,
+  /// Unexpected statuses or request failures, which may indicate auth or
+  /// store outages rather than absent data.
+  errors: u64,
+  /// Objects downloaded and decrypted.
+  read: u64,
+  corrupt_by_store: BTreeMap<i32, u64>,
+}
+
+impl ValidationCounts {
+  fn add(mut self, other: ValidationCounts) -> ValidationCounts {
+    self.files += other.files;
+    self.objects += other.objects;
+    self.missing += other.missing;
+    self.corrupt += other.corrupt;
+    self.errors += other.errors;
+    self.read += other.read;
+    for (store_id, count) in other.corrupt_by_store {
+      *self.corrupt_by_store.entry(store_id).or_default() += count;
+    }
+    self
+  }
+}
+
+/// Checks that backup `backup` is complete on `stores`: that the metadata
+/// file is identical everywhere and every data object exists (with the
+/// checksum recorded at upload, where known).  With `read_data`, the selected
+/// data objects are also downloaded, decrypted and hashed with `hmac_secret`.
+/// `parallel` files are checked at once.
+#[allow(clippy::too_many_arguments)]
+pub async fn validate_backup(backup: &str, stores: &[DataStore], key_file: PathBuf, signing_key_file: &Option<PathBuf>, hmac_secret: &str, read_data_sample: Option<DataSample>, parallel: usize, mp: MultiProgress) -> bool {
   assert!(!stores.is_empty(), "At least one store is required");
 
   // Partition the store list so that metadata-only mirrors are not checked
@@ -274,71 +585,162 @@ pub async fn validate_backup(backup: &str, stores: &[DataStore], key_file: PathB
     info!("No stores with upload_data=true in the selected set — skipping data object checks");
   }
 
+  // The local cache holds the MD5 of each encrypted object as uploaded.  On a
+  // machine that never ran a backup there is none, and checksums are not
+  // compared.
+  let cache = AsyncCache::open_existing().await;
+  if cache.is_none() {
+    info!("No local cache found; not comparing checksums recorded at upload");
+  }
+
+  // Objects already read back from a store, so that files sharing content
+  // are only downloaded once.
+  let read_checked: Arc<Mutex<HashSet<(i32, String)>>> = Arc::new(Mutex::new(HashSet::new()));
+  let (cert, tmp_dir, reader) = (&key, &tmp_dir, &metadata_reader);
+
   // Stream metadata entries directly; check each FILE's hash against every
-  // store concurrently. Folding into (total, missing, errors) avoids
-  // collecting the full file list into memory. `missing` counts definitive
-  // 404 responses; `errors` counts unexpected statuses / request failures
-  // that may indicate auth or Swift outages rather than absent data.
-  let (total_files, missing, errors): (u64, u64, u64) = metadata_reader.read(false).await
+  // store concurrently. Folding into the counts avoids collecting the full
+  // file list into memory.
+  let counts: ValidationCounts = metadata_reader.read(false).await
     .filter(|e| futures::future::ready(matches!(&e.ttype, FileType::FILE) && e.data_hash.is_some()))
     .map(|e| {
       let buckets = Arc::clone(&buckets);
+      let cache = cache.clone();
+      let read_checked = Arc::clone(&read_checked);
       async move {
-        let data_hash = e.data_hash.unwrap();
-        let mut file_missing: u64 = 0;
-        let mut file_errors: u64 = 0;
-        for (store_id, data_prefix, bucket) in buckets.iter() {
-          let key = format!("{}{}", data_prefix, data_hash);
-          match bucket.exists(&key).await {
-            Ok(true) => {
-              trace!("OK  store={}  hash={}", store_id, &data_hash[..16]);
-            }
-            Ok(false) => {
-              error!("MISSING  store={}  hash={}  file={}", store_id, &data_hash[..16], e.name);
-              file_missing += 1;
+        // A whole file is one data object, a chunked file one per chunk and
+        // a packed file is checked through its pack.
+        let objects = match reader.content(e.uid).await {
+          Content::Object => vec![e.data_hash.unwrap()],
+          Content::Chunks(chunks) => chunks,
+          Content::Packed { pack, .. } => vec![pack],
+        };
+        let mut counts = ValidationCounts { files: 1, objects: objects.len() as u64, ..ValidationCounts::default() };
+        for data_hash in objects {
+          let read_this = read_data_sample.is_some_and(|sample| sample.includes(&data_hash));
+          for (store_id, data_prefix, bucket) in buckets.iter() {
+            let key = format!("{}{}", data_prefix, data_hash);
+            let mut corrupt = false;
+            match bucket.stat(&key).await {
+              Ok(Some(entry)) => {
+                let expected = match &cache {
+                  Some(cache) if bucket.hash_is_md5(&entry) => cache.encrypted_md5(&data_hash, *store_id).await.unwrap_or(None),
+                  _ => None,
+                };
+                match expected {
+                  Some(md5) if !md5.eq_ignore_ascii_case(&entry.hash) => {
+                    error!("CHECKSUM MISMATCH  store={}  hash={}  file={}  expected md5={}  stored={}",
+                           store_id, &data_hash[..16], e.name, md5, entry.hash);
+                    corrupt = true;
+                  }
+                  _ => trace!("OK  store={}  hash={}", store_id, &data_hash[..16]),
+                }
+                if !corrupt && read_this && read_checked.lock().unwrap().insert((*store_id, data_hash.clone())) {
+                  counts.read += 1;
+                  match read_data(bucket, &key, &data_hash, cert, hmac_secret, tmp_dir).await {
+                    DataCheck::Intact => trace!("READ OK  store={}  hash={}", store_id, &data_hash[..16]),
+                    DataCheck::Corrupt(reason) => {
+                      error!("CORRUPT  store={}  hash={}  file={} ({})", store_id, &data_hash[..16], e.name, reason);
+                      corrupt = true;
+                    }
+                    DataCheck::Failed(reason) => {
+                      error!("ERROR  store={}  hash={}  file={} (could not read: {})", store_id, &data_hash[..16], e.name, reason);
+                      counts.errors += 1;
+                    }
+                  }
+                }
+              }
+              Ok(None) => {
+                error!("MISSING  store={}  hash={}  file={}", store_id, &data_hash[..16], e.name);
+                counts.missing += 1;
+              }
+              Err(err) => {
+                error!("ERROR  store={}  hash={}  file={} (could not verify: {})", store_id, &data_hash[..16], e.name, err);
+                counts.errors += 1;
+              }
             }
-            Err(_) => {
-              // exists() has already logged the status/error detail.
-              error!("ERROR  store={}  hash={}  file={} (could not verify — see above)", store_id, &data_hash[..16], e.name);
-              file_errors += 1;
+            if corrupt {
+              counts.corrupt += 1;
+              *counts.corrupt_by_store.entry(*store_id).or_default() += 1;
             }
           }
         }
-        (1u64, file_missing, file_errors)
+        counts
       }
     })
-    .buffer_unordered(16)
-    .fold((0u64, 0u64, 0u64), |acc, (t, m, e)| {
+    .buffer_unordered(parallel)
+    .fold(ValidationCounts::default(), |acc, counts| {
       checker_pb.inc(1);
-      futures::future::ready((acc.0 + t, acc.1 + m, acc.2 + e))
+      futures::future::ready(acc.add(counts))
     })
     .await;
 
-  remove_dir_all(&tmp_dir).unwrap();
+  if let Some(cache) = cache {
+    cache.close().await;
+  }
+  remove_dir_all(tmp_dir).unwrap();
+
+  if read_data_sample.is_some() {
+    info!("Downloaded and decrypted {} data object(s)", counts.read);
+  }
+  for (store_id, corrupt) in &counts.corrupt_by_store {
+    error!("Store {}: {} corrupt object(s)", store_id, corrupt);
+  }
 
-  if missing == 0 && errors == 0 {
+  if counts.missing == 0 && counts.corrupt == 0 && counts.errors == 0 {
     let data_store_count = data_stores.len();
     checker_pb.finish_with_message(format!(" — passed ({} metadata store(s), {} data store(s))", meta_stores.len(), data_store_count));
     true
   } else {
-    let total_checks = total_files * data_stores.len() as u64;
+    let total_checks = counts.objects * data_stores.len() as u64;
     let mut parts: Vec<String> = Vec::new();
-    if missing > 0 {
-      parts.push(format!("{}/{} missing", missing, total_checks));
+    if counts.missing > 0 {
+      parts.push(format!("{}/{} missing", counts.missing, total_checks));
     }
-    if errors > 0 {
-      parts.push(format!("{} check error(s) (auth/network/Swift failure)", errors));
+    if counts.corrupt > 0 {
+      parts.push(format!("{} corrupt (checksum mismatch or unreadable data)", counts.corrupt));
+    }
+    if counts.errors > 0 {
+      parts.push(format!("{} check error(s) (auth/network/Swift failure)", counts.errors));
     }
     checker_pb.finish_with_message(format!(" — FAILED: {}", parts.join(", ")));
     false
   }
 }
 
-pub async fn restore_backup(destination: PathBuf, backup: &String, metadata_store: &DataStore, data_store: &DataStore, key_file: PathBuf, hmac_secret: &String, signing_key_file: &Option<PathBuf>, mp: MultiProgress) {
+/// Downloads the metadata file of `backup` from `bucket` into
+/// `encrypted_metadata_file` and decrypts it into `metadata_file`.
+pub async fn fetch_metadata(bucket: &Bucket, metadata_prefix: &str, backup: &str, encrypted_metadata_file: &Path, metadata_file: &Path, key: &Cert, signing_key_file: &Option<PathBuf>) -> Result<(), String> {
+  {
+    let encrypted_file = File::create(encrypted_metadata_file).unwrap();
+    bucket.download(format!("{metadata_prefix}{backup}.metadata").as_str(), encrypted_file).await
+      .map_err(|e| format!("download failed: {}", e))?;
+  }
+
+  let mut source = File::open(encrypted_metadata_file).unwrap();
+  let mut dest = File::create(metadata_file).unwrap();
+  let signing_key = signing_key_file.clone().map(|x| Cert::from_file(x).unwrap());
+  decryption::decrypt_file(&mut source, &mut dest, key, signing_key)
+    .map_err(|e| format!("decryption failed: {}", e))?;
+  Ok(())
+}
+
+/// Restores `backup` into `destination`, which must not exist yet.  Each
+/// named root of the backup is restored into a subdirectory of the same
+/// name; with `root`, only that root is restored, directly into
+/// `destination`.
+///
+/// The metadata file is read from the first of `metadata_stores` that can
+/// provide it.  Each data object is fetched from the first of `data_stores`
+/// holding an intact copy; a store whose copy is missing, unreachable or
+/// fails the hash check is skipped in favour of the next.  `parallel` files
+/// are restored at once.  Returns false if anything could not be restored.
+#[allow(clippy::too_many_arguments)]
+pub async fn restore_backup(destination: PathBuf, backup: &String, root: Option<&str>, metadata_stores: &[DataStore], data_stores: &[DataStore], key_file: PathBuf, hmac_secret: &String, signing_key_file: &Option<PathBuf>, parallel: usize, mp: MultiProgress) -> bool {
 
   if destination.exists() {
     error!("Bailing because destination already exists");
-    return;
+    return false;
   }
 
   // Use a random suffix for the temp dir so it cannot collide with a
@@ -359,24 +761,51 @@ pub async fn restore_backup(destination: PathBuf, backup: &String, metadata_stor
     let encrypted_metadata_file = temporary_data_dir.join("metadata");
     
     trace!("creating {:?}", encrypted_metadata_file);
-    
-    {
-      let encrypted_file = File::create(&encrypted_metadata_file).unwrap();
-      let bucket = metadata_store.init().await;
-      let prefix = &metadata_store.metadata_prefix;
-      bucket.download(format!("{prefix}{backup}.metadata").as_str(), encrypted_file).await.unwrap();
+
+    let mut metadata_store_id = None;
+    for store in metadata_stores {
+      let fetched = match store.try_init().await {
+        Ok(bucket) => fetch_metadata(&bucket, &store.metadata_prefix, backup, &encrypted_metadata_file, &metadata_file, key, signing_key_file).await,
+        Err(e) => Err(e),
+      };
+      match fetched {
+        Ok(()) => {
+          metadata_store_id = Some(store.id);
+          break;
+        }
+        Err(e) => warn!("Could not read the metadata of {} from store {}: {}", backup, store.id, e),
+      }
     }
-    
-    {
-      let mut source = File::open(&encrypted_metadata_file).unwrap();
-      let mut dest = File::create(&metadata_file).unwrap();
-      let signing_key = signing_key_file.clone().map(|x| Cert::from_file(x).unwrap());
-      decryption::decrypt_file(&mut source, &mut dest, &key, signing_key).unwrap();
+    match metadata_store_id {
+      Some(store_id) => info!("Using metadata from store {}", store_id),
+      None => {
+        error!("No store could provide the metadata of {}", backup);
+        remove_dir_all(&destination).unwrap();
+        return false;
+      }
     }
   }
 
   let metadata_reader = crate::metadata_file::MetadataReader::new(metadata_file.clone()).await;
 
+  let roots = metadata_reader.roots().await;
+  match root {
+    Some(root) if !roots.iter().any(|(name, _)| name == root) => {
+      if roots.is_empty() {
+        error!("{} has no named roots", backup);
+      } else {
+        error!("{} has no root {:?}; its roots are {}", backup, root,
+          roots.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", "));
+      }
+      remove_dir_all(&destination).unwrap();
+      return false;
+    }
+    Some(root) => info!("Restoring root {}", root),
+    None => for (name, path) in &roots {
+      info!("Restoring root {} (backed up from {}) into {:?}", name, path, destination.join(name));
+    },
+  }
+
   let size: u64 = metadata_reader.read_metadata("size").await.parse().unwrap();
   info!("Backup is {}", humanise_bytes(size));
 
@@ -394,21 +823,76 @@ pub async fn restore_backup(destination: PathBuf, backup: &String, metadata_stor
   counter_pb.set_prefix("[Restore]");
   counter_pb.enable_steady_tick(Duration::from_millis(80));
 
+  // Stores that cannot be initialised (e.g. failed authentication) are left
+  // out rather than tried for every object.
+  let mut data_sources: Vec<(i32, String, Bucket)> = Vec::new();
+  for store in data_stores {
+    match store.try_init().await {
+      Ok(bucket) => data_sources.push((store.id, store.data_prefix.clone(), bucket)),
+      Err(e) => warn!("Not restoring data from store {}: {}", store.id, e),
+    }
+  }
+  if data_sources.is_empty() {
+    error!("No data store is available to restore from");
+    remove_dir_all(&destination).unwrap();
+    return false;
+  }
+  info!("Restoring data from store(s) {}", data_sources.iter().map(|(id, _, _)| id.to_string()).collect::<Vec<_>>().join(", "));
+
   let data_cache = &temporary_data_dir;
   trace!("Destination: {:?}", destination.as_path());
   let destination = &destination;
-  let data_bucket = &data_store.init().await;
+  let data_sources = &data_sources;
   let mp_ref = &mp;
+  let reader = &metadata_reader;
   let counter_inc = counter_pb.clone();
-  metadata_reader.read(false).await
+  // With a root, packs shared with files outside it are left for the removal
+  // of the temporary directory.
+  let packs = PackCache::new(metadata_reader.pack_members().await, data_sources, key, data_cache, hmac_secret, mp_ref);
+  let packs = &packs;
+  // Count objects per store and remember those that did not come from the
+  // first store, so the summary shows where the fallbacks were needed.
+  let (per_store, fallbacks, failed) = metadata_reader.read(false).await
+    .filter_map(|entry| futures::future::ready(within_root(entry, root)))
     .map(|entry| async move {
-      process_file(&entry, destination.clone(), &data_bucket, &data_store.data_prefix, &data_cache, &key, hmac_secret, mp_ref).await
+      let content = reader.content(entry.uid).await;
+      let restored = process_file(&entry, &content, packs, destination.clone(), data_sources, data_cache, key, hmac_secret, mp_ref).await;
+      (entry.name, restored)
+    })
+    .buffer_unordered(parallel)
+    .fold((HashMap::<i32, u64>::new(), Vec::<(String, i32)>::new(), 0u64), |(mut per_store, mut fallbacks, mut failed), (name, restored)| {
+      counter_inc.inc(1);
+      match restored {
+        Restored::FromStore(store_id) => {
+          *per_store.entry(store_id).or_default() += 1;
+          if store_id != data_sources[0].0 {
+            fallbacks.push((name, store_id));
+          }
+        }
+        Restored::Failed => failed += 1,
+        Restored::Skipped | Restored::Local => {}
+      }
+      futures::future::ready((per_store, fallbacks, failed))
     })
-    .buffer_unordered(4)
-    .for_each(|_| { counter_inc.inc(1); futures::future::ready(()) })
     .await;
 
-  counter_pb.finish_with_message("done");
+  if failed == 0 {
+    counter_pb.finish_with_message("done");
+  } else {
+    counter_pb.finish_with_message(format!("done, {} FAILED", failed));
+  }
+
+  for (store_id, _, _) in data_sources.iter() {
+    if let Some(count) = per_store.get(store_id) {
+      info!("{} object(s) restored from store {}", count, store_id);
+    }
+  }
+  if !fallbacks.is_empty() {
+    info!("{} object(s) restored from a fallback store:", fallbacks.len());
+    for (name, store_id) in &fallbacks {
+      info!("  store={}  file={}", store_id, name);
+    }
+  }
 
   // Second pass: create empty directories and apply mtimes + permissions to all directories.
   // Directory mtimes are updated whenever files or subdirectories are created
@@ -425,6 +909,7 @@ pub async fn restore_backup(destination: PathBuf, backup: &String, metadata_stor
       if entry.ttype != FileType::DIRECTORY {
         continue;
       }
+      let Some(entry) = within_root(entry, root) else { continue };
       let rel = match safe_relative_path(entry.name.as_str()) {
         Some(p) => p,
         None => continue,
@@ -455,4 +940,9 @@ pub async fn restore_backup(destination: PathBuf, backup: &String, metadata_stor
     let mtime = FileTime::from_unix_time(root.mtime, 0);
     set_file_mtime(destination.as_path(), mtime).unwrap();
   }
+
+  if failed > 0 {
+    error!("{} file(s) could not be restored from any store", failed);
+  }
+  failed == 0
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use log::warn;
+use rand::Rng;
+use crate::storage::StoreError;
+
+fn default_max_attempts() -> u32 { 5 }
+fn default_base_delay_ms() -> u64 { 500 }
+fn default_max_delay_ms() -> u64 { 30_000 }
+fn default_jitter() -> f64 { 0.5 }
+fn default_retryable_statuses() -> Vec<u16> { vec![408, 425, 429, 500, 502, 503, 504] }
+
+/// How store operations are retried, configured per store as `[stores.retry]`.
+///
+/// The delay before attempt `n + 1` is `base_delay_ms * 2^(n - 1)`, capped at
+/// `max_delay_ms`, and then shortened by a random fraction of up to `jitter`
+/// so that parallel uploads do not retry in lockstep.
+#[derive(Deserialize, Clone, Debug)]
+pub struct RetryPolicy {
+  /// Total number of attempts, including the first (1 disables retries).
+  #[serde(default = "default_max_attempts")]
+  pub max_attempts: u32,
+  #[serde(default = "default_base_delay_ms")]
+  pub base_delay_ms: u64,
+  #[serde(default = "default_max_delay_ms")]
+  pub max_delay_ms: u64,
+  /// Between 0 (no jitter) and 1.
+  #[serde(default = "default_jitter")]
+  pub jitter: f64,
+  /// HTTP statuses worth retrying.  Connection failures and timeouts are
+  /// always retried.
+  #[serde(default = "default_retryable_statuses")]
+  pub retryable_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> RetryPolicy {
+    RetryPolicy {
+      max_attempts: default_max_attempts(),
+      base_delay_ms: default_base_delay_ms(),
+      max_delay_ms: default_max_delay_ms(),
+      jitter: default_jitter(),
+      retryable_statuses: default_retryable_statuses(),
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// The delay after failed attempt number `attempt` (counting from 1).
+  pub fn delay(&self, attempt: u32) -> Duration {
+    let exponential = self.base_delay_ms.saturating_mul(1u64 << (attempt - 1).min(32));
+    let capped = Duration::from_millis(exponential.min(self.max_delay_ms));
+    let jitter = self.jitter.clamp(0.0, 1.0);
+    capped.mul_f64(1.0 - jitter * rand::thread_rng().gen::<f64>())
+  }
+
+  pub fn should_retry(&self, error: &StoreError) -> bool {
+    error.transient || error.status.is_some_and(|s| self.retryable_statuses.contains(&s))
+  }
+
+  /// Runs `operation` until it succeeds, fails with an error that is not
+  /// worth retrying, or `max_attempts` is reached.  `on_retry` is called
+  /// with the attempt that failed, the delay before the next one and the
+  /// error, before sleeping.
+  pub async fn run<T, E, F, Fut>(
+    &self,
+    description: &str,
+    mut operation: F,
+    on_retry: impl Fn(u32, Duration, &E),
+  ) -> Result<T, E>
+  where
+    E: Retryable + std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+  {
+    let mut attempt = 1;
+    loop {
+      match operation().await {
+        Ok(value) => return Ok(value),
+        Err(e) if attempt < self.max_attempts && e.is_retryable(self) => {
+          let delay = self.delay(attempt);
+          warn!("{} failed (attempt {}/{}), retrying in {:.1}s: {}",
+            description, attempt, self.max_attempts, delay.as_secs_f64(), e);
+          on_retry(attempt, delay, &e);
+          tokio::time::sleep(delay).await;
+          attempt += 1;
+        }
+        Err(e) => return Err(e),
+      }
+    }
+  }
+}
+
+/// Errors that [`RetryPolicy::run`] knows how to classify.
+pub trait Retryable {
+  fn is_retryable(&self, policy: &RetryPolicy) -> bool;
+}
+
+impl Retryable for StoreError {
+  fn is_retryable(&self, policy: &RetryPolicy) -> bool {
+    policy.should_retry(self)
+  }
+}
+
+impl Retryable for io::Error {
+  fn is_retryable(&self, policy: &RetryPolicy) -> bool {
+    if let Some(e) = self.get_ref().and_then(|e| e.downcast_ref::<StoreError>()) {
+      return policy.should_retry(e);
+    }
+    // Errors from reading a response body mean the connection dropped
+    // part-way through.
+    if self.get_ref().is_some_and(|e| e.is::<reqwest::Error>()) {
+      return true;
+    }
+    matches!(self.kind(),
+      io::ErrorKind::ConnectionReset
+      | io::ErrorKind::ConnectionAborted
+      | io::ErrorKind::ConnectionRefused
+      | io::ErrorKind::BrokenPipe
+      | io::ErrorKind::TimedOut
+      | io::ErrorKind::Interrupted
+      | io::ErrorKind::UnexpectedEof)
+  }
+}
@@ -0,0 +1,396 @@
+use std::fs::File;
+use std::io::{self, Write};
+use async_trait::async_trait;
+use base64::Engine;
+use futures::TryStreamExt;
+use futures::stream::StreamExt;
+use hmac::{Hmac, Mac};
+use log::error;
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use tokio_util::io::StreamReader;
+use chrono::Utc;
+use crate::bucket::ObjectEntry;
+use crate::throttle::{Direction, Throttle};
+use crate::storage::{ProgressCallback, StorageBackend, StoreError};
+use crate::utils::uri_encode;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+const EMPTY_PAYLOAD_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+fn default_region() -> String { "us-east-1".to_string() }
+
+/// Connection details for an S3-compatible store (AWS, MinIO, Ceph RGW, ...).
+#[derive(Deserialize, Clone, Debug)]
+pub struct S3Config {
+  /// Base URL of the S3 API, e.g. `https://s3.eu-west-1.amazonaws.com` or
+  /// `http://127.0.0.1:9000` for a local MinIO.
+  pub endpoint: String,
+  #[serde(default = "default_region")]
+  pub region: String,
+  pub bucket: String,
+  pub access_key_id: String,
+  pub secret_access_key: String,
+  /// Optional STS session token for temporary credentials.
+  pub session_token: Option<String>,
+  /// Address the bucket as `<endpoint>/<bucket>/<key>` rather than
+  /// `<bucket>.<endpoint>/<key>`.  Most self-hosted servers need this.
+  #[serde(default)]
+  pub path_style: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+  #[serde(default)]
+  contents: Vec<S3Object>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct S3Object {
+  key: String,
+  last_modified: String,
+  #[serde(rename = "ETag", default)]
+  e_tag: String,
+  size: i128,
+}
+
+/// Converts a hex MD5 into the base64 form expected in `Content-MD5`.
+fn md5_base64(hex: &str) -> Option<String> {
+    let bytes = (0..hex.len()).step_by(2)
+      .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+      .collect::<Option<Vec<u8>>>()?;
+    Some(base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+/// The S3-backed implementation.  Use [`Bucket`](crate::bucket::Bucket) in calling code.
+///
+/// Requests are signed with AWS Signature Version 4.  Payloads are sent as
+/// `UNSIGNED-PAYLOAD` so that uploads can be streamed from disk without
+/// hashing the file first.
+pub struct S3Bucket {
+  client: reqwest::Client,
+  config: S3Config,
+  base_url: Url,
+  throttle: Throttle,
+}
+
+impl S3Bucket {
+    /// Fails if `endpoint` is not a URL with a host, or if the bucket name
+    /// cannot be put in front of that host for virtual-hosted addressing.
+    pub fn new(config: &S3Config) -> Result<S3Bucket, String> {
+      let base_url = Url::parse(&config.endpoint)
+        .map_err(|e| format!("Invalid S3 endpoint {:?}: {}", config.endpoint, e))?;
+      let host = base_url.host_str()
+        .ok_or_else(|| format!("Invalid S3 endpoint {:?}: it has no host", config.endpoint))?;
+      if !config.path_style {
+        let virtual_host = format!("{}.{}", config.bucket, host);
+        base_url.clone().set_host(Some(&virtual_host))
+          .map_err(|e| format!("Bucket {:?} cannot be addressed as host {:?}: {}; set path_style", config.bucket, virtual_host, e))?;
+      }
+      Ok(S3Bucket {
+        client: reqwest::Client::new(),
+        config: config.clone(),
+        base_url,
+        throttle: Throttle::default(),
+      })
+    }
+
+    /// Limits the bandwidth used by uploads and downloads.
+    pub fn with_throttle(mut self, throttle: Throttle) -> S3Bucket {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Builds the request URL for `key` (or the bucket itself when `key` is
+    /// empty) together with the canonical URI used for signing.
+    fn object_url(&self, key: &str) -> (Url, String) {
+      let mut url = self.base_url.clone();
+      let encoded_key = uri_encode(key, false);
+      let path = if self.config.path_style {
+        format!("/{}/{}", uri_encode(&self.config.bucket, true), encoded_key)
+      } else {
+        let host = format!("{}.{}", self.config.bucket, self.base_url.host_str().unwrap_or_default());
+        url.set_host(Some(&host)).expect("virtual-host name checked in S3Bucket::new");
+        format!("/{}", encoded_key)
+      };
+      url.set_path(&path);
+      (url, path)
+    }
+
+    fn host_header(url: &Url) -> String {
+      match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+      }
+    }
+
+    /// Returns a request builder for `method` on `key` carrying a SigV4
+    /// `Authorization` header.  `query` must not be percent-encoded.
+    fn signed_request(&self, method: Method, key: &str, query: &[(&str, &str)], payload_hash: &str) -> reqwest::RequestBuilder {
+      let (mut url, canonical_uri) = self.object_url(key);
+
+      let mut sorted_query: Vec<(String, String)> = query.iter()
+        .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+        .collect();
+      sorted_query.sort();
+      let canonical_query = sorted_query.iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&");
+      // Set the query string verbatim so that the encoding on the wire matches
+      // the encoding that was signed.
+      url.set_query(if canonical_query.is_empty() { None } else { Some(&canonical_query) });
+
+      let now = Utc::now();
+      let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+      let date = now.format("%Y%m%d").to_string();
+      let host = S3Bucket::host_header(&url);
+
+      let mut headers: Vec<(&str, String)> = vec![
+        ("host", host),
+        ("x-amz-content-sha256", payload_hash.to_string()),
+        ("x-amz-date", amz_date.clone()),
+      ];
+      if let Some(ref token) = self.config.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+      }
+      let canonical_headers: String = headers.iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v.trim()))
+        .collect();
+      let signed_headers = headers.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(";");
+
+      let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method.as_str(), canonical_uri, canonical_query, canonical_headers, signed_headers, payload_hash
+      );
+      let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+      let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+        amz_date, scope, Sha256::digest(canonical_request.as_bytes())
+      );
+
+      let k_date = hmac_sha256(format!("AWS4{}", self.config.secret_access_key).as_bytes(), date.as_bytes());
+      let k_region = hmac_sha256(&k_date, self.config.region.as_bytes());
+      let k_service = hmac_sha256(&k_region, b"s3");
+      let k_signing = hmac_sha256(&k_service, b"aws4_request");
+      let signature: String = hmac_sha256(&k_signing, string_to_sign.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+      let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        self.config.access_key_id, scope, signed_headers, signature
+      );
+
+      let mut builder = self.client.request(method, url)
+        .header("Authorization", authorization);
+      // reqwest derives the Host header from the URL itself.
+      for (name, value) in headers.into_iter().filter(|(k, _)| *k != "host") {
+        builder = builder.header(name, value);
+      }
+      builder
+    }
+
+    async fn get(&self, key: &str, range: Option<(u64, u64)>) -> io::Result<reqwest::Response> {
+      let mut request = self.signed_request(Method::GET, key, &[], EMPTY_PAYLOAD_SHA256);
+      if let Some((offset, length)) = range {
+        request = request.header("Range", format!("bytes={}-{}", offset, offset + length - 1));
+      }
+      let response = request.send().await
+        .map_err(|e| io::Error::from(StoreError::transient(format!("S3 download error for {}: {:?}", key, e))))?;
+      let status = response.status();
+      if status == StatusCode::NOT_FOUND {
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("{}/{} not found", self.config.bucket, key)))
+      } else if !status.is_success() {
+        Err(StoreError::http(status, format!("S3 download failed: HTTP {} for {}/{}", status, self.config.bucket, key)).into())
+      } else {
+        Ok(response)
+      }
+    }
+
+    /// Downloads `length` bytes starting at `offset` and appends them to `dest`
+    /// at its current position.  This is not part of [`StorageBackend`] and
+    /// is not retried: restores never need part of an object, since packs
+    /// are encrypted as a whole.
+    pub async fn download_range(&self, key: &str, offset: u64, length: u64, mut dest: File) -> io::Result<u64> {
+      // A Range header cannot express an empty range.
+      if length == 0 {
+        return Ok(0);
+      }
+      let response = self.get(key, Some((offset, length))).await?;
+      if response.status() != StatusCode::PARTIAL_CONTENT {
+        // The server ignored the Range header; fall back to discarding the
+        // bytes outside the requested window.
+        let bytes = response.bytes().await.map_err(io::Error::other)?;
+        self.throttle.consume(Direction::Download, bytes.len()).await;
+        let start = (offset as usize).min(bytes.len());
+        let end = (start + length as usize).min(bytes.len());
+        dest.write_all(&bytes[start..end])?;
+        return Ok((end - start) as u64);
+      }
+      let stream = self.throttle.stream(Direction::Download, response.bytes_stream())
+        .map(|result| result.map_err(io::Error::other));
+      let mut reader = StreamReader::new(stream);
+      let mut tokio_file = tokio::fs::File::from(dest);
+      tokio::io::copy(&mut reader, &mut tokio_file).await
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Bucket {
+    async fn upload(&self, key: &str, source: File, md5: Option<&str>, callback: ProgressCallback) -> Result<(), StoreError> {
+      let length = source.metadata()
+        .map_err(|e| format!("Failed to stat upload source for {}: {}", key, e))?
+        .len();
+      let tokio_file = tokio::fs::File::from(source);
+      let stream = self.throttle.stream(Direction::Upload, tokio_util::io::ReaderStream::new(tokio_file)).inspect_ok(move |bytes| {
+        callback(bytes.len())
+      });
+      // S3 does not accept chunked transfer encoding on PUT, so the length
+      // must be sent up-front.
+      let mut request = self.signed_request(Method::PUT, key, &[], UNSIGNED_PAYLOAD)
+        .header("Content-Length", length);
+      // With Content-MD5 set, S3 rejects the upload with BadDigest if the
+      // payload it received does not match.
+      if let Some(digest) = md5.and_then(md5_base64) {
+        request = request.header("Content-MD5", digest);
+      }
+      let response = request
+        .body(reqwest::Body::wrap_stream(stream))
+        .send().await
+        .map_err(|e| StoreError::transient(format!("S3 upload error: {:?}", e)))?;
+      let status = response.status();
+      if status.is_success() {
+        Ok(())
+      } else {
+        let body = response.text().await.unwrap_or_else(|_| "<unreadable body>".to_string());
+        Err(StoreError::http(status, format!("S3 upload failed: HTTP {} for {}/{}: {}", status, self.config.bucket, key, body)))
+      }
+    }
+
+    /// Returns `Ok(true)` if the object exists (2xx), `Ok(false)` if it is
+    /// definitively absent (HTTP 404), or `Err` for any other non-success
+    /// status or request-level failure.
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        match self.signed_request(Method::HEAD, key, &[], EMPTY_PAYLOAD_SHA256).send().await {
+            Ok(response) => {
+                let status = response.status();
+                if status.is_success() {
+                    Ok(true)
+                } else if status == StatusCode::NOT_FOUND {
+                    Ok(false)
+                } else {
+                    let msg = format!(
+                        "Unexpected status {} checking existence of {}/{}",
+                        status, self.config.bucket, key
+                    );
+                    error!("{}", msg);
+                    Err(StoreError::http(status, msg))
+                }
+            }
+            Err(e) => {
+                let msg = format!("Error checking existence of {}/{}: {:?}", self.config.bucket, key, e);
+                error!("{}", msg);
+                Err(StoreError::transient(msg))
+            }
+        }
+    }
+
+    async fn download(&self, key: &str, dest: File, callback: ProgressCallback) -> io::Result<u64> {
+      let response = self.get(key, None).await?;
+      let stream = self.throttle.stream(Direction::Download, response.bytes_stream())
+        .map(move |result| {
+            result.inspect(|bytes| {
+                callback(bytes.len());
+            }).map_err(|e| {
+                error!("Encountered error");
+                io::Error::other(e)
+            })
+        });
+      let mut reader = StreamReader::new(stream);
+      let mut tokio_file = tokio::fs::File::from(dest);
+      tokio::io::copy(&mut reader, &mut tokio_file).await
+    }
+
+    /// Returns up to 100 entries whose key begins with `prefix`, with keys
+    /// strictly greater than `marker`, using ListObjectsV2's `start-after`.
+    async fn list(&self, prefix: Option<&str>, marker: Option<&str>) -> io::Result<Vec<ObjectEntry>> {
+      let mut query: Vec<(&str, &str)> = vec![("list-type", "2"), ("max-keys", "100")];
+      if let Some(p) = prefix {
+        query.push(("prefix", p));
+      }
+      if let Some(m) = marker {
+        query.push(("start-after", m));
+      }
+      let response = self.signed_request(Method::GET, "", &query, EMPTY_PAYLOAD_SHA256)
+        .send().await
+        .map_err(|e| io::Error::from(StoreError::transient(format!("S3 list error: {:?}", e))))?;
+      let status = response.status();
+      let body = response.text().await.map_err(io::Error::other)?;
+      if !status.is_success() {
+        return Err(StoreError::http(status, format!("S3 list failed: HTTP {} for {}: {}", status, self.config.bucket, body)).into());
+      }
+      let result: ListBucketResult = quick_xml::de::from_str(&body)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Unparseable S3 listing: {}", e)))?;
+      Ok(result.contents.into_iter().map(|o| ObjectEntry {
+        hash: o.e_tag.trim_matches('"').to_string(),
+        last_modified: normalise_timestamp(&o.last_modified),
+        bytes: o.size,
+        name: o.key,
+        content_type: String::from("application/octet-stream"),
+        slo_etag: None,
+      }).collect())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+      let response = self.signed_request(Method::DELETE, key, &[], EMPTY_PAYLOAD_SHA256)
+        .send().await
+        .map_err(|e| StoreError::transient(format!("S3 delete error for {}/{}: {:?}", self.config.bucket, key, e)))?;
+      let status = response.status();
+      // S3 itself answers 204 for absent keys; some compatible servers use 404.
+      if status.is_success() || status == StatusCode::NOT_FOUND {
+        Ok(())
+      } else {
+        let body = response.text().await.unwrap_or_else(|_| "<unreadable body>".to_string());
+        Err(StoreError::http(status, format!("S3 delete failed: HTTP {} for {}/{}: {}", status, self.config.bucket, key, body)))
+      }
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<ObjectEntry>, StoreError> {
+      let response = self.signed_request(Method::HEAD, key, &[], EMPTY_PAYLOAD_SHA256)
+        .send().await
+        .map_err(|e| StoreError::transient(format!("Error checking {}/{}: {:?}", self.config.bucket, key, e)))?;
+      let status = response.status();
+      if status.is_success() {
+        Ok(Some(ObjectEntry::from_headers(key, response.headers())))
+      } else if status == StatusCode::NOT_FOUND {
+        Ok(None)
+      } else {
+        Err(StoreError::http(status, format!("Unexpected status {} checking {}/{}", status, self.config.bucket, key)))
+      }
+    }
+
+    fn hash_is_md5(&self, entry: &ObjectEntry) -> bool {
+      // Multipart uploads get an ETag of the form `<md5 of part md5s>-<parts>`.
+      !entry.hash.is_empty() && !entry.hash.contains('-')
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+  let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+  mac.update(data);
+  mac.finalize().into_bytes().to_vec()
+}
+
+/// S3 reports `2024-01-15T10:30:00.000Z`; the rest of the tool uses Swift's
+/// `2024-01-15T10:30:00.000000` form.
+fn normalise_timestamp(s: &str) -> String {
+  chrono::DateTime::parse_from_rfc3339(s)
+    .map(|dt| dt.naive_utc().format("%Y-%m-%dT%H:%M:%S%.6f").to_string())
+    .unwrap_or_else(|_| s.to_string())
+}
@@ -0,0 +1,434 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use log::{error, trace, warn};
+use ssh2::{ErrorCode, FileStat, HashType, MethodType, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use tokio::sync::Mutex;
+use crate::bucket::ObjectEntry;
+use crate::storage::{ProgressCallback, StorageBackend, StoreError};
+use crate::throttle::{Direction, Throttle};
+
+/// SFTP status code for the end of a file or directory (`SSH_FX_EOF`).
+const SSH_FX_EOF: i32 = 1;
+
+/// SFTP status code for "no such file" (`SSH_FX_NO_SUCH_FILE`).
+const SSH_FX_NO_SUCH_FILE: i32 = 2;
+
+/// SFTP status codes for a connection that is missing or was lost
+/// (`SSH_FX_NO_CONNECTION`, `SSH_FX_CONNECTION_LOST`).
+const SSH_FX_NO_CONNECTION: i32 = 6;
+const SSH_FX_CONNECTION_LOST: i32 = 7;
+
+fn default_port() -> u16 { 22 }
+
+/// Connection details for an SFTP store.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SftpConfig {
+  pub host: String,
+  #[serde(default = "default_port")]
+  pub port: u16,
+  pub username: String,
+  /// Private key used for public-key authentication.  Password
+  /// authentication is deliberately not supported.
+  pub private_key_file: PathBuf,
+  pub private_key_passphrase: Option<String>,
+  /// Expected SHA-256 fingerprint of the server's host key, in the form
+  /// printed by `ssh-keygen -lf` (`SHA256:<base64>`).  The connection is
+  /// refused if the server presents any other key.
+  pub host_key_fingerprint: String,
+  /// Host key algorithm to negotiate (e.g. `ssh-ed25519`).  Servers usually
+  /// hold several host keys, and the fingerprint must match the one that is
+  /// actually negotiated; leave unset to accept libssh2's preference.
+  pub host_key_algorithm: Option<String>,
+  /// Remote directory used as the container root.
+  pub root: String,
+}
+
+/// The SFTP-backed implementation.  Use [`Bucket`](crate::bucket::Bucket) in calling code.
+///
+/// libssh2 is blocking, so every operation runs on tokio's blocking pool.
+/// Object keys are mapped to paths beneath `root` exactly as in
+/// [`LocalBucket`](crate::local_bucket::LocalBucket).
+///
+/// An operation that fails because the session or its connection did is
+/// reported as transient, and the connection is dropped; the next attempt
+/// connects again.
+pub struct SftpBucket {
+  config: SftpConfig,
+  connection: Mutex<Option<Arc<Connection>>>,
+  root: PathBuf,
+  throttle: Throttle,
+}
+
+/// An authenticated session and the SFTP channel opened on it.
+struct Connection {
+  sftp: Sftp,
+  // The session must outlive the SFTP channel opened on it.
+  _session: Session,
+}
+
+impl SftpBucket {
+    pub async fn connect(config: &SftpConfig) -> Result<SftpBucket, String> {
+      let bucket = SftpBucket {
+        config: config.clone(),
+        connection: Mutex::new(None),
+        root: PathBuf::from(&config.root),
+        throttle: Throttle::default(),
+      };
+      bucket.connection().await.map_err(|e| e.to_string())?;
+      Ok(bucket)
+    }
+
+    fn connect_blocking(config: &SftpConfig) -> Result<Connection, StoreError> {
+      let address = format!("{}:{}", config.host, config.port);
+      trace!("Connecting to SFTP store at {}", address);
+      let tcp = TcpStream::connect(&address)
+        .map_err(|e| StoreError::transient(format!("Failed to connect to {}: {}", address, e)))?;
+      let mut session = Session::new().map_err(|e| format!("Failed to create SSH session: {}", e))?;
+      session.set_tcp_stream(tcp);
+      if let Some(ref algorithm) = config.host_key_algorithm {
+        session.method_pref(MethodType::HostKey, algorithm)
+          .map_err(|e| format!("Unsupported host key algorithm {:?}: {}", algorithm, e))?;
+      }
+      session.handshake()
+        .map_err(|e| StoreError::transient(format!("SSH handshake with {} failed: {}", address, e)))?;
+
+      let presented = session.host_key_hash(HashType::Sha256)
+        .map(|h| format!("SHA256:{}", STANDARD_NO_PAD.encode(h)))
+        .ok_or_else(|| format!("{} did not present a host key", address))?;
+      let expected = config.host_key_fingerprint.trim().trim_end_matches('=');
+      if presented != expected {
+        return Err(StoreError::permanent(format!(
+          "Host key mismatch for {}: expected {} but server presented {}",
+          address, expected, presented
+        )));
+      }
+
+      session.userauth_pubkey_file(
+        &config.username,
+        None,
+        &config.private_key_file,
+        config.private_key_passphrase.as_deref(),
+      ).map_err(|e| format!("Public key authentication to {} as {} failed: {}", address, config.username, e))?;
+
+      let sftp = session.sftp()
+        .map_err(|e| store_error(e, format!("Failed to start SFTP subsystem on {}", address)))?;
+      Ok(Connection { sftp, _session: session })
+    }
+
+    /// Limits the bandwidth used by uploads and downloads.
+    pub fn with_throttle(mut self, throttle: Throttle) -> SftpBucket {
+        self.throttle = throttle;
+        self
+    }
+
+    fn key_path(&self, key: &str) -> PathBuf {
+        // Strip a leading '/' so that absolute-looking keys still land
+        // safely inside root.
+        self.root.join(key.trim_start_matches('/'))
+    }
+
+    /// The current connection, made again if a failure dropped the last one.
+    async fn connection(&self) -> Result<Arc<Connection>, StoreError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(ref current) = *connection {
+            return Ok(Arc::clone(current));
+        }
+        let config = self.config.clone();
+        let new = tokio::task::spawn_blocking(move || SftpBucket::connect_blocking(&config))
+            .await
+            .map_err(|e| StoreError::permanent(format!("SFTP connection task failed: {}", e)))??;
+        let new = Arc::new(new);
+        *connection = Some(Arc::clone(&new));
+        Ok(new)
+    }
+
+    /// Runs `operation` on the blocking pool over the current connection, and
+    /// drops the connection if it fails with a transient error so that a
+    /// retry starts on a new one.
+    async fn run<T, E>(&self, operation: impl FnOnce(&Sftp) -> Result<T, E> + Send + 'static) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: Transient + From<StoreError> + Send + 'static,
+    {
+        let connection = self.connection().await?;
+        let used = Arc::clone(&connection);
+        let result = tokio::task::spawn_blocking(move || operation(&used.sftp))
+            .await
+            .map_err(|e| StoreError::permanent(format!("SFTP task failed: {}", e)))?;
+        if result.as_ref().is_err_and(|e| e.is_transient()) {
+            let mut current = self.connection.lock().await;
+            if current.as_ref().is_some_and(|c| Arc::ptr_eq(c, &connection)) {
+                warn!("Dropping the connection to SFTP store {}:{} after a failure", self.config.host, self.config.port);
+                *current = None;
+            }
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SftpBucket {
+    async fn upload(
+        &self,
+        key: &str,
+        mut source: File,
+        _md5: Option<&str>,
+        callback: ProgressCallback,
+    ) -> Result<(), StoreError> {
+        let root = self.root.clone();
+        let dest_path = self.key_path(key);
+        let key = key.to_string();
+        let throttle = self.throttle.clone();
+        self.run(move |sftp| {
+            if let Some(parent) = dest_path.parent() {
+                create_dir_all(sftp, &root, parent)
+                    .map_err(|e| store_error(e, format!("Failed to create directories for {key}")))?;
+            }
+            // Write to a temporary name and rename into place so that a dropped
+            // connection never leaves a truncated object under the real key.
+            let mut partial_path = dest_path.clone().into_os_string();
+            partial_path.push(".partial");
+            let partial_path = PathBuf::from(partial_path);
+            let mut dest = sftp.open_mode(
+                &partial_path,
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                0o644,
+                OpenType::File,
+            ).map_err(|e| store_error(e, format!("Failed to create {}", partial_path.display())))?;
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                let n = source.read(&mut buf).map_err(|e| StoreError::permanent(format!("Read error: {e}")))?;
+                if n == 0 {
+                    break;
+                }
+                throttle.consume_blocking(Direction::Upload, n);
+                dest.write_all(&buf[..n]).map_err(|e| StoreError::transient(format!("Write error: {e}")))?;
+                callback(n);
+            }
+            drop(dest);
+            rename_into_place(sftp, &partial_path, &dest_path)
+                .map_err(|e| store_error(e, format!("Failed to rename {} into place", partial_path.display())))
+        }).await
+    }
+
+    /// Returns `Ok(true)` if the object exists, `Ok(false)` if the server
+    /// reports it as absent, or `Err` for any other failure.
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        let path = self.key_path(key);
+        let key = key.to_string();
+        self.run(move |sftp| match sftp.stat(&path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == ErrorCode::SFTP(SSH_FX_NO_SUCH_FILE) => Ok(false),
+            Err(e) => {
+                let e = store_error(e, format!("Error checking existence of {}", key));
+                error!("{}", e);
+                Err(e)
+            }
+        }).await
+    }
+
+    async fn download(
+        &self,
+        key: &str,
+        mut dest: File,
+        callback: ProgressCallback,
+    ) -> io::Result<u64> {
+        let path = self.key_path(key);
+        let throttle = self.throttle.clone();
+        self.run(move |sftp| {
+            let mut source = sftp.open(&path).map_err(|e| io_error(e, format!("Failed to open {}", path.display())))?;
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut total = 0u64;
+            loop {
+                let n = source.read(&mut buf).map_err(transfer_error)?;
+                if n == 0 {
+                    break;
+                }
+                throttle.consume_blocking(Direction::Download, n);
+                dest.write_all(&buf[..n])?;
+                callback(n);
+                total += n as u64;
+            }
+            Ok(total)
+        }).await
+    }
+
+    /// Returns up to 100 entries whose key begins with `prefix`, with
+    /// keys strictly greater than `marker` (mimicking Swift's pagination).
+    async fn list(
+        &self,
+        prefix: Option<&str>,
+        marker: Option<&str>,
+    ) -> io::Result<Vec<ObjectEntry>> {
+        let root = self.root.clone();
+        let prefix = prefix.map(|p| p.to_string());
+        let marker = marker.map(|m| m.to_string());
+        self.run(move |sftp| {
+            let mut entries: Vec<ObjectEntry> = Vec::new();
+            collect_entries(sftp, &root, &root, prefix.as_deref(), &mut entries)?;
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            if let Some(m) = marker {
+                entries.retain(|e| e.name > m);
+            }
+            entries.truncate(100);
+            Ok(entries)
+        }).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        let path = self.key_path(key);
+        let key = key.to_string();
+        self.run(move |sftp| match sftp.unlink(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.code() == ErrorCode::SFTP(SSH_FX_NO_SUCH_FILE) => Ok(()),
+            Err(e) => Err(store_error(e, format!("Failed to delete {key}"))),
+        }).await
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<ObjectEntry>, StoreError> {
+        let path = self.key_path(key);
+        let key = key.to_string();
+        self.run(move |sftp| match sftp.stat(&path) {
+            Ok(stat) => Ok(Some(entry_for(key, &stat))),
+            Err(e) if e.code() == ErrorCode::SFTP(SSH_FX_NO_SUCH_FILE) => Ok(None),
+            Err(e) => Err(store_error(e, format!("Failed to stat {key}"))),
+        }).await
+    }
+}
+
+/// Errors that [`SftpBucket::run`] can tell the connection broke on.
+trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for StoreError {
+    fn is_transient(&self) -> bool {
+        self.transient
+    }
+}
+
+impl Transient for io::Error {
+    fn is_transient(&self) -> bool {
+        self.get_ref().and_then(|e| e.downcast_ref::<StoreError>()).is_some_and(|e| e.transient)
+    }
+}
+
+/// Classifies a libssh2 error, prefixed with `message`.  Failures of the
+/// session itself (socket errors, timeouts, a closed channel) and an SFTP
+/// end of file or lost connection are transient; a status the server sent
+/// back, such as permission denied, is not.
+fn store_error(e: ssh2::Error, message: String) -> StoreError {
+    let transient = match e.code() {
+        ErrorCode::Session(_) => true,
+        ErrorCode::SFTP(code) => matches!(code, SSH_FX_EOF | SSH_FX_NO_CONNECTION | SSH_FX_CONNECTION_LOST),
+    };
+    let message = format!("{}: {}", message, e);
+    if transient { StoreError::transient(message) } else { StoreError::permanent(message) }
+}
+
+/// Like [`store_error`], for operations that report `io::Error`, keeping a
+/// missing file as [`io::ErrorKind::NotFound`].
+fn io_error(e: ssh2::Error, message: String) -> io::Error {
+    if e.code() == ErrorCode::SFTP(SSH_FX_NO_SUCH_FILE) {
+        return io::Error::new(io::ErrorKind::NotFound, format!("{}: {}", message, e));
+    }
+    store_error(e, message).into()
+}
+
+/// Reading or seeking a remote file loses the libssh2 error code.  Once the
+/// file is open the likely cause is a dropped connection, so the failure is
+/// taken as transient.
+fn transfer_error(e: io::Error) -> io::Error {
+    StoreError::transient(format!("SFTP transfer failed: {}", e)).into()
+}
+
+fn entry_for(name: String, stat: &FileStat) -> ObjectEntry {
+    let last_modified = stat.mtime
+        .and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0))
+        .map(|dt| dt.format("%Y-%m-%dT%H:%M:%S%.6f").to_string())
+        .unwrap_or_default();
+    ObjectEntry {
+        hash: String::new(),
+        last_modified,
+        bytes: stat.size.unwrap_or(0) as i128,
+        name,
+        content_type: String::from("application/octet-stream"),
+        slo_etag: None,
+    }
+}
+
+/// Creates `dir` and any missing ancestors below `root`.  `root` itself must
+/// already exist.
+fn create_dir_all(sftp: &Sftp, root: &Path, dir: &Path) -> Result<(), ssh2::Error> {
+    if dir == root || sftp.stat(dir).is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dir_all(sftp, root, parent)?;
+    }
+    match sftp.mkdir(dir, 0o755) {
+        Ok(()) => Ok(()),
+        // Another concurrent upload may have created it in the meantime.
+        Err(_) if sftp.stat(dir).map(|s| s.is_dir()).unwrap_or(false) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Renames `from` to `to`, replacing any object already there.  The rename
+/// flags are only sent from SFTP version 5 on; OpenSSH's sftp-server speaks
+/// version 3 and refuses to rename onto an existing file, so the old object
+/// is removed and the rename repeated.  Until then the old object stays in
+/// place, so the key is only missing for the moment between the two.
+fn rename_into_place(sftp: &Sftp, from: &Path, to: &Path) -> Result<(), ssh2::Error> {
+    match sftp.rename(from, to, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE)) {
+        Ok(()) => Ok(()),
+        Err(e) if sftp.lstat(to).is_err() => Err(e),
+        Err(_) => {
+            sftp.unlink(to)?;
+            sftp.rename(from, to, None)
+        }
+    }
+}
+
+fn collect_entries(
+    sftp: &Sftp,
+    root: &Path,
+    dir: &Path,
+    prefix: Option<&str>,
+    result: &mut Vec<ObjectEntry>,
+) -> io::Result<()> {
+    let listing: Vec<(PathBuf, FileStat)> = sftp.readdir(dir)
+        .map_err(|e| io_error(e, format!("Failed to list {}", dir.display())))?;
+    for (path, stat) in listing {
+        let rel = path.strip_prefix(root).unwrap_or(&path);
+        let name = rel.to_string_lossy().replace('\\', "/");
+        if stat.is_dir() {
+            // Only descend into directories that could contain a match.
+            let could_match = prefix.is_none_or(|p| {
+                let dir_name = format!("{}/", name);
+                dir_name.starts_with(p) || p.starts_with(&dir_name)
+            });
+            if could_match {
+                collect_entries(sftp, root, &path, prefix, result)?;
+            }
+        } else {
+            if let Some(p) = prefix {
+                if !name.starts_with(p) {
+                    continue;
+                }
+            }
+            // Skip uploads that are still in flight (or were interrupted).
+            if name.ends_with(".partial") {
+                continue;
+            }
+            result.push(entry_for(name, &stat));
+        }
+    }
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use std::fs::Metadata;
 use std::path::Path;
 
+use crate::chunking::Chunk;
 use crate::datastore;
 use crate::hash;
 
@@ -11,7 +12,7 @@ use sqlx::Row;
 use sqlx::SqlitePool;
 use sqlx;
 
-use log::error;
+use log::{error, warn};
 use sqlx::sqlite::SqliteQueryResult;
 
 pub struct AsyncCache {
@@ -28,15 +29,34 @@ impl Clone for AsyncCache {
 
 use std::str::FromStr;
 
+/// The cache lives in the working directory.
+const CACHE_FILE: &str = "cache.db";
+
 impl AsyncCache {
-  pub async fn new<'b>() -> AsyncCache {
-    let options = sqlx::sqlite::SqliteConnectOptions::from_str("sqlite:cache.db?mode=rwc").unwrap()
+  pub async fn new() -> AsyncCache {
+    let options = sqlx::sqlite::SqliteConnectOptions::from_str(&format!("sqlite:{}?mode=rwc", CACHE_FILE)).unwrap()
       .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal);
     AsyncCache {
       pool: SqlitePool::connect_with(options).await.unwrap()
     }
   }
 
+  /// Opens the cache left by an earlier backup, for reading.  Returns `None`
+  /// instead of creating it if there is none.
+  pub async fn open_existing() -> Option<AsyncCache> {
+    if !Path::new(CACHE_FILE).exists() {
+      return None;
+    }
+    let options = sqlx::sqlite::SqliteConnectOptions::from_str(&format!("sqlite:{}?mode=rw", CACHE_FILE)).unwrap();
+    match SqlitePool::connect_with(options).await {
+      Ok(pool) => Some(AsyncCache { pool }),
+      Err(e) => {
+        warn!("Failed to open {}: {}", CACHE_FILE, e);
+        None
+      }
+    }
+  }
+
   /// Clear the cold storage cache. If `store_ids` is non-empty, only rows for
   /// those stores are removed; otherwise all rows are deleted.
   pub async fn clear_cold_storage_cache(&self, store_ids: &[i32]) -> Result<SqliteQueryResult, sqlx::Error> {
@@ -57,10 +77,13 @@ impl AsyncCache {
     }
   }
 
-  pub async fn set_data_in_cold_storage(&self, hash: &str, md5_hash: &str, store_ids: &Vec<i32>) -> Result<usize, String> {
+  /// Records that `hash` has been uploaded to each of `store_ids`, along with
+  /// the MD5 of the encrypted object where it is known.  A store already
+  /// recorded for `hash` keeps its MD5 unless a new one is given.
+  pub async fn set_data_in_cold_storage(&self, hash: &str, md5_hash: Option<&str>, store_ids: &Vec<i32>) -> Result<usize, String> {
     for store_id in store_ids {
       let query =
-        sqlx::query("INSERT INTO uploaded_objects VALUES ($1, $2, $3)")
+        sqlx::query("INSERT INTO uploaded_objects VALUES ($1, $2, $3) ON CONFLICT(data_hash, datastore_id) DO UPDATE SET encrypted_md5 = COALESCE(excluded.encrypted_md5, encrypted_md5)")
           .bind(hash)
           .bind(md5_hash)
           .bind(store_id);
@@ -83,20 +106,51 @@ impl AsyncCache {
         }
       }
     }
-    return Ok(1);
+    Ok(1)
+  }
+
+  /// The hashes recorded as uploaded to `store_id`.
+  pub async fn cold_storage_hashes(&self, store_id: i32) -> Result<Vec<String>, sqlx::Error> {
+    let query = sqlx::query("SELECT data_hash FROM uploaded_objects WHERE datastore_id = ?")
+      .bind(store_id);
+    let rows = self.pool.fetch_all(query).await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+  }
+
+  /// Forgets that `hashes` were uploaded to `store_id`.
+  pub async fn remove_data_from_cold_storage(&self, hashes: &[String], store_id: i32) -> Result<u64, sqlx::Error> {
+    let mut transaction = self.pool.begin().await?;
+    let mut removed = 0;
+    for hash in hashes {
+      removed += sqlx::query("DELETE FROM uploaded_objects WHERE data_hash = ? AND datastore_id = ?")
+        .bind(hash)
+        .bind(store_id)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    }
+    transaction.commit().await?;
+    Ok(removed)
+  }
+
+  /// The MD5 recorded for the encrypted object when it was uploaded to the
+  /// store, if any.
+  pub async fn encrypted_md5(&self, hash: &str, store_id: i32) -> Result<Option<String>, sqlx::Error> {
+    let query = sqlx::query("SELECT encrypted_md5 FROM uploaded_objects WHERE data_hash = ? AND datastore_id = ?")
+      .bind(hash)
+      .bind(store_id);
+    let row = self.pool.fetch_optional(query).await?;
+    Ok(row.and_then(|r| r.get(0)))
   }
 
   pub async fn lock_data(&self, hash: &str) -> bool {
     let query =
       sqlx::query("INSERT INTO hash_lock VALUES ($1)")
         .bind(hash);
-    match self.pool.execute(query).await {
-      Ok(_) => true,
-      Err(_) => false
-    }
+    self.pool.execute(query).await.is_ok()
   }
 
-  pub async fn requires_upload(&self, data_hash: &String, stores: &Vec<DataStore>) -> Result<Vec<i32>, sqlx::Error> {
+  pub async fn requires_upload(&self, data_hash: &String, stores: &[DataStore]) -> Result<Vec<i32>, sqlx::Error> {
     let query = sqlx::query("SELECT datastore_id FROM uploaded_objects WHERE data_hash = ?")
       .bind(data_hash);
 
@@ -121,12 +175,72 @@ impl AsyncCache {
     self.pool.execute(sqlx::query("CREATE TABLE IF NOT EXISTS fs_hash_cache (fs_hash CHARACTER(128) UNIQUE, data_hash CHARACTER(128) NULL, in_use BOOLEAN);")).await.unwrap();
     self.pool.execute(sqlx::query("CREATE TABLE IF NOT EXISTS uploaded_objects (data_hash TEXT, encrypted_md5 TEXT NULL, datastore_id INTEGER, UNIQUE(data_hash, datastore_id));")).await.unwrap();
     self.pool.execute(sqlx::query("CREATE TABLE IF NOT EXISTS hash_lock (data_hash TEXT, UNIQUE(data_hash));")).await.unwrap();
+    self.pool.execute(sqlx::query("CREATE TABLE IF NOT EXISTS packed_objects (data_hash TEXT UNIQUE, pack TEXT, pack_offset INTEGER, length INTEGER);")).await.unwrap();
+    self.pool.execute(sqlx::query("CREATE TABLE IF NOT EXISTS file_chunks (data_hash TEXT, seq INTEGER, chunk_hash TEXT, chunk_offset INTEGER, length INTEGER, UNIQUE(data_hash, seq));")).await.unwrap();
     self.pool.execute(sqlx::query("UPDATE fs_hash_cache set in_use = false;")).await.unwrap();
     self.pool.execute(sqlx::query("DELETE FROM hash_lock;")).await.unwrap();
   }
 
   pub async fn cleanup(&self) {
     self.pool.execute(sqlx::query("DELETE FROM fs_hash_cache WHERE in_use = false;")).await.unwrap();
+    self.pool.execute(sqlx::query("DELETE FROM file_chunks WHERE data_hash NOT IN (SELECT data_hash FROM fs_hash_cache WHERE data_hash IS NOT NULL);")).await.unwrap();
+  }
+
+  /// The chunks recorded for the file content `data_hash`, in order; empty if
+  /// it has not been chunked.
+  pub async fn get_chunks(&self, data_hash: &str) -> Result<Vec<Chunk>, sqlx::Error> {
+    let query = sqlx::query("SELECT chunk_hash, chunk_offset, length FROM file_chunks WHERE data_hash = ? ORDER BY seq")
+      .bind(data_hash);
+    let rows = self.pool.fetch_all(query).await?;
+    Ok(rows.iter().map(|row| Chunk {
+      hash: row.get(0),
+      offset: row.get::<i64, _>(1) as u64,
+      length: row.get::<i64, _>(2) as u64,
+    }).collect())
+  }
+
+  /// The pack holding the content `data_hash`, with its offset and length in
+  /// the pack, if it has been packed.
+  pub async fn packed_location(&self, data_hash: &str) -> Result<Option<(String, u64, u64)>, sqlx::Error> {
+    let query = sqlx::query("SELECT pack, pack_offset, length FROM packed_objects WHERE data_hash = ?")
+      .bind(data_hash);
+    let row = self.pool.fetch_optional(query).await?;
+    Ok(row.map(|row| (row.get(0), row.get::<i64, _>(1) as u64, row.get::<i64, _>(2) as u64)))
+  }
+
+  /// Records the contents of `pack` as (data hash, offset, length), replacing
+  /// older locations of the same contents.
+  pub async fn set_packed(&self, pack: &str, members: &[(String, u64, u64)]) -> Result<(), sqlx::Error> {
+    let mut transaction = self.pool.begin().await?;
+    for (data_hash, offset, length) in members {
+      sqlx::query("INSERT INTO packed_objects VALUES (?, ?, ?, ?) ON CONFLICT(data_hash) DO UPDATE SET pack = excluded.pack, pack_offset = excluded.pack_offset, length = excluded.length")
+        .bind(data_hash)
+        .bind(pack)
+        .bind(*offset as i64)
+        .bind(*length as i64)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await
+  }
+
+  pub async fn set_chunks(&self, data_hash: &str, chunks: &[Chunk]) -> Result<(), sqlx::Error> {
+    let mut transaction = self.pool.begin().await?;
+    sqlx::query("DELETE FROM file_chunks WHERE data_hash = ?")
+      .bind(data_hash)
+      .execute(&mut *transaction)
+      .await?;
+    for (seq, chunk) in chunks.iter().enumerate() {
+      sqlx::query("INSERT INTO file_chunks VALUES (?, ?, ?, ?, ?)")
+        .bind(data_hash)
+        .bind(seq as i64)
+        .bind(&chunk.hash)
+        .bind(chunk.offset as i64)
+        .bind(chunk.length as i64)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await
   }
 
   pub async fn try_get_hash(&self, path: &Path, metadata: &Metadata) -> Result<Option<String>, sqlx::Error> {
@@ -136,7 +250,7 @@ impl AsyncCache {
     self.mark_used_and_lookup_hash(&metadata_hash)
       .and_then(|v: Option<_>| async {
         match v {
-          Some(s) => return Ok(Some(s)),
+          Some(s) => Ok(Some(s)),
           None => Ok(None)
         }
       }).await
@@ -158,7 +272,7 @@ impl AsyncCache {
   pub async fn set_data_hash(&self, metadata_hash: &str, data_hash: &str) -> Result<u64, sqlx::Error> {
     let query = sqlx::query("UPDATE fs_hash_cache set data_hash = ? where fs_hash = ?").bind(data_hash).bind(metadata_hash);
     let result = self.pool.execute(query).await;
-    return result.map(|x| x.rows_affected());
+    result.map(|x| x.rows_affected())
   }  
 
   pub async fn close(&self) -> () {
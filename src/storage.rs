@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, OnceLock, RwLock};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::{join_all, BoxFuture};
+use futures::stream::BoxStream;
+use futures::FutureExt;
+use log::error;
+use crate::bucket::ObjectEntry;
+use crate::datastore::DataStore;
+use crate::local_bucket::LocalBucket;
+use crate::s3_bucket::S3Bucket;
+use crate::sftp_bucket::SftpBucket;
+use crate::throttle::Throttle;
+use crate::swift::{SwiftBucket, DEFAULT_SEGMENT_PREFIX, DEFAULT_SEGMENT_SIZE, MIN_SEGMENT_SIZE};
+use crate::webdav_bucket::WebDavBucket;
+
+/// Called with the number of bytes transferred each time a chunk is sent or
+/// received.
+pub type ProgressCallback = Box<dyn Fn(usize) + Sync + Send + 'static>;
+
+/// The bytes of an object uploaded as they are produced.  The producer ends
+/// the stream with an error if it fails part-way through.
+pub type UploadStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// Builds a backend from a store's configuration.  The store is passed by
+/// value so that the returned future can outlive the borrow of the config.
+pub type BackendFactory = Arc<dyn Fn(DataStore) -> BoxFuture<'static, Result<Box<dyn StorageBackend>, String>> + Send + Sync>;
+
+/// An error from a storage backend, classified so that the
+/// [`RetryPolicy`](crate::retry::RetryPolicy) can tell transient failures
+/// from permanent ones.
+#[derive(Debug, Clone)]
+pub struct StoreError {
+    /// HTTP status returned by the server, if the request got that far.
+    pub status: Option<u16>,
+    /// Set for failures below HTTP (connection refused or reset, timeouts)
+    /// that are worth retrying whatever the status.
+    pub transient: bool,
+    pub message: String,
+}
+
+impl StoreError {
+    pub fn http(status: reqwest::StatusCode, message: impl Into<String>) -> StoreError {
+        StoreError { status: Some(status.as_u16()), transient: false, message: message.into() }
+    }
+
+    pub fn transient(message: impl Into<String>) -> StoreError {
+        StoreError { status: None, transient: true, message: message.into() }
+    }
+
+    pub fn permanent(message: impl Into<String>) -> StoreError {
+        StoreError { status: None, transient: false, message: message.into() }
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<String> for StoreError {
+    fn from(message: String) -> StoreError {
+        StoreError::permanent(message)
+    }
+}
+
+/// Download and list errors travel as `io::Error`; the `StoreError` is kept
+/// as the inner error so that it can still be classified.
+impl From<StoreError> for io::Error {
+    fn from(e: StoreError) -> io::Error {
+        let kind = if e.status == Some(404) { io::ErrorKind::NotFound } else { io::ErrorKind::Other };
+        io::Error::new(kind, e)
+    }
+}
+
+/// How many single-object deletes [`StorageBackend::delete_many`] issues at
+/// once when the backend has no bulk operation.
+const DELETE_CONCURRENCY: usize = 8;
+
+/// The outcome of [`StorageBackend::delete_many`].  Objects that were already
+/// absent count as deleted.
+#[derive(Debug, Default)]
+pub struct DeleteReport {
+    pub deleted: usize,
+    /// Keys that could not be deleted, with the reason.
+    pub errors: Vec<(String, StoreError)>,
+}
+
+impl DeleteReport {
+    pub fn merge(&mut self, other: DeleteReport) {
+        self.deleted += other.deleted;
+        self.errors.extend(other.errors);
+    }
+}
+
+/// An object store that backups can be written to and restored from.
+///
+/// Operations are attempted once; retrying is left to
+/// [`Bucket`](crate::bucket::Bucket).  Failures that happen at the HTTP
+/// level should carry the status, either in a [`StoreError`] or wrapped in
+/// the `io::Error` via `StoreError::into`.
+///
+/// Keys are flat strings such as `data/<hash>`; backends that have a notion
+/// of directories map each `/`-separated segment onto one.  Callers work with
+/// [`Bucket`](crate::bucket::Bucket), which wraps a boxed backend.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Stores the contents of `source` under `key`.  When `md5` (lower-case
+    /// hex) is given, backends that can have the server check it should send
+    /// it so that a corrupted upload is rejected rather than stored.
+    async fn upload(&self, key: &str, source: File, md5: Option<&str>, callback: ProgressCallback) -> Result<(), StoreError>;
+
+    /// Whether [`upload_stream`](StorageBackend::upload_stream) can store an
+    /// object of about `length` bytes.
+    fn can_stream(&self, _length: u64) -> bool {
+        false
+    }
+
+    /// Stores the bytes of `source` under `key` as they arrive, without
+    /// knowing their length up-front.  An error from `source` must fail the
+    /// upload without leaving a partial object behind.
+    async fn upload_stream(&self, key: &str, _source: UploadStream, _callback: ProgressCallback) -> Result<(), StoreError> {
+        Err(StoreError::permanent(format!("Streaming upload of {} is not supported by this store", key)))
+    }
+
+    /// Returns `Ok(true)` if the object exists, `Ok(false)` if it is
+    /// definitively absent, or `Err` if that could not be determined.
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        self.stat(key).await.map(|entry| entry.is_some())
+    }
+
+    async fn download(&self, key: &str, dest: File, callback: ProgressCallback) -> io::Result<u64>;
+
+    /// Returns up to 100 entries whose key begins with `prefix`, with keys
+    /// strictly greater than `marker`, sorted by key (Swift's pagination).
+    async fn list(&self, prefix: Option<&str>, marker: Option<&str>) -> io::Result<Vec<ObjectEntry>>;
+
+    /// Removes the object.  Deleting an object that does not exist succeeds.
+    async fn delete(&self, key: &str) -> Result<(), StoreError>;
+
+    /// Removes every object in `keys`, reporting failures per key rather than
+    /// stopping at the first.  Backends with a bulk operation should override
+    /// this; the default issues single deletes a few at a time.
+    async fn delete_many(&self, keys: &[String]) -> DeleteReport {
+        delete_each(self, keys).await
+    }
+
+    /// Returns the object's size, modification time and (where the backend
+    /// provides one) hash, or `Ok(None)` if it does not exist.
+    async fn stat(&self, key: &str) -> Result<Option<ObjectEntry>, StoreError>;
+
+    /// Whether the `hash` of `entry`, as reported by
+    /// [`stat`](StorageBackend::stat) or [`list`](StorageBackend::list), is
+    /// the MD5 of the object's contents (true of Swift and S3 for objects that
+    /// were not segmented).  Other backends report opaque ETags or nothing at
+    /// all.
+    fn hash_is_md5(&self, _entry: &ObjectEntry) -> bool {
+        false
+    }
+}
+
+/// Deletes `keys` a few at a time through
+/// [`StorageBackend::delete`].  This is the default `delete_many`, exposed so
+/// that backends with an optional bulk operation can fall back to it.
+pub async fn delete_each<B: StorageBackend + ?Sized>(backend: &B, keys: &[String]) -> DeleteReport {
+    let mut report = DeleteReport::default();
+    for batch in keys.chunks(DELETE_CONCURRENCY) {
+        let results = join_all(batch.iter().map(|key| backend.delete(key))).await;
+        for (key, result) in batch.iter().zip(results) {
+            match result {
+                Ok(()) => report.deleted += 1,
+                Err(e) => {
+                    error!("Failed to delete {}: {}", key, e);
+                    report.errors.push((key.clone(), e));
+                }
+            }
+        }
+    }
+    report
+}
+
+fn registry() -> &'static RwLock<HashMap<String, BackendFactory>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, BackendFactory>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut factories: HashMap<String, BackendFactory> = HashMap::new();
+        factories.insert("swift".to_string(), Arc::new(|store| swift_backend(store).boxed()));
+        factories.insert("local".to_string(), Arc::new(|store| local_backend(store).boxed()));
+        factories.insert("s3".to_string(), Arc::new(|store| s3_backend(store).boxed()));
+        factories.insert("sftp".to_string(), Arc::new(|store| sftp_backend(store).boxed()));
+        factories.insert("webdav".to_string(), Arc::new(|store| webdav_backend(store).boxed()));
+        RwLock::new(factories)
+    })
+}
+
+/// Makes `factory` available to stores configured with `type = "<name>"`.
+/// Registering a name a second time replaces the earlier factory, so the
+/// built-in backends can be overridden too.
+///
+/// ```ignore
+/// backup_tool::storage::register_backend("tape", |store| Box::pin(async move {
+///     let options: TapeConfig = store.options.clone()
+///         .ok_or("tape stores need an [stores.options] table")?
+///         .try_into()
+///         .map_err(|e| format!("Invalid tape options: {}", e))?;
+///     Ok(Box::new(TapeBackend::new(options)) as Box<dyn StorageBackend>)
+/// }));
+/// ```
+pub fn register_backend<F>(name: &str, factory: F)
+where
+    F: Fn(DataStore) -> BoxFuture<'static, Result<Box<dyn StorageBackend>, String>> + Send + Sync + 'static,
+{
+    registry().write().unwrap().insert(name.to_string(), Arc::new(factory));
+}
+
+/// Builds the backend named by the store's `type`.
+pub async fn create_backend(store: &DataStore) -> Result<Box<dyn StorageBackend>, String> {
+    let backend_type = store.backend_type();
+    let factory = registry().read().unwrap().get(backend_type).cloned()
+        .ok_or_else(|| format!("Store {} has unknown type {:?}", store.id, backend_type))?;
+    factory(store.clone()).await
+}
+
+async fn swift_backend(store: DataStore) -> Result<Box<dyn StorageBackend>, String> {
+    let container = store.container.as_deref()
+        .ok_or_else(|| format!("Store {} has no container configured and no local_path, s3, sftp or webdav set", store.id))?;
+    let segment_size = store.segment_size.unwrap_or(DEFAULT_SEGMENT_SIZE);
+    if segment_size < MIN_SEGMENT_SIZE {
+        return Err(format!("Store {} has segment_size {} below Swift's minimum of {} bytes", store.id, segment_size, MIN_SEGMENT_SIZE));
+    }
+    let segment_prefix = store.segment_prefix.as_deref().unwrap_or(DEFAULT_SEGMENT_PREFIX);
+    let session = match store.cloud_config.clone() {
+        Some(config) => config.create_session().await,
+        None => osauth::Session::from_env().await
+    }.map_err(|e| format!("Failed to create an identity provider: {:?}", e))?;
+    let throttle = Throttle::new(store.bandwidth.as_ref())?;
+    let bucket = SwiftBucket::new(session, container, segment_size, segment_prefix).with_throttle(throttle);
+    bucket.ensure_container(
+        store.create_container,
+        store.storage_policy.as_deref(),
+        &store.container_metadata.clone().unwrap_or_default(),
+        store.versioning,
+    ).await?;
+    Ok(Box::new(bucket))
+}
+
+async fn local_backend(store: DataStore) -> Result<Box<dyn StorageBackend>, String> {
+    let path = store.local_path.as_deref()
+        .ok_or_else(|| format!("Store {} has type \"local\" but no local_path", store.id))?;
+    let throttle = Throttle::new(store.bandwidth.as_ref())?;
+    Ok(Box::new(LocalBucket::new(path).with_throttle(throttle)))
+}
+
+async fn s3_backend(store: DataStore) -> Result<Box<dyn StorageBackend>, String> {
+    let config = store.s3.as_ref()
+        .ok_or_else(|| format!("Store {} has type \"s3\" but no [stores.s3] section", store.id))?;
+    let throttle = Throttle::new(store.bandwidth.as_ref())?;
+    let bucket = S3Bucket::new(config).map_err(|e| format!("Store {}: {}", store.id, e))?;
+    Ok(Box::new(bucket.with_throttle(throttle)))
+}
+
+async fn sftp_backend(store: DataStore) -> Result<Box<dyn StorageBackend>, String> {
+    let config = store.sftp.as_ref()
+        .ok_or_else(|| format!("Store {} has type \"sftp\" but no [stores.sftp] section", store.id))?;
+    let bucket = SftpBucket::connect(config).await
+        .map_err(|e| format!("Failed to connect to SFTP store {}: {}", store.id, e))?;
+    let throttle = Throttle::new(store.bandwidth.as_ref())?;
+    Ok(Box::new(bucket.with_throttle(throttle)))
+}
+
+async fn webdav_backend(store: DataStore) -> Result<Box<dyn StorageBackend>, String> {
+    let config = store.webdav.as_ref()
+        .ok_or_else(|| format!("Store {} has type \"webdav\" but no [stores.webdav] section", store.id))?;
+    let throttle = Throttle::new(store.bandwidth.as_ref())?;
+    let bucket = WebDavBucket::new(config).map_err(|e| format!("Store {}: {}", store.id, e))?;
+    Ok(Box::new(bucket.with_throttle(throttle)))
+}
@@ -1,88 +1,560 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::future::Future;
+use std::io::{Seek, SeekFrom};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use async_trait::async_trait;
 use futures::TryStreamExt;
-use log::error;
+use log::{error, info, warn};
+use md5::{Digest, Md5};
 use reqwest::Method;
 use osauth::Session;
 use osauth::services::OBJECT_STORAGE;
 use futures::stream::StreamExt;
+use tokio::io::AsyncReadExt;
 use tokio_util::io::StreamReader;
 use crate::query;
 use query::Query;
 use crate::bucket::ObjectEntry;
+use crate::throttle::{Direction, Throttle};
+use crate::storage::{delete_each, DeleteReport, ProgressCallback, StorageBackend, StoreError, UploadStream};
+use crate::utils::{uri_decode, uri_encode};
 
-/// The Swift-backed implementation.  Use [`Bucket`] in calling code.
+/// Objects per bulk-delete request.  Swift's default `max_deletes_per_request`
+/// is 10000; smaller batches keep each request well inside proxy timeouts.
+const BULK_DELETE_BATCH: usize = 1000;
+
+/// Objects larger than this are uploaded as Static Large Objects.  Swift's
+/// default `max_file_size` is 5 GiB; 1 GiB segments keep retries cheap.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 1024 * 1024 * 1024;
+/// Swift's default `min_segment_size`.
+pub const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_SEGMENT_PREFIX: &str = "segments/";
+
+/// One entry of an SLO manifest, as sent with `?multipart-manifest=put`.
+#[derive(Serialize)]
+struct SloSegment {
+  path: String,
+  etag: Option<String>,
+  size_bytes: u64,
+}
+
+/// Response body of the bulk middleware when called with `Accept: application/json`.
+#[derive(Deserialize)]
+struct BulkDeleteResponse {
+  #[serde(rename = "Number Deleted")]
+  number_deleted: usize,
+  #[serde(rename = "Number Not Found")]
+  number_not_found: usize,
+  #[serde(rename = "Response Status")]
+  response_status: String,
+  #[serde(rename = "Response Body", default)]
+  response_body: String,
+  /// `[path, status]` pairs, with `path` percent-encoded.
+  #[serde(rename = "Errors", default)]
+  errors: Vec<(String, String)>,
+}
+
+/// The Swift-backed implementation.  Use [`Bucket`](crate::bucket::Bucket) in calling code.
+///
+/// Objects larger than `segment_size` are split into segments stored under
+/// `<segment_prefix><key>/` and tied together by a Static Large Object
+/// manifest at `key`.  Swift reassembles them on GET, so downloads, range
+/// requests and HEAD need no special handling; listings hide the segments.
+///
+/// Every request goes through [`send`](SwiftBucket::send), which obtains a
+/// new token and repeats the request when Swift answers 401, so that backups
+/// outlasting the Keystone token lifetime keep working.
 pub struct SwiftBucket {
-  session: Session,
+  session: RwLock<Session>,
+  /// Incremented each time the session is re-authenticated, so that
+  /// requests that failed with the same expired token only refresh it once.
+  auth_generation: AtomicU64,
+  reauthenticating: tokio::sync::Mutex<()>,
   container: String,
+  segment_size: u64,
+  segment_prefix: String,
+  /// Cleared once the cluster turns out not to have the bulk middleware.
+  bulk_delete: AtomicBool,
+  throttle: Throttle,
 }
 
 impl SwiftBucket {
-    pub fn new(session: Session, container: &str) -> SwiftBucket {
+    pub fn new(session: Session, container: &str, segment_size: u64, segment_prefix: &str) -> SwiftBucket {
         SwiftBucket {
-            session,
+            session: RwLock::new(session),
+            auth_generation: AtomicU64::new(0),
+            reauthenticating: tokio::sync::Mutex::new(()),
             container: container.to_string(),
+            segment_size,
+            segment_prefix: segment_prefix.to_string(),
+            bulk_delete: AtomicBool::new(true),
+            throttle: Throttle::default(),
         }
     }
 
-    pub async fn upload_with_progress(&self, key: &str, source: File, callback: impl Fn(usize) + Sync + Send + 'static) -> Result<(), String> {
-      let tokio_file = tokio::fs::File::from(source);
-      let stream = tokio_util::io::ReaderStream::new(tokio_file).inspect_ok(move |bytes| {
+    /// Limits the bandwidth used by uploads and downloads.
+    pub fn with_throttle(mut self, throttle: Throttle) -> SwiftBucket {
+        self.throttle = throttle;
+        self
+    }
+
+    fn current_session(&self) -> (Session, u64) {
+        let session = self.session.read().unwrap();
+        (session.clone(), self.auth_generation.load(Ordering::Acquire))
+    }
+
+    /// Obtains a new token, unless another request already did so after
+    /// `generation` was observed.
+    async fn reauthenticate(&self, generation: u64) -> Result<(), StoreError> {
+      let _guard = self.reauthenticating.lock().await;
+      if self.auth_generation.load(Ordering::Acquire) != generation {
+        return Ok(());
+      }
+      warn!("Swift rejected the token for container {}; re-authenticating", self.container);
+      let mut session = self.session.read().unwrap().clone();
+      session.refresh().await
+        .map_err(|e| StoreError::transient(format!("Failed to re-authenticate to Swift: {:?}", e)))?;
+      *self.session.write().unwrap() = session;
+      self.auth_generation.fetch_add(1, Ordering::AcqRel);
+      Ok(())
+    }
+
+    /// Sends the request that `build` creates from the current session.  If
+    /// Swift rejects the token, re-authenticates and calls `build` again, so
+    /// streamed bodies must be recreated from the start each time.  Failures
+    /// to send are reported as transient errors prefixed with `context`.
+    async fn send<F, Fut>(&self, context: &str, mut build: F) -> Result<reqwest::Response, StoreError>
+    where
+      F: FnMut(Session) -> Result<Fut, StoreError>,
+      Fut: Future<Output = Result<reqwest::Response, osauth::Error>>,
+    {
+      let mut reauthenticated = false;
+      loop {
+        let (session, generation) = self.current_session();
+        let result = build(session)?.await;
+        let rejected = match &result {
+          Ok(response) => response.status() == reqwest::StatusCode::UNAUTHORIZED,
+          Err(e) => matches!(e.kind(), osauth::ErrorKind::AuthenticationFailed),
+        };
+        if rejected && !reauthenticated {
+          self.reauthenticate(generation).await?;
+          reauthenticated = true;
+          continue;
+        }
+        return result.map_err(|e| StoreError::transient(format!("{}: {:?}", context, e)));
+      }
+    }
+
+    /// A request body streaming `size` bytes of `source` from `offset`,
+    /// reporting progress to `callback` and feeding `hasher` if given.
+    fn body(
+      &self,
+      source: &File,
+      offset: u64,
+      size: u64,
+      callback: &Arc<ProgressCallback>,
+      hasher: Option<&Arc<Mutex<Md5>>>,
+    ) -> Result<reqwest::Body, StoreError> {
+      let mut source = source.try_clone()
+        .map_err(|e| format!("Failed to reopen upload source: {}", e))?;
+      source.seek(SeekFrom::Start(offset))
+        .map_err(|e| format!("Failed to seek upload source: {}", e))?;
+      let reader = tokio::fs::File::from(source).take(size);
+      let callback = Arc::clone(callback);
+      let hasher = hasher.cloned();
+      let stream = self.throttle.stream(Direction::Upload, tokio_util::io::ReaderStream::new(reader)).inspect_ok(move |bytes| {
+        if let Some(hasher) = &hasher {
+          hasher.lock().unwrap().update(bytes);
+        }
         callback(bytes.len())
       });
-      let response = self.session.put(OBJECT_STORAGE, &[self.container.as_ref(), key])
-        .body(reqwest::Body::wrap_stream(stream))
-        .send().await
-        .map_err(|e| format!("Swift upload error: {:?}", e))?;
+      Ok(reqwest::Body::wrap_stream(stream))
+    }
+
+    /// Checks that the container exists, creating it with the given storage
+    /// policy, `X-Container-Meta-*` metadata and versioning if `create` is
+    /// set.  An existing container is left as it is.
+    pub async fn ensure_container(
+      &self,
+      create: bool,
+      storage_policy: Option<&str>,
+      metadata: &HashMap<String, String>,
+      versioning: bool,
+    ) -> Result<(), String> {
+      let context = format!("Error checking Swift container {}", self.container);
+      let response = self.send(&context, |session| Ok(async move {
+        session.request(OBJECT_STORAGE, Method::HEAD, &[self.container.as_str()]).send().await
+      })).await.map_err(|e| e.to_string())?;
       let status = response.status();
       if status.is_success() {
+        let policy = response.headers().get("x-storage-policy").and_then(|v| v.to_str().ok());
+        if let (Some(wanted), Some(actual)) = (storage_policy, policy) {
+          if !wanted.eq_ignore_ascii_case(actual) {
+            warn!("Swift container {} uses storage policy {:?}, not {:?}; the policy of an existing container cannot be changed",
+              self.container, actual, wanted);
+          }
+        }
+        return Ok(());
+      }
+      if status != reqwest::StatusCode::NOT_FOUND {
+        return Err(format!("Unexpected status {} checking Swift container {}", status, self.container));
+      }
+      if !create {
+        return Err(format!("Swift container {} does not exist; create it or set create_container = true", self.container));
+      }
+
+      let context = format!("Error creating Swift container {}", self.container);
+      let response = self.send(&context, |session| Ok(async move {
+        let mut request = session.put(OBJECT_STORAGE, &[self.container.as_str()]);
+        if let Some(policy) = storage_policy {
+          request = request.header("X-Storage-Policy", policy);
+        }
+        for (name, value) in metadata {
+          request = request.header(format!("X-Container-Meta-{}", name), value);
+        }
+        if versioning {
+          request = request.header("X-Versions-Enabled", "true");
+        }
+        request.send().await
+      })).await.map_err(|e| e.to_string())?;
+      let status = response.status();
+      if status.is_success() {
+        info!("Created Swift container {}{}", self.container,
+          storage_policy.map(|p| format!(" with storage policy {}", p)).unwrap_or_default());
         Ok(())
       } else {
         let body = response.text().await.unwrap_or_else(|_| "<unreadable body>".to_string());
-        Err(format!("Swift upload failed: HTTP {} for {}/{}: {}", status, self.container, key, body))
+        Err(format!("Failed to create Swift container {}: HTTP {}: {}", self.container, status, body))
       }
     }
 
-    /// Returns `Ok(true)` if the object exists (2xx), `Ok(false)` if it is
-    /// definitively absent (HTTP 404), or `Err` for any other non-success
-    /// status or request-level failure.
-    pub async fn exists(&self, key: &str) -> Result<bool, String> {
-        match self.session.request(OBJECT_STORAGE, Method::HEAD, &[self.container.as_ref(), key])
+    async fn put_object(&self, key: &str, source: File, length: u64, md5: Option<&str>, callback: ProgressCallback) -> Result<(), StoreError> {
+      let callback = Arc::new(callback);
+      let response = self.send("Swift upload error", |session| {
+        let body = self.body(&source, 0, length, &callback, None)?;
+        Ok(async move {
+          let mut request = session.put(OBJECT_STORAGE, &[self.container.as_str(), key]);
+          // Swift answers 422 Unprocessable Entity if what it received does
+          // not hash to the ETag we send.
+          if let Some(md5) = md5 {
+            request = request.header("ETag", md5);
+          }
+          request.body(body).send().await
+        })
+      }).await?;
+      let status = response.status();
+      if status.is_success() {
+        Ok(())
+      } else {
+        let body = response.text().await.unwrap_or_else(|_| "<unreadable body>".to_string());
+        Err(StoreError::http(status, format!("Swift upload failed: HTTP {} for {}/{}: {}", status, self.container, key, body)))
+      }
+    }
+
+    /// PUTs the bytes of `source` with chunked transfer encoding as they
+    /// arrive.  The MD5 of what was sent is only known at the end, so it is
+    /// compared with the ETag Swift returns instead of being sent up-front.
+    async fn put_stream(&self, key: &str, source: UploadStream, callback: ProgressCallback) -> Result<(), StoreError> {
+      // A stream can only be sent once, so a request repeated after
+      // reauthenticating fails and the caller uploads from a file instead.
+      let source = Mutex::new(Some(source));
+      let hasher = Arc::new(Mutex::new(Md5::new()));
+      let callback = Arc::new(callback);
+      let response = self.send("Swift upload error", |session| {
+        let source = source.lock().unwrap().take()
+          .ok_or_else(|| StoreError::transient(format!("Upload stream of {} cannot be restarted", key)))?;
+        let hasher = Arc::clone(&hasher);
+        let callback = Arc::clone(&callback);
+        let stream = self.throttle.stream(Direction::Upload, source).inspect_ok(move |bytes| {
+          hasher.lock().unwrap().update(bytes);
+          callback(bytes.len())
+        });
+        Ok(async move {
+          session.put(OBJECT_STORAGE, &[self.container.as_str(), key])
+            .body(reqwest::Body::wrap_stream(stream))
             .send().await
-        {
-            Ok(response) => {
-                let status = response.status();
-                if status.is_success() {
-                    Ok(true)
-                } else if status == reqwest::StatusCode::NOT_FOUND {
-                    Ok(false)
-                } else {
-                    let msg = format!(
-                        "Unexpected status {} checking existence of {}/{}",
-                        status, self.container, key
-                    );
-                    error!("{}", msg);
-                    Err(msg)
-                }
+        })
+      }).await?;
+      let status = response.status();
+      if !status.is_success() {
+        let body = response.text().await.unwrap_or_else(|_| "<unreadable body>".to_string());
+        return Err(StoreError::http(status, format!("Swift upload failed: HTTP {} for {}/{}: {}", status, self.container, key, body)));
+      }
+      let etag = response.headers().get("etag")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_matches('"').to_string());
+      let sent = format!("{:x}", hasher.lock().unwrap().clone().finalize());
+      if etag.as_deref().is_some_and(|etag| etag != sent) {
+        return Err(StoreError::transient(format!("Swift object {}/{} was corrupted in transit: sent MD5 {}, stored ETag {}",
+          self.container, key, sent, etag.unwrap_or_default())));
+      }
+      Ok(())
+    }
+
+    /// Uploads `source` as consecutive segments of at most `segment_size`
+    /// bytes, then PUTs the manifest at `key`.  The whole-object MD5 cannot be
+    /// checked against an SLO, so each segment is hashed as it is sent and
+    /// compared with the ETag Swift returns for it instead.
+    async fn put_segmented(&self, key: &str, source: File, length: u64, callback: ProgressCallback) -> Result<(), StoreError> {
+      let callback = Arc::new(callback);
+      let mut segments: Vec<SloSegment> = Vec::new();
+      let mut offset = 0;
+      while offset < length {
+        let size = self.segment_size.min(length - offset);
+        let segment_key = format!("{}{}/{:08}", self.segment_prefix, key, segments.len());
+        let hasher = Arc::new(Mutex::new(Md5::new()));
+        let response = self.send("Swift segment upload error", |session| {
+          *hasher.lock().unwrap() = Md5::new();
+          let body = self.body(&source, offset, size, &callback, Some(&hasher))?;
+          let segment_key = segment_key.as_str();
+          Ok(async move {
+            session.put(OBJECT_STORAGE, &[self.container.as_str(), segment_key])
+              .header("Content-Length", size)
+              .body(body)
+              .send().await
+          })
+        }).await?;
+        let status = response.status();
+        if !status.is_success() {
+          let body = response.text().await.unwrap_or_else(|_| "<unreadable body>".to_string());
+          return Err(StoreError::http(status, format!("Swift segment upload failed: HTTP {} for {}/{}: {}", status, self.container, segment_key, body)));
+        }
+        // Passing each segment's ETag back in the manifest makes Swift check
+        // that the segments are the ones we just wrote.
+        let etag = response.headers().get("etag")
+          .and_then(|v| v.to_str().ok())
+          .map(|v| v.trim_matches('"').to_string());
+        let sent = format!("{:x}", hasher.lock().unwrap().clone().finalize());
+        if etag.as_deref().is_some_and(|etag| etag != sent) {
+          return Err(StoreError::transient(format!("Swift segment {}/{} was corrupted in transit: sent MD5 {}, stored ETag {}",
+            self.container, segment_key, sent, etag.unwrap_or_default())));
+        }
+        segments.push(SloSegment {
+          path: format!("/{}/{}", self.container, segment_key),
+          etag,
+          size_bytes: size,
+        });
+        offset += size;
+      }
+
+      let segments = &segments;
+      let response = self.send("Swift manifest upload error", |session| Ok(async move {
+        session.put(OBJECT_STORAGE, &[self.container.as_str(), key])
+          .query(&[("multipart-manifest", "put")])
+          .json(segments)
+          .send().await
+      })).await?;
+      let status = response.status();
+      if status.is_success() {
+        Ok(())
+      } else {
+        let body = response.text().await.unwrap_or_else(|_| "<unreadable body>".to_string());
+        Err(StoreError::http(status, format!("Swift manifest upload failed: HTTP {} for {}/{}: {}", status, self.container, key, body)))
+      }
+    }
+
+    async fn get(&self, key: &str) -> std::io::Result<reqwest::Response> {
+      let context = format!("Swift download error for {}/{}", self.container, key);
+      let response = self.send(&context, |session| Ok(async move {
+        session.get(OBJECT_STORAGE, &[self.container.as_str(), key]).send().await
+      })).await?;
+      let status = response.status();
+      if !status.is_success() {
+        return Err(StoreError::http(status, format!("Swift download failed: HTTP {} for {}/{}", status, self.container, key)).into());
+      }
+      Ok(response)
+    }
+
+    /// One page of the raw container listing, segments included.
+    async fn list_page(&self, prefix: Option<&str>, marker: Option<&str>) -> std::io::Result<Vec<ObjectEntry>> {
+      let mut query = Query::new();
+      query.push_str("format", "json");
+      query.push_str("limit", "100");
+      if let Some(p) = prefix {
+        query.push_str("prefix", p);
+      }
+      if let Some(m) = marker {
+        query.push_str("marker", m);
+      }
+
+      let query = &query;
+      let context = format!("Swift list error for {}", self.container);
+      let response = self.send(&context, |session| Ok(async move {
+        session.get(OBJECT_STORAGE, &[self.container.as_str(), ""])
+          .query(query)
+          .send().await
+      })).await?;
+      let status = response.status();
+      if !status.is_success() {
+        return Err(StoreError::http(status, format!("Swift list failed: HTTP {} for {}", status, self.container)).into());
+      }
+      response.json().await
+        .map_err(|e| std::io::Error::from(StoreError::transient(format!("Unreadable Swift listing for {}: {:?}", self.container, e))))
+    }
+
+    /// Returns the segment keys belonging to each of `keys` that has any.
+    /// Segments are rare, so a single walk over the segment prefix is cheaper
+    /// than a HEAD per key.
+    async fn segments_of(&self, keys: &[String]) -> std::io::Result<HashMap<String, Vec<String>>> {
+      let wanted: HashSet<&str> = keys.iter().map(|k| k.as_str()).collect();
+      let mut segments: HashMap<String, Vec<String>> = HashMap::new();
+      let mut marker: Option<String> = None;
+      loop {
+        let page = self.list_page(Some(&self.segment_prefix), marker.as_deref()).await?;
+        let Some(last) = page.last() else { break };
+        marker = Some(last.name.clone());
+        for entry in page {
+          let owner = entry.name.strip_prefix(&self.segment_prefix)
+            .and_then(|rest| rest.rsplit_once('/'))
+            .map(|(owner, _)| owner)
+            .filter(|owner| wanted.contains(owner));
+          if let Some(owner) = owner {
+            segments.entry(owner.to_string()).or_default().push(entry.name);
+          }
+        }
+      }
+      Ok(segments)
+    }
+
+    /// Deletes `keys` in one request to the bulk middleware.  Returns
+    /// `Ok(None)` if the cluster does not support bulk delete.
+    async fn bulk_delete(&self, keys: &[String]) -> Result<Option<DeleteReport>, StoreError> {
+      let container_path = format!("/{}/", uri_encode(&self.container, true));
+      let body: String = keys.iter()
+        .map(|key| format!("{}{}\n", container_path, uri_encode(key, false)))
+        .collect();
+      let body = &body;
+      let response = self.send("Swift bulk delete error", |session| Ok(async move {
+        session.post(OBJECT_STORAGE, std::iter::empty::<&str>())
+          .query(&[("bulk-delete", "true")])
+          .header("Content-Type", "text/plain")
+          .header("Accept", "application/json")
+          .body(body.clone())
+          .send().await
+      })).await?;
+      let status = response.status();
+      // Without the middleware the request is an ordinary account POST, which
+      // answers 204 (or is refused outright).
+      if status == reqwest::StatusCode::NO_CONTENT
+        || status == reqwest::StatusCode::NOT_FOUND
+        || status == reqwest::StatusCode::METHOD_NOT_ALLOWED
+        || status == reqwest::StatusCode::NOT_IMPLEMENTED {
+        return Ok(None);
+      }
+      if !status.is_success() {
+        let body = response.text().await.unwrap_or_else(|_| "<unreadable body>".to_string());
+        return Err(StoreError::http(status, format!("Swift bulk delete failed: HTTP {}: {}", status, body)));
+      }
+      let result: BulkDeleteResponse = match response.json().await {
+        Ok(result) => result,
+        Err(_) => return Ok(None),
+      };
+      let mut report = DeleteReport {
+        deleted: result.number_deleted + result.number_not_found,
+        errors: Vec::new(),
+      };
+      for (path, status) in result.errors {
+        let path = uri_decode(&path);
+        let key = path.strip_prefix(&uri_decode(&container_path)).unwrap_or(&path).to_string();
+        let message = format!("Failed to delete {}/{}: {}", self.container, key, status);
+        error!("{}", message);
+        let error = match status.split(' ').next().and_then(|code| code.parse().ok()).and_then(|code| reqwest::StatusCode::from_u16(code).ok()) {
+          Some(code) => StoreError::http(code, message),
+          None => StoreError::permanent(message),
+        };
+        report.errors.push((key, error));
+      }
+      // The whole request can fail (e.g. 400 for too many paths) without any
+      // per-object errors.
+      if report.errors.is_empty() && !result.response_status.starts_with('2') {
+        return Err(StoreError::permanent(format!("Swift bulk delete failed: {}: {}", result.response_status, result.response_body)));
+      }
+      Ok(Some(report))
+    }
+
+    /// Deletes `keys` in batches through the bulk middleware, or one at a
+    /// time once the cluster turns out not to have it.
+    async fn delete_batches(&self, keys: &[String]) -> DeleteReport {
+      let mut report = DeleteReport::default();
+      for batch in keys.chunks(BULK_DELETE_BATCH) {
+        if self.bulk_delete.load(Ordering::Relaxed) {
+          match self.bulk_delete(batch).await {
+            Ok(Some(batch_report)) => {
+              report.merge(batch_report);
+              continue;
+            }
+            Ok(None) => {
+              warn!("Bulk delete is not available on this cluster; deleting objects individually");
+              self.bulk_delete.store(false, Ordering::Relaxed);
             }
             Err(e) => {
-                let msg = format!("Error checking existence of {}/{}: {:?}", self.container, key, e);
-                error!("{}", msg);
-                Err(msg)
+              error!("{}", e);
+              report.errors.extend(batch.iter().map(|key| (key.clone(), e.clone())));
+              continue;
             }
+          }
         }
+        report.merge(delete_each(self, batch).await);
+      }
+      report
     }
+}
 
-    pub async fn download_with_progress(&self, key: &str, dest: File, callback: impl Fn(usize) + Sync + Send + 'static) -> std::io::Result<u64> {
-      let response = self.session.get(OBJECT_STORAGE, &[self.container.as_ref(), key]).send().await.unwrap();
-      let stream = response
-        .bytes_stream()
+#[async_trait]
+impl StorageBackend for SwiftBucket {
+    async fn upload(&self, key: &str, source: File, md5: Option<&str>, callback: ProgressCallback) -> Result<(), StoreError> {
+      let length = source.metadata()
+        .map_err(|e| format!("Failed to stat upload source for {}: {}", key, e))?
+        .len();
+      if length > self.segment_size {
+        self.put_segmented(key, source, length, callback).await
+      } else {
+        self.put_object(key, source, length, md5, callback).await
+      }
+    }
+
+    /// Objects that would be segmented need their length up-front.
+    fn can_stream(&self, length: u64) -> bool {
+      length < self.segment_size
+    }
+
+    async fn upload_stream(&self, key: &str, source: UploadStream, callback: ProgressCallback) -> Result<(), StoreError> {
+      self.put_stream(key, source, callback).await
+    }
+
+    /// Returns `Ok(true)` if the object exists (2xx), `Ok(false)` if it is
+    /// definitively absent (HTTP 404), or `Err` for any other non-success
+    /// status or request-level failure.
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        let context = format!("Error checking existence of {}/{}", self.container, key);
+        let response = self.send(&context, |session| Ok(async move {
+            session.request(OBJECT_STORAGE, Method::HEAD, &[self.container.as_str(), key]).send().await
+        })).await.inspect_err(|e| error!("{}", e))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(true)
+        } else if status == reqwest::StatusCode::NOT_FOUND {
+            Ok(false)
+        } else {
+            let msg = format!(
+                "Unexpected status {} checking existence of {}/{}",
+                status, self.container, key
+            );
+            error!("{}", msg);
+            Err(StoreError::http(status, msg))
+        }
+    }
+
+    async fn download(&self, key: &str, dest: File, callback: ProgressCallback) -> std::io::Result<u64> {
+      let response = self.get(key).await?;
+      let stream = self.throttle.stream(Direction::Download, response.bytes_stream())
         .map(move |result| {
-            result.map(|bytes| {
+            result.inspect(|bytes| {
                 callback(bytes.len());
-                bytes
-            }).map_err(|_error| {
-                error!("Encountered error");
-                std::io::Error::new(std::io::ErrorKind::Other, "Error!")
+            }).map_err(|e| {
+                std::io::Error::from(StoreError::transient(format!("Swift download interrupted: {:?}", e)))
             })
         });
       let mut reader = StreamReader::new(stream);
@@ -90,43 +562,108 @@ impl SwiftBucket {
       tokio::io::copy(&mut reader, &mut tokio_file).await
     }
 
-    pub async fn download(&self, key: &str, dest: File) -> std::io::Result<u64> {
-      let response = self.session.get(OBJECT_STORAGE, &[self.container.as_ref(), key]).send().await.unwrap();
-      let stream = response
-        .bytes_stream()
-        .map(|result| {
-            result.map_err(|_error| {
-                error!("Encountered error");
-                std::io::Error::new(std::io::ErrorKind::Other, "Error!")
-              }
-            )
-        });
-      let mut reader = StreamReader::new(stream);
-      let mut tokio_file = tokio::fs::File::from(dest);
-      tokio::io::copy(&mut reader, &mut tokio_file).await
+    /// Segments are left out unless `prefix` is within the segment prefix,
+    /// so that callers see each large object once, as its manifest.
+    async fn list(&self, prefix: Option<&str>, marker: Option<&str>) -> std::io::Result<Vec<ObjectEntry>> {
+      let show_segments = prefix.is_some_and(|p| p.starts_with(&self.segment_prefix));
+      let mut marker = marker.map(|m| m.to_string());
+      loop {
+        let page = self.list_page(prefix, marker.as_deref()).await?;
+        if show_segments || page.is_empty() {
+          return Ok(page);
+        }
+        marker = page.last().map(|e| e.name.clone());
+        let entries: Vec<ObjectEntry> = page.into_iter()
+          .filter(|e| !e.name.starts_with(&self.segment_prefix))
+          .collect();
+        // A page made up entirely of segments must not look like the end of
+        // the listing.
+        if !entries.is_empty() {
+          return Ok(entries);
+        }
+      }
     }
 
-    pub async fn list(&self, prefix: Option<&str>, marker: Option<&str>) -> std::io::Result<Vec<ObjectEntry>> {
-      let mut query = Query::new();
-      query.push_str("format", "json");
-      query.push_str("limit", "100");
-      match prefix {
-        Some(p) => {
-          query.push_str("prefix", p);
-        },
-        _ => {}
-      };
-      match marker {
-        Some(m) => {
-          query.push_str("marker", m);
-        },
-        _ => {}
+    /// Deletes the object, and its segments if it is a Static Large Object.
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+      let context = format!("Swift delete error for {}/{}", self.container, key);
+      let response = self.send(&context, |session| Ok(async move {
+        session.delete(OBJECT_STORAGE, &[self.container.as_str(), key])
+          .query(&[("multipart-manifest", "delete")])
+          .header("Accept", "application/json")
+          .send().await
+      })).await?;
+      let status = response.status();
+      if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(());
+      }
+      if !status.is_success() {
+        return Err(StoreError::http(status, format!("Swift delete failed: HTTP {} for {}/{}", status, self.container, key)));
+      }
+      // With the SLO middleware the result is reported in a bulk-style body;
+      // without it this is a plain 204.
+      match response.json::<BulkDeleteResponse>().await {
+        Ok(result) if !result.errors.is_empty() => Err(StoreError::permanent(format!(
+          "Swift delete failed for {}/{}: {}", self.container, key,
+          result.errors.iter().map(|(path, status)| format!("{} {}", uri_decode(path), status)).collect::<Vec<_>>().join(", ")
+        ))),
+        Ok(result) if !result.response_status.starts_with('2') => Err(StoreError::permanent(format!(
+          "Swift delete failed for {}/{}: {}: {}", self.container, key, result.response_status, result.response_body
+        ))),
+        _ => Ok(()),
+      }
+    }
+
+    /// Uses Swift's bulk-delete middleware when the cluster provides it,
+    /// falling back to one DELETE per object otherwise.  The bulk middleware
+    /// does not follow manifests, so segments are looked up and deleted
+    /// first; a manifest is only deleted once all of its segments are gone,
+    /// and a failed segment is reported against its manifest.  Only `keys`
+    /// are counted as deleted.
+    async fn delete_many(&self, keys: &[String]) -> DeleteReport {
+      let segments = match self.segments_of(keys).await {
+        Ok(segments) => segments,
+        Err(e) => {
+          // A single DELETE with multipart-manifest=delete removes the
+          // segments of a manifest without them being listed.
+          warn!("Failed to list segments in {}; deleting objects one at a time: {}", self.container, e);
+          return delete_each(self, keys).await;
+        }
       };
+      let all_segments: Vec<String> = segments.values().flatten().cloned().collect();
+      let segment_report = self.delete_batches(&all_segments).await;
+      let failed: HashMap<&str, &StoreError> = segment_report.errors.iter().map(|(key, e)| (key.as_str(), e)).collect();
+      let mut report = DeleteReport::default();
+      let mut manifests = Vec::new();
+      for key in keys {
+        let failed_segment = segments.get(key)
+          .and_then(|owned| owned.iter().find_map(|segment| failed.get(segment.as_str())));
+        match failed_segment {
+          Some(e) => report.errors.push((key.clone(), (*e).clone())),
+          None => manifests.push(key.clone()),
+        }
+      }
+      report.merge(self.delete_batches(&manifests).await);
+      report
+    }
 
-      let response = self.session.get(OBJECT_STORAGE, &[self.container.as_ref(), ""])
-        .query(&query)
-        .send().await.unwrap()
-        .json().await.unwrap();
-      Ok(response)
+    async fn stat(&self, key: &str) -> Result<Option<ObjectEntry>, StoreError> {
+      let context = format!("Error checking {}/{}", self.container, key);
+      let response = self.send(&context, |session| Ok(async move {
+        session.request(OBJECT_STORAGE, Method::HEAD, &[self.container.as_str(), key]).send().await
+      })).await?;
+      let status = response.status();
+      if status.is_success() {
+        Ok(Some(ObjectEntry::from_headers(key, response.headers())))
+      } else if status == reqwest::StatusCode::NOT_FOUND {
+        Ok(None)
+      } else {
+        Err(StoreError::http(status, format!("Unexpected status {} checking {}/{}", status, self.container, key)))
+      }
+    }
+
+    fn hash_is_md5(&self, entry: &ObjectEntry) -> bool {
+      // SLO manifests report the MD5 of their segments' ETags, not of the content.
+      entry.slo_etag.is_none() && !entry.hash.is_empty()
     }
 }
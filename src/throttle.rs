@@ -0,0 +1,193 @@
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use chrono::{Local, NaiveTime};
+use futures::stream::{BoxStream, Stream, StreamExt};
+
+/// Bandwidth limits in bytes per second, configured per store as
+/// `[stores.bandwidth]` and for all stores together as `[bandwidth]`.
+///
+/// Outside every `schedule` window `upload` and `download` apply; inside a
+/// window the window's own limits apply instead, and a limit the window
+/// leaves out is lifted.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct BandwidthLimit {
+  pub upload: Option<u64>,
+  pub download: Option<u64>,
+  #[serde(default)]
+  pub schedule: Vec<BandwidthWindow>,
+}
+
+/// A daily window in local time, from `start` up to `end` (both `HH:MM`).
+/// Windows may wrap past midnight; the first one that matches wins.
+#[derive(Deserialize, Clone, Debug)]
+pub struct BandwidthWindow {
+  pub start: String,
+  pub end: String,
+  pub upload: Option<u64>,
+  pub download: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+  Upload,
+  Download,
+}
+
+struct Window {
+  start: NaiveTime,
+  end: NaiveTime,
+  upload: Option<u64>,
+  download: Option<u64>,
+}
+
+impl Window {
+  fn contains(&self, time: NaiveTime) -> bool {
+    if self.start <= self.end {
+      self.start <= time && time < self.end
+    } else {
+      time >= self.start || time < self.end
+    }
+  }
+}
+
+/// Bytes that may be sent right away; negative when callers are already
+/// waiting for bandwidth.
+struct Allowance {
+  available: f64,
+  updated: Instant,
+}
+
+/// The limits of one [`BandwidthLimit`]: a token bucket for each direction,
+/// refilled at the rate in force and holding at most one second's worth.
+pub struct Limiter {
+  upload: Option<u64>,
+  download: Option<u64>,
+  windows: Vec<Window>,
+  allowances: [Mutex<Allowance>; 2],
+}
+
+impl Limiter {
+  pub fn new(limit: &BandwidthLimit) -> Result<Limiter, String> {
+    let parse = |s: &str| NaiveTime::parse_from_str(s, "%H:%M")
+      .map_err(|e| format!("Invalid bandwidth schedule time {:?} (expected HH:MM): {}", s, e));
+    let windows = limit.schedule.iter()
+      .map(|w| Ok(Window { start: parse(&w.start)?, end: parse(&w.end)?, upload: w.upload, download: w.download }))
+      .collect::<Result<Vec<_>, String>>()?;
+    let allowance = || Mutex::new(Allowance { available: f64::INFINITY, updated: Instant::now() });
+    Ok(Limiter { upload: limit.upload, download: limit.download, windows, allowances: [allowance(), allowance()] })
+  }
+
+  /// The rate in force for `direction` at local time `time`, in bytes per
+  /// second, or `None` if it is unlimited.
+  pub fn rate(&self, direction: Direction, time: NaiveTime) -> Option<u64> {
+    let (upload, download) = match self.windows.iter().find(|w| w.contains(time)) {
+      Some(window) => (window.upload, window.download),
+      None => (self.upload, self.download),
+    };
+    match direction {
+      Direction::Upload => upload,
+      Direction::Download => download,
+    }.filter(|rate| *rate > 0)
+  }
+
+  /// Takes `bytes` from the allowance and returns how long the caller has to
+  /// wait before sending them.
+  fn reserve(&self, direction: Direction, bytes: usize) -> Duration {
+    self.reserve_at(direction, bytes, Local::now().time(), Instant::now())
+  }
+
+  /// [`reserve`](Limiter::reserve) at local time `time` and instant `now`.
+  pub fn reserve_at(&self, direction: Direction, bytes: usize, time: NaiveTime, now: Instant) -> Duration {
+    let Some(rate) = self.rate(direction, time) else {
+      return Duration::ZERO;
+    };
+    let rate = rate as f64;
+    let mut allowance = self.allowances[direction as usize].lock().unwrap();
+    let refill = now.duration_since(allowance.updated).as_secs_f64() * rate;
+    allowance.available = (allowance.available + refill).min(rate) - bytes as f64;
+    allowance.updated = now;
+    if allowance.available < 0.0 {
+      Duration::from_secs_f64(-allowance.available / rate)
+    } else {
+      Duration::ZERO
+    }
+  }
+}
+
+fn global_limiter() -> &'static OnceLock<Arc<Limiter>> {
+  static GLOBAL: OnceLock<Arc<Limiter>> = OnceLock::new();
+  &GLOBAL
+}
+
+/// Sets the `[bandwidth]` cap shared by every store.  Only the first call
+/// has any effect; it must happen before stores are initialised.
+pub fn set_global_limit(limit: &BandwidthLimit) -> Result<(), String> {
+  let _ = global_limiter().set(Arc::new(Limiter::new(limit)?));
+  Ok(())
+}
+
+/// Rate limiting for one store's transfers: the store's own limits together
+/// with the global cap.  The default places no limit.
+#[derive(Clone, Default)]
+pub struct Throttle {
+  limiters: Vec<Arc<Limiter>>,
+}
+
+impl Throttle {
+  pub fn new(limit: Option<&BandwidthLimit>) -> Result<Throttle, String> {
+    let mut limiters = Vec::new();
+    if let Some(limit) = limit {
+      limiters.push(Arc::new(Limiter::new(limit)?));
+    }
+    if let Some(global) = global_limiter().get() {
+      limiters.push(Arc::clone(global));
+    }
+    Ok(Throttle { limiters })
+  }
+
+  fn reserve(&self, direction: Direction, bytes: usize) -> Duration {
+    self.limiters.iter()
+      .map(|limiter| limiter.reserve(direction, bytes))
+      .max()
+      .unwrap_or(Duration::ZERO)
+  }
+
+  /// Waits until `bytes` may be transferred.
+  pub async fn consume(&self, direction: Direction, bytes: usize) {
+    let wait = self.reserve(direction, bytes);
+    if !wait.is_zero() {
+      tokio::time::sleep(wait).await;
+    }
+  }
+
+  /// Like [`consume`](Throttle::consume), for transfers that run on a
+  /// blocking thread.
+  pub fn consume_blocking(&self, direction: Direction, bytes: usize) {
+    let wait = self.reserve(direction, bytes);
+    if !wait.is_zero() {
+      std::thread::sleep(wait);
+    }
+  }
+
+  /// Delays each chunk of `stream` until it may be transferred.
+  pub fn stream<S, B, E>(&self, direction: Direction, stream: S) -> BoxStream<'static, Result<B, E>>
+  where
+    S: Stream<Item = Result<B, E>> + Send + 'static,
+    B: AsRef<[u8]> + Send + 'static,
+    E: Send + 'static,
+  {
+    if self.limiters.is_empty() {
+      return stream.boxed();
+    }
+    let throttle = self.clone();
+    stream.then(move |chunk| {
+      let throttle = throttle.clone();
+      async move {
+        if let Ok(bytes) = &chunk {
+          throttle.consume(direction, bytes.as_ref().len()).await;
+        }
+        chunk
+      }
+    }).boxed()
+  }
+}
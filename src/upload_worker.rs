@@ -1,65 +1,231 @@
-use std::path::PathBuf;
+use std::path::Path;
 use std::fs;
 use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
 
+use bytes::Bytes;
 use log::error;
 use log::trace;
 use sequoia_openpgp::Cert;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 
 use crate::{datastore, encryption};
+use crate::compression::Compression;
 use datastore::DataStore;
-use crate::bucket::Bucket;
+use crate::bucket::{Bucket, Transfer, UploadSlot};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct UploadRequest {
     pub filename: std::path::PathBuf,
     pub data_hash: String,
+    /// MD5 of the encrypted file, set once it has been encrypted.
+    pub encrypted_md5: Option<String>,
+    /// For a chunk, its offset and length within `filename`.
+    pub range: Option<(u64, u64)>,
+    /// Plaintext and ciphertext sizes, set once it has been encrypted.
+    pub sizes: Option<(u64, u64)>,
 }
 
 pub struct UploadReport {
     pub filename: std::path::PathBuf,
     pub data_hash: String,
+    pub encrypted_md5: Option<String>,
     pub store_ids: Vec<i32>,
+    /// Plaintext and ciphertext sizes, if known.
+    pub sizes: Option<(u64, u64)>,
 }
 
+/// Ciphertext is sent to the uploads of a streamed object in pieces of this
+/// size.
+const STREAM_PIECE_SIZE: usize = 256 * 1024;
+
+/// Pieces buffered for each upload of a streamed object before encryption
+/// waits for the slowest store.
+const STREAM_QUEUE_LENGTH: usize = 8;
+
+/// Bytes buffered for each store a streamed object is uploaded to.
+pub const STREAM_BUFFER_SIZE: u64 = (STREAM_PIECE_SIZE * (STREAM_QUEUE_LENGTH + 1)) as u64;
+
+/// Uploads the encrypted file of `request` to all of `buckets` at once, each
+/// as soon as one of its store's upload slots is free.
 pub async fn upload(request: UploadRequest, buckets: &Vec<&(DataStore, Bucket)>, mp: &MultiProgress) -> UploadReport {
     trace!("{:?}\n", request.data_hash);
-    let mut success_ids: Vec<i32> = Vec::new();
     let style =
         ProgressStyle::with_template("{prefix:.bold.dim} {spinner:.green} [{elapsed_precise}] {msg} [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}")
             .unwrap()
             .progress_chars("#>-");
-    for (store, bucket) in buckets.iter() {
-        trace!("Uploading {} to store {}", request.data_hash, store.id);
-        let encrypted_file = fs::File::open(&request.filename).unwrap();
-        let key = format!("{}{}", store.data_prefix, request.data_hash);
+    let uploads = buckets.iter().map(|(store, bucket)| {
+        let request = &request;
+        let style = style.clone();
+        async move {
+            trace!("Uploading {} to store {}", request.data_hash, store.id);
+            let encrypted_file = fs::File::open(&request.filename).unwrap();
+            let key = format!("{}{}", store.data_prefix, request.data_hash);
 
-        let pb = mp.add(ProgressBar::new(encrypted_file.metadata().unwrap().len()));
-        pb.set_style(style.clone());
-        pb.set_message(format!("{}", &request.data_hash[..16]));
-        pb.set_prefix("[Upload] ");
-        // Clone the handle so the callback can own one copy while we retain
-        // another for finish_and_clear() after the upload.
-        let pb_callback = pb.clone();
-        let callback = move |bytes: usize| {
-            pb_callback.inc(u64::try_from(bytes).unwrap_or(0));
-        };
-        match bucket.upload_with_progress(&key, encrypted_file, callback).await {
-            Ok(_) => {
-                pb.finish_and_clear();
-                success_ids.push(store.id);
-            },
-            Err(_) => {
-                pb.abandon_with_message("upload failed");
-                error!("Failed to upload {:?} to {:?}\n", request.data_hash, store.id)
+            let pb = mp.add(ProgressBar::new(encrypted_file.metadata().unwrap().len()));
+            pb.set_style(style);
+            pb.set_message(request.data_hash[..16].to_string());
+            pb.set_prefix("[Upload] ");
+            // Clone the handle so the callback can own one copy while we retain
+            // another for finish_and_clear() after the upload.
+            let pb_callback = pb.clone();
+            let short_hash = request.data_hash[..16].to_string();
+            let callback = move |event: Transfer| match event {
+                Transfer::Bytes(bytes) => pb_callback.inc(u64::try_from(bytes).unwrap_or(0)),
+                Transfer::Retry { attempt, max_attempts, .. } => {
+                    pb_callback.set_position(0);
+                    pb_callback.set_message(format!("{} (retry {}/{})", short_hash, attempt + 1, max_attempts));
+                }
+            };
+            match bucket.upload_with_progress(&key, encrypted_file, request.encrypted_md5.as_deref(), callback).await {
+                Ok(_) => {
+                    pb.finish_and_clear();
+                    Some(store.id)
+                },
+                Err(e) => {
+                    pb.abandon_with_message("upload failed");
+                    error!("Failed to upload {:?} to {:?}: {}\n", request.data_hash, store.id, e);
+                    None
+                }
             }
         }
+    });
+    let success_ids: Vec<i32> = futures::future::join_all(uploads).await.into_iter().flatten().collect();
+    UploadReport { filename: request.filename, data_hash: request.data_hash, encrypted_md5: request.encrypted_md5, store_ids: success_ids, sizes: request.sizes }
+}
+
+/// Sends everything written to it to each upload of a streamed object.  An
+/// upload that has failed stops receiving; once all have, writes fail.
+struct FanOut {
+    senders: Vec<Option<mpsc::Sender<io::Result<Bytes>>>>,
+}
+
+impl FanOut {
+    /// Ends every stream with an error, so that no store keeps a truncated
+    /// object.
+    fn fail(self, message: String) {
+        for sender in self.senders.into_iter().flatten() {
+            let _ = sender.blocking_send(Err(io::Error::other(message.clone())));
+        }
+    }
+}
+
+impl Write for FanOut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes = Bytes::copy_from_slice(buf);
+        for sender in self.senders.iter_mut() {
+            if sender.as_ref().is_some_and(|s| s.blocking_send(Ok(bytes.clone())).is_err()) {
+                *sender = None;
+            }
+        }
+        if self.senders.iter().all(Option::is_none) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "every upload of the stream failed"));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
-    UploadReport { filename: request.filename, data_hash: request.data_hash, store_ids: success_ids }
 }
 
-pub async fn encryption_work(data_cache: &PathBuf, request: UploadRequest, key: &Cert, mp: &MultiProgress) -> UploadRequest {
+/// Encrypts `request` on a blocking thread of its own and uploads the
+/// ciphertext to all of `buckets` at once as it is produced, without writing
+/// it to disk.  The report lists the stores that received the whole object;
+/// the others have to be sent it from a file.
+///
+/// An upload slot is taken on every store before encryption starts, in order
+/// of store id: an encryptor holding one store's slot while waiting for
+/// another's would stall its own uploads, and two of them could wait on each
+/// other for good.
+pub async fn stream_upload(request: UploadRequest, key: &Cert, compression: Compression, buckets: &Vec<&(DataStore, Bucket)>, mp: &MultiProgress) -> UploadReport {
+    trace!("Streaming {:?} to {} store(s)\n", request.data_hash, buckets.len());
+    let style =
+        ProgressStyle::with_template("{prefix:.bold.dim} {spinner:.green} [{elapsed_precise}] {msg} {bytes} ({bytes_per_sec})")
+            .unwrap();
+    let (senders, receivers): (Vec<_>, Vec<_>) = buckets.iter()
+        .map(|_| {
+            let (sender, receiver) = mpsc::channel(STREAM_QUEUE_LENGTH);
+            (Some(sender), receiver)
+        })
+        .unzip();
+
+    let mut slots: Vec<Option<UploadSlot>> = buckets.iter().map(|_| None).collect();
+    let mut order: Vec<usize> = (0..buckets.len()).collect();
+    order.sort_by_key(|&i| buckets[i].0.id);
+    for i in order {
+        slots[i] = Some(buckets[i].1.upload_slot().await);
+    }
+
+    let key = key.clone();
+    let source_request = request.clone();
+    // Not on rayon: the encryptor blocks whenever a store falls behind, and
+    // enough of them waiting would starve hashing and encryption to files.
+    let encryptor = tokio::task::spawn_blocking(move || {
+        let encrypted = (|| {
+            let mut source = fs::File::open(&source_request.filename)?;
+            let mut sink = BufWriter::with_capacity(STREAM_PIECE_SIZE, FanOut { senders });
+            let encrypted = match source_request.range {
+                Some((offset, length)) => {
+                    source.seek(SeekFrom::Start(offset))?;
+                    encryption::encrypt_to_writer(&mut source.take(length), &mut sink, &key, None, compression)
+                }
+                None => encryption::encrypt_to_writer(&mut source, &mut sink, &key, None, compression),
+            };
+            let flushed = sink.flush();
+            let (fan_out, _) = sink.into_parts();
+            match encrypted.and_then(|e| flushed.map(|()| e).map_err(Into::into)) {
+                Ok(encrypted) => Ok(encrypted),
+                Err(e) => {
+                    fan_out.fail(e.to_string());
+                    Err(e)
+                }
+            }
+        })();
+        encrypted.map_err(|e: anyhow::Error| e.to_string())
+    });
+
+    let uploads = buckets.iter().zip(receivers).zip(slots).map(|(((store, bucket), receiver), slot)| {
+        let key = format!("{}{}", store.data_prefix, request.data_hash);
+        let pb = mp.add(ProgressBar::new_spinner());
+        pb.set_style(style.clone());
+        pb.set_message(request.data_hash[..16].to_string());
+        pb.set_prefix("[Stream] ");
+        let pb_callback = pb.clone();
+        async move {
+            let result = bucket.upload_stream(&key, Box::pin(ReceiverStream::new(receiver)), slot.expect("a slot is taken for every store"), move |event| {
+                if let Transfer::Bytes(bytes) = event {
+                    pb_callback.inc(u64::try_from(bytes).unwrap_or(0));
+                }
+            }).await;
+            pb.finish_and_clear();
+            (store.id, result)
+        }
+    });
+    let results = futures::future::join_all(uploads).await;
+
+    let (encrypted_md5, sizes, store_ids) = match encryptor.await.expect("Panic in the streaming encryptor") {
+        Ok(encrypted) => {
+            let store_ids = results.into_iter().filter_map(|(store_id, result)| match result {
+                Ok(()) => Some(store_id),
+                Err(e) => {
+                    error!("{}; retrying from a temporary file", e);
+                    None
+                }
+            }).collect();
+            (Some(encrypted.md5), Some((encrypted.plaintext_size, encrypted.encrypted_size)), store_ids)
+        }
+        Err(e) => {
+            error!("Failed to encrypt {:?} for streaming: {}", request.filename, e);
+            (None, None, Vec::new())
+        }
+    };
+    UploadReport { filename: request.filename, data_hash: request.data_hash, encrypted_md5, store_ids, sizes }
+}
+
+pub async fn encryption_work(data_cache: &Path, request: UploadRequest, key: &Cert, compression: Compression, mp: &MultiProgress) -> UploadRequest {
     let destination_filename = data_cache.join(&request.data_hash);
     trace!("Processing as rayon {:?}\n", &request.filename);
     let (send, recv) = tokio::sync::oneshot::channel();
@@ -73,16 +239,28 @@ pub async fn encryption_work(data_cache: &PathBuf, request: UploadRequest, key:
     rayon::spawn(move || {
         let pb = mp.add(ProgressBar::new_spinner());
         pb.set_style(spinner_style.clone());
-        pb.set_prefix(format!("[Encrypt]"));
+        pb.set_prefix("[Encrypt]");
         pb.inc(1);
-        pb.set_message(format!("{}", filename));
+        pb.set_message(filename);
 
         let mut source = fs::File::open(request.filename).unwrap();
         trace!("Creating {:?}\n", destination_filename);
         let mut dest = File::create(&destination_filename).unwrap();
-        encryption::encrypt_file(&mut source, &mut dest, &key, None).unwrap();
+        let encrypted = match request.range {
+            Some((offset, length)) => {
+                source.seek(SeekFrom::Start(offset)).unwrap();
+                encryption::encrypt_file(&mut source.take(length), &mut dest, &key, None, compression).unwrap()
+            }
+            None => encryption::encrypt_file(&mut source, &mut dest, &key, None, compression).unwrap(),
+        };
         pb.finish_and_clear();
-        let _ = send.send(UploadRequest { filename: destination_filename, data_hash: request.data_hash });
+        let _ = send.send(UploadRequest {
+            filename: destination_filename,
+            data_hash: request.data_hash,
+            encrypted_md5: Some(encrypted.md5),
+            range: None,
+            sizes: Some((encrypted.plaintext_size, encrypted.encrypted_size)),
+        });
     });
             
     recv.await.expect("Panic in rayon::spawn")
@@ -8,4 +8,41 @@ pub fn humanise_bytes(b: u64) -> String {
   } else {
     format!("{} bytes", b)
   }
-}
\ No newline at end of file
+}
+
+/// Percent-encodes `s` for use in a URL path or query: every byte except the
+/// RFC 3986 unreserved characters is encoded, and `/` is kept unless
+/// `encode_slash`.  This is also the encoding AWS SigV4 signs over.
+pub fn uri_encode(s: &str, encode_slash: bool) -> String {
+  let mut out = String::with_capacity(s.len());
+  for b in s.bytes() {
+    match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+      b'/' if !encode_slash => out.push('/'),
+      _ => out.push_str(&format!("%{:02X}", b)),
+    }
+  }
+  out
+}
+
+/// Reverses [`uri_encode`] (and any other percent-encoding).  Invalid escapes
+/// are passed through unchanged.
+pub fn uri_decode(s: &str) -> String {
+  let bytes = s.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'%' && i + 2 < bytes.len() {
+      let escaped = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+        .and_then(|h| u8::from_str_radix(h, 16).ok());
+      if let Some(b) = escaped {
+        out.push(b);
+        i += 3;
+        continue;
+      }
+    }
+    out.push(bytes[i]);
+    i += 1;
+  }
+  String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,376 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use futures::stream::StreamExt;
+use log::{error, trace};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use reqwest::{Method, StatusCode, Url};
+use tokio_util::io::StreamReader;
+use crate::bucket::ObjectEntry;
+use crate::throttle::{Direction, Throttle};
+use crate::storage::{ProgressCallback, StorageBackend, StoreError};
+use crate::utils::{uri_decode, uri_encode};
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength/>
+    <d:getlastmodified/>
+    <d:getetag/>
+  </d:prop>
+</d:propfind>"#;
+
+/// Connection details for a WebDAV store (Nextcloud, ownCloud, Apache
+/// mod_dav, ...).
+#[derive(Deserialize, Clone, Debug)]
+pub struct WebDavConfig {
+  /// URL of the collection used as the container root, e.g.
+  /// `https://cloud.example.com/remote.php/dav/files/alice/backups/`.
+  pub url: String,
+  pub username: Option<String>,
+  pub password: Option<String>,
+}
+
+/// One `<d:response>` element from a PROPFIND multistatus body.
+#[derive(Default)]
+struct DavResource {
+  href: String,
+  is_collection: bool,
+  content_length: i128,
+  last_modified: String,
+  etag: String,
+}
+
+/// The WebDAV-backed implementation.  Use [`Bucket`](crate::bucket::Bucket) in calling code.
+///
+/// Object keys are mapped onto collections beneath `url`, so `data/<hash>`
+/// becomes the resource `<hash>` inside the collection `data/`.  Missing
+/// collections are created with MKCOL on upload.
+pub struct WebDavBucket {
+  client: reqwest::Client,
+  config: WebDavConfig,
+  base_url: Url,
+  /// Collections known to exist, so that MKCOL is only issued once per run.
+  collections: Mutex<HashSet<String>>,
+  throttle: Throttle,
+}
+
+impl WebDavBucket {
+    /// Fails if `url` is not a URL that keys can be joined onto.
+    pub fn new(config: &WebDavConfig) -> Result<WebDavBucket, String> {
+      let mut url = config.url.clone();
+      if !url.ends_with('/') {
+        url.push('/');
+      }
+      let base_url = Url::parse(&url)
+        .map_err(|e| format!("Invalid WebDAV url {:?}: {}", config.url, e))?;
+      if base_url.cannot_be_a_base() {
+        return Err(format!("Invalid WebDAV url {:?}: it cannot hold a collection", config.url));
+      }
+      Ok(WebDavBucket {
+        client: reqwest::Client::new(),
+        config: config.clone(),
+        base_url,
+        collections: Mutex::new(HashSet::new()),
+        throttle: Throttle::default(),
+      })
+    }
+
+    /// Limits the bandwidth used by uploads and downloads.
+    pub fn with_throttle(mut self, throttle: Throttle) -> WebDavBucket {
+        self.throttle = throttle;
+        self
+    }
+
+    fn key_url(&self, key: &str) -> Url {
+      // Strip a leading '/' so that absolute-looking keys still land
+      // safely inside the root collection.
+      self.base_url.join(&uri_encode(key.trim_start_matches('/'), false))
+        .expect("Encoded WebDAV key is always a valid relative URL")
+    }
+
+    fn request(&self, method: Method, url: Url) -> reqwest::RequestBuilder {
+      let builder = self.client.request(method, url);
+      match self.config.username {
+        Some(ref username) => builder.basic_auth(username, self.config.password.as_ref()),
+        None => builder,
+      }
+    }
+
+    /// Creates every collection on the path to `key` that is not already
+    /// known to exist.
+    async fn create_collections(&self, key: &str) -> Result<(), StoreError> {
+      let segments: Vec<&str> = key.trim_start_matches('/').split('/').collect();
+      let mut collection = String::new();
+      for segment in &segments[..segments.len().saturating_sub(1)] {
+        collection.push_str(segment);
+        collection.push('/');
+        if self.collections.lock().unwrap().contains(&collection) {
+          continue;
+        }
+        trace!("MKCOL {}", collection);
+        let response = self.request(Method::from_bytes(b"MKCOL").unwrap(), self.key_url(&collection))
+          .send().await
+          .map_err(|e| StoreError::transient(format!("WebDAV MKCOL error for {}: {:?}", collection, e)))?;
+        let status = response.status();
+        // 405 Method Not Allowed means the collection already exists.
+        if !status.is_success() && status != StatusCode::METHOD_NOT_ALLOWED {
+          return Err(StoreError::http(status, format!("WebDAV MKCOL failed: HTTP {} for {}", status, collection)));
+        }
+        self.collections.lock().unwrap().insert(collection.clone());
+      }
+      Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<reqwest::Response> {
+      let response = self.request(Method::GET, self.key_url(key)).send().await
+        .map_err(|e| io::Error::from(StoreError::transient(format!("WebDAV download error for {}: {:?}", key, e))))?;
+      let status = response.status();
+      if status == StatusCode::NOT_FOUND {
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", key)))
+      } else if !status.is_success() {
+        Err(StoreError::http(status, format!("WebDAV download failed: HTTP {} for {}", status, key)).into())
+      } else {
+        Ok(response)
+      }
+    }
+
+    /// Issues a `Depth: 1` PROPFIND on `collection` (a key prefix ending in
+    /// `/`, or empty for the root).  The collection itself is omitted from the
+    /// result; a missing collection yields an empty listing.
+    async fn propfind(&self, collection: &str) -> io::Result<Vec<DavResource>> {
+      let response = self.request(Method::from_bytes(b"PROPFIND").unwrap(), self.key_url(collection))
+        .header("Depth", "1")
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(PROPFIND_BODY)
+        .send().await
+        .map_err(|e| io::Error::from(StoreError::transient(format!("WebDAV PROPFIND error for {}: {:?}", collection, e))))?;
+      let status = response.status();
+      if status == StatusCode::NOT_FOUND {
+        return Ok(Vec::new());
+      }
+      let body = response.text().await.map_err(io::Error::other)?;
+      if status != StatusCode::MULTI_STATUS {
+        return Err(StoreError::http(status, format!("WebDAV PROPFIND failed: HTTP {} for {}: {}", status, collection, body)).into());
+      }
+      // Servers differ in which characters they percent-encode, so compare
+      // decoded paths.
+      let self_path = uri_decode(self.key_url(collection).path());
+      Ok(parse_multistatus(&body)?
+        .into_iter()
+        .filter(|r| uri_decode(&r.href).trim_end_matches('/') != self_path.trim_end_matches('/'))
+        .collect())
+    }
+
+    /// Walks `collection` recursively, descending only into collections that
+    /// could contain keys beginning with `prefix`.
+    async fn collect_entries(&self, collection: String, prefix: Option<&str>, result: &mut Vec<ObjectEntry>) -> io::Result<()> {
+      let base_path = uri_decode(self.base_url.path());
+      let mut pending = vec![collection];
+      while let Some(collection) = pending.pop() {
+        for resource in self.propfind(&collection).await? {
+          let href = uri_decode(&resource.href);
+          let name = href.strip_prefix(base_path.as_str()).unwrap_or(&href)
+            .trim_end_matches('/')
+            .to_string();
+          if resource.is_collection {
+            let dir_name = format!("{}/", name);
+            if prefix.is_none_or(|p| dir_name.starts_with(p) || p.starts_with(&dir_name)) {
+              pending.push(dir_name);
+            }
+          } else if prefix.is_none_or(|p| name.starts_with(p)) {
+            result.push(ObjectEntry {
+              hash: resource.etag.trim_start_matches("W/").trim_matches('"').to_string(),
+              last_modified: resource.last_modified,
+              bytes: resource.content_length,
+              name,
+              content_type: String::from("application/octet-stream"),
+              slo_etag: None,
+            });
+          }
+        }
+      }
+      Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for WebDavBucket {
+    async fn upload(&self, key: &str, source: File, _md5: Option<&str>, callback: ProgressCallback) -> Result<(), StoreError> {
+      self.create_collections(key).await?;
+      let length = source.metadata()
+        .map_err(|e| format!("Failed to stat upload source for {}: {}", key, e))?
+        .len();
+      let tokio_file = tokio::fs::File::from(source);
+      let stream = self.throttle.stream(Direction::Upload, tokio_util::io::ReaderStream::new(tokio_file)).inspect_ok(move |bytes| {
+        callback(bytes.len())
+      });
+      // Several servers (notably Nextcloud behind some proxies) reject chunked
+      // PUTs, so always send the length.
+      let response = self.request(Method::PUT, self.key_url(key))
+        .header("Content-Length", length)
+        .body(reqwest::Body::wrap_stream(stream))
+        .send().await
+        .map_err(|e| StoreError::transient(format!("WebDAV upload error: {:?}", e)))?;
+      let status = response.status();
+      if status.is_success() {
+        Ok(())
+      } else {
+        let body = response.text().await.unwrap_or_else(|_| "<unreadable body>".to_string());
+        Err(StoreError::http(status, format!("WebDAV upload failed: HTTP {} for {}: {}", status, key, body)))
+      }
+    }
+
+    /// Returns `Ok(true)` if the object exists (2xx), `Ok(false)` if it is
+    /// definitively absent (HTTP 404), or `Err` for any other non-success
+    /// status or request-level failure.
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        match self.request(Method::HEAD, self.key_url(key)).send().await {
+            Ok(response) => {
+                let status = response.status();
+                if status.is_success() {
+                    Ok(true)
+                } else if status == StatusCode::NOT_FOUND {
+                    Ok(false)
+                } else {
+                    let msg = format!("Unexpected status {} checking existence of {}", status, key);
+                    error!("{}", msg);
+                    Err(StoreError::http(status, msg))
+                }
+            }
+            Err(e) => {
+                let msg = format!("Error checking existence of {}: {:?}", key, e);
+                error!("{}", msg);
+                Err(StoreError::transient(msg))
+            }
+        }
+    }
+
+    async fn download(&self, key: &str, dest: File, callback: ProgressCallback) -> io::Result<u64> {
+      let response = self.get(key).await?;
+      let stream = self.throttle.stream(Direction::Download, response.bytes_stream())
+        .map(move |result| {
+            result.inspect(|bytes| {
+                callback(bytes.len());
+            }).map_err(|e| {
+                error!("Encountered error");
+                io::Error::other(e)
+            })
+        });
+      let mut reader = StreamReader::new(stream);
+      let mut tokio_file = tokio::fs::File::from(dest);
+      tokio::io::copy(&mut reader, &mut tokio_file).await
+    }
+
+    /// Returns up to 100 entries whose key begins with `prefix`, with
+    /// keys strictly greater than `marker` (mimicking Swift's pagination).
+    async fn list(&self, prefix: Option<&str>, marker: Option<&str>) -> io::Result<Vec<ObjectEntry>> {
+      // Start from the deepest collection named by the prefix rather than
+      // walking the whole tree.
+      let start = prefix
+        .and_then(|p| p.rfind('/').map(|i| p[..=i].to_string()))
+        .unwrap_or_default();
+      let mut entries: Vec<ObjectEntry> = Vec::new();
+      self.collect_entries(start, prefix, &mut entries).await?;
+      entries.sort_by(|a, b| a.name.cmp(&b.name));
+      if let Some(m) = marker {
+        entries.retain(|e| e.name.as_str() > m);
+      }
+      entries.truncate(100);
+      Ok(entries)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+      let response = self.request(Method::DELETE, self.key_url(key))
+        .send().await
+        .map_err(|e| StoreError::transient(format!("WebDAV delete error for {}: {:?}", key, e)))?;
+      let status = response.status();
+      if status.is_success() || status == StatusCode::NOT_FOUND {
+        Ok(())
+      } else {
+        Err(StoreError::http(status, format!("WebDAV delete failed: HTTP {} for {}", status, key)))
+      }
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<ObjectEntry>, StoreError> {
+      let response = self.request(Method::HEAD, self.key_url(key))
+        .send().await
+        .map_err(|e| StoreError::transient(format!("Error checking {}: {:?}", key, e)))?;
+      let status = response.status();
+      if status.is_success() {
+        Ok(Some(ObjectEntry::from_headers(key, response.headers())))
+      } else if status == StatusCode::NOT_FOUND {
+        Ok(None)
+      } else {
+        Err(StoreError::http(status, format!("Unexpected status {} checking {}", status, key)))
+      }
+    }
+}
+
+/// Parses a `207 Multi-Status` body.  Element names are matched on their
+/// local name because servers disagree on the namespace prefix used for
+/// `DAV:`.  `href`s are reduced to their (still percent-encoded) path.
+fn parse_multistatus(body: &str) -> io::Result<Vec<DavResource>> {
+  let mut reader = Reader::from_str(body);
+  reader.trim_text(true);
+  let mut resources = Vec::new();
+  let mut current: Option<DavResource> = None;
+  let mut element: Vec<u8> = Vec::new();
+  loop {
+    match reader.read_event() {
+      Ok(Event::Start(e)) => {
+        let name = e.local_name().as_ref().to_vec();
+        if name == b"response" {
+          current = Some(DavResource::default());
+        } else if name == b"collection" {
+          if let Some(ref mut r) = current { r.is_collection = true; }
+        }
+        element = name;
+      }
+      Ok(Event::Empty(e)) => {
+        if e.local_name().as_ref() == b"collection" {
+          if let Some(ref mut r) = current { r.is_collection = true; }
+        }
+      }
+      Ok(Event::Text(t)) => {
+        if let Some(ref mut r) = current {
+          let text = t.unescape()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Unparseable PROPFIND response: {}", e)))?
+            .into_owned();
+          match element.as_slice() {
+            b"href" => {
+              r.href = Url::parse(&text).map(|u| u.path().to_string()).unwrap_or(text);
+            }
+            b"getcontentlength" => r.content_length = text.parse().unwrap_or(0),
+            b"getlastmodified" => {
+              r.last_modified = chrono::DateTime::parse_from_rfc2822(&text)
+                .map(|dt| dt.naive_utc().format("%Y-%m-%dT%H:%M:%S%.6f").to_string())
+                .unwrap_or(text);
+            }
+            b"getetag" => r.etag = text,
+            _ => {}
+          }
+        }
+      }
+      Ok(Event::End(e)) => {
+        if e.local_name().as_ref() == b"response" {
+          if let Some(r) = current.take() {
+            resources.push(r);
+          }
+        }
+        element.clear();
+      }
+      Ok(Event::Eof) => break,
+      Ok(_) => {}
+      Err(e) => {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unparseable PROPFIND response: {}", e)));
+      }
+    }
+  }
+  Ok(resources)
+}
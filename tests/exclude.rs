@@ -0,0 +1,54 @@
+//! Checks that `one_file_system` prunes mount points from the walk unless
+//! they are listed in `include_mounts`, using `/proc` below `/`, which is a
+//! separate filesystem on any Linux system.  The integration script checks
+//! the same with tmpfs mounts inside a source directory.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use backup_tool::config::Source;
+use backup_tool::exclude::ExcludeFilter;
+use walkdir::WalkDir;
+
+/// The top-level entries of `/` that the walk keeps.
+fn walk_root(one_file_system: bool, include_mounts: Vec<PathBuf>) -> Vec<PathBuf> {
+    let source = Source { root: String::new(), path: PathBuf::from("/"), one_file_system, include_mounts };
+    let mut filter = ExcludeFilter::new(&source, &[], &[]).unwrap();
+    WalkDir::new("/").max_depth(1).into_iter()
+        .filter_entry(move |entry| filter.keep(entry))
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .collect()
+}
+
+/// Whether `/proc` is mounted on a filesystem of its own.
+fn proc_is_mounted() -> bool {
+    match (std::fs::metadata("/"), std::fs::metadata("/proc")) {
+        (Ok(root), Ok(proc)) => root.dev() != proc.dev(),
+        _ => false,
+    }
+}
+
+#[test]
+fn mount_points_are_skipped() {
+    if !proc_is_mounted() {
+        eprintln!("/proc is not a separate filesystem here; skipping");
+        return;
+    }
+    let kept = walk_root(true, Vec::new());
+    assert!(kept.iter().any(|p| p == Path::new("/")));
+    assert!(!kept.iter().any(|p| p == Path::new("/proc")), "/proc should be skipped: {:?}", kept);
+
+    let kept = walk_root(false, Vec::new());
+    assert!(kept.iter().any(|p| p == Path::new("/proc")), "without one_file_system /proc is walked");
+}
+
+#[test]
+fn include_mounts_are_walked() {
+    if !proc_is_mounted() {
+        eprintln!("/proc is not a separate filesystem here; skipping");
+        return;
+    }
+    let kept = walk_root(true, vec![PathBuf::from("/proc")]);
+    assert!(kept.iter().any(|p| p == Path::new("/proc")), "/proc is in include_mounts: {:?}", kept);
+}
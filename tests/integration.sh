@@ -1,7 +1,8 @@
 #!/usr/bin/env bash
 # Integration test for backup-tool
 #
-# Requires: docker, sq, cargo, rsync, curl
+# Requires: docker, sq, cargo, rsync, curl, ssh-keygen, ssh-keyscan
+#           (and root or passwordless sudo for the one_file_system check)
 #
 # What it does:
 #   1. Builds the binary
@@ -9,10 +10,21 @@
 #   3. Creates a PGP keypair for encryption
 #   4. Starts jeantil/openstack-keystone-swift (Keystone v3 + Swift in one container)
 #   5. Registers the Swift endpoint in Keystone
-#   6. Creates Swift containers for data and metadata
+#   6. Authenticates to Swift (the data container is created by backup-tool)
+#   6b. Starts MinIO and creates an S3 bucket
+#   6c. Starts an SFTP server with a generated client key
+#   6d. Starts a WebDAV server
 #   7. Runs backup  (credentials via OS_* env vars; no inline cloud config needed)
+#      and checks the Swift container was created
 #   8. Runs validate
-#   9. Runs restore
+#   9. Runs restore from each store, and from store 2 falling back to store 1,
+#      backs up to the SFTP store again without a cache so that its objects
+#      are replaced, and once more while the SFTP server restarts, then
+#      replicates store 1 to store 2 to repair it and runs gc on store 2;
+#      backs up two sources under named roots, with chunking, packing,
+#      compression and streamed uploads, and restores them; backs up a
+#      source with tmpfs mounts using one_file_system and include_mounts;
+#      streams a backup to two stores with upload limits
 #  10. Verifies content, symlinks, and mtimes match the source
 #  11. Cleans up
 
@@ -37,6 +49,24 @@ OS_PROJECT_DOMAIN_NAME="Default"
 
 DATA_CONTAINER="backup-data"
 
+MINIO_IMAGE="minio/minio:latest"
+MINIO_CONTAINER_NAME="backup-tool-test-minio-$$"
+MINIO_HOST_PORT=19000
+MINIO_USER="minioadmin"
+MINIO_PASSWORD="minioadmin"
+S3_BUCKET="backup-s3"
+
+SFTP_IMAGE="atmoz/sftp:alpine"
+SFTP_CONTAINER_NAME="backup-tool-test-sftp-$$"
+SFTP_HOST_PORT=12222
+SFTP_USER="backup"
+
+WEBDAV_IMAGE="bytemark/webdav"
+WEBDAV_CONTAINER_NAME="backup-tool-test-webdav-$$"
+WEBDAV_HOST_PORT=18081
+WEBDAV_USER="backup"
+WEBDAV_PASSWORD="webdav-password"
+
 # Export so backup-tool's osauth::Session::from_env() picks them up
 export OS_AUTH_URL OS_USERNAME OS_PASSWORD OS_PROJECT_NAME \
        OS_USER_DOMAIN_NAME OS_PROJECT_DOMAIN_NAME
@@ -62,6 +92,9 @@ WORK_DIR="$(mktemp -d)"
 SOURCE_DIR="${WORK_DIR}/source"
 RESTORE_DIR="${WORK_DIR}/restore"
 RESTORE_LOCAL_DIR="${WORK_DIR}/restore_local"
+RESTORE_S3_DIR="${WORK_DIR}/restore_s3"
+RESTORE_SFTP_DIR="${WORK_DIR}/restore_sftp"
+RESTORE_WEBDAV_DIR="${WORK_DIR}/restore_webdav"
 CONFIG_DIR="${WORK_DIR}/config"
 BACKUP_DESTINATION="${WORK_DIR}/backup"
 mkdir -p "${SOURCE_DIR}" "${CONFIG_DIR}"
@@ -73,15 +106,27 @@ cleanup() {
     info "Cleaning up..."
     docker stop "${CONTAINER_NAME}" 2>/dev/null || true
     docker rm   "${CONTAINER_NAME}" 2>/dev/null || true
+    docker stop "${MINIO_CONTAINER_NAME}" 2>/dev/null || true
+    docker rm   "${MINIO_CONTAINER_NAME}" 2>/dev/null || true
+    docker stop "${SFTP_CONTAINER_NAME}" 2>/dev/null || true
+    docker rm   "${SFTP_CONTAINER_NAME}" 2>/dev/null || true
+    docker stop "${WEBDAV_CONTAINER_NAME}" 2>/dev/null || true
+    docker rm   "${WEBDAV_CONTAINER_NAME}" 2>/dev/null || true
+    for mount in "${MOUNTED[@]+"${MOUNTED[@]}"}"; do
+        ${AS_ROOT} umount "${mount}" 2>/dev/null || true
+    done
     rm -rf "${WORK_DIR}"
     info "Done."
 }
+# tmpfs mounts made for the one_file_system check, unmounted on exit.
+MOUNTED=()
+if [[ "${EUID}" -eq 0 ]]; then AS_ROOT=""; else AS_ROOT="sudo -n"; fi
 trap cleanup EXIT
 
 ### Step 0: Prerequisites ####################################################
 
 info "Checking prerequisites..."
-for cmd in docker sq cargo rsync curl; do
+for cmd in docker sq cargo rsync curl ssh-keygen ssh-keyscan; do
     command -v "${cmd}" >/dev/null 2>&1 || fail "Required command not found: ${cmd}"
 done
 
@@ -112,6 +157,15 @@ touch -t "202106151200" "${SOURCE_DIR}/docs/readme.txt"
 ln -s "../small.txt" "${SOURCE_DIR}/docs/link_to_small.txt"
 ln -s "../docs"      "${SOURCE_DIR}/media/link_to_docs"
 
+# Excluded from the backup: by pattern, by .backupignore and by CACHEDIR.TAG.
+echo "scratch"        > "${SOURCE_DIR}/docs/notes.tmp"
+mkdir -p "${SOURCE_DIR}/docs/scratch" "${SOURCE_DIR}/media/thumbs"
+echo "draft"          > "${SOURCE_DIR}/docs/scratch/draft.txt"
+echo "scratch/"       > "${SOURCE_DIR}/docs/.backupignore"
+echo "thumbnail"      > "${SOURCE_DIR}/media/thumbs/photo.png"
+printf 'Signature: 8a477f597d28d172789f06886806bc55\n' > "${SOURCE_DIR}/media/thumbs/CACHEDIR.TAG"
+RSYNC_EXCLUDES=(--exclude='*.tmp' --exclude=/docs/scratch/ --exclude=/media/thumbs/)
+
 pass "Test data generated ($(find "${SOURCE_DIR}" | wc -l | tr -d ' ') entries)"
 
 ### Step 3: PGP keypair ######################################################
@@ -173,7 +227,7 @@ docker exec "${CONTAINER_NAME}" \
 sleep 2
 pass "Swift endpoint registered"
 
-### Step 6: Wait for Swift and create containers ##############################
+### Step 6: Wait for Swift and authenticate ###################################
 
 info "Waiting for Swift on http://127.0.0.1:${SWIFT_HOST_PORT}..."
 SWIFT_READY=0
@@ -210,14 +264,86 @@ fi
 [[ -n "${SWIFT_TOKEN}" ]]       || fail "Failed to obtain Keystone token"
 [[ -n "${SWIFT_ACCOUNT_URL}" ]] || fail "Failed to discover Swift account URL from catalog"
 
-for CONT in "${DATA_CONTAINER}"; do
-    HTTP_STATUS=$(curl --silent --output /dev/null --write-out "%{http_code}" \
-        -X PUT "${SWIFT_ACCOUNT_URL}/${CONT}" \
-        -H "X-Auth-Token: ${SWIFT_TOKEN}") || true
-    [[ "${HTTP_STATUS}" =~ ^2 ]] || fail "Failed to create Swift container '${CONT}' (HTTP ${HTTP_STATUS})"
-    info "  Container '${CONT}' created"
+# The data container is not created here: store 1 sets create_container = true
+# so that backup-tool creates it, which is checked after the backup.
+pass "Swift authenticated"
+
+### Step 6b: Start MinIO and create the S3 bucket ############################
+
+info "Starting ${MINIO_IMAGE}..."
+docker run --detach \
+    --name "${MINIO_CONTAINER_NAME}" \
+    -p "${MINIO_HOST_PORT}:9000" \
+    -e "MINIO_ROOT_USER=${MINIO_USER}" \
+    -e "MINIO_ROOT_PASSWORD=${MINIO_PASSWORD}" \
+    "${MINIO_IMAGE}" server /data >/dev/null
+
+info "Waiting for MinIO on http://127.0.0.1:${MINIO_HOST_PORT} (up to 60 s)..."
+MINIO_READY=0
+for i in $(seq 1 30); do
+    if curl --silent --fail --max-time 2 "http://127.0.0.1:${MINIO_HOST_PORT}/minio/health/live" >/dev/null 2>&1; then
+        MINIO_READY=1; break
+    fi
+    sleep 2
+done
+[[ "${MINIO_READY}" -eq 1 ]] || fail "MinIO did not become ready in time"
+
+docker exec "${MINIO_CONTAINER_NAME}" \
+    mc alias set local "http://127.0.0.1:9000" "${MINIO_USER}" "${MINIO_PASSWORD}" >/dev/null
+docker exec "${MINIO_CONTAINER_NAME}" mc mb "local/${S3_BUCKET}" >/dev/null \
+    || fail "Failed to create S3 bucket '${S3_BUCKET}'"
+pass "MinIO bucket created"
+
+### Step 6c: Start the SFTP server ###########################################
+
+info "Starting ${SFTP_IMAGE}..."
+SFTP_KEY_FILE="${CONFIG_DIR}/sftp_key"
+ssh-keygen -q -t ed25519 -N "" -f "${SFTP_KEY_FILE}"
+# atmoz/sftp chroots the user into its home directory and creates "upload"
+# inside it, so the store root is /upload from the client's point of view.
+docker run --detach \
+    --name "${SFTP_CONTAINER_NAME}" \
+    -p "${SFTP_HOST_PORT}:22" \
+    -v "${SFTP_KEY_FILE}.pub:/home/${SFTP_USER}/.ssh/keys/id.pub:ro" \
+    "${SFTP_IMAGE}" "${SFTP_USER}::1001::upload" >/dev/null
+
+info "Waiting for SFTP on 127.0.0.1:${SFTP_HOST_PORT} (up to 60 s)..."
+SFTP_FINGERPRINT=""
+for i in $(seq 1 30); do
+    SFTP_FINGERPRINT=$(ssh-keyscan -p "${SFTP_HOST_PORT}" -t ed25519 127.0.0.1 2>/dev/null \
+        | ssh-keygen -lf - 2>/dev/null | awk '{print $2}') || true
+    [[ -n "${SFTP_FINGERPRINT}" ]] && break
+    sleep 2
+done
+[[ -n "${SFTP_FINGERPRINT}" ]] || fail "SFTP server did not become ready in time"
+info "SFTP host key: ${SFTP_FINGERPRINT}"
+pass "SFTP server is ready"
+
+### Step 6d: Start the WebDAV server #########################################
+
+info "Starting ${WEBDAV_IMAGE}..."
+docker run --detach \
+    --name "${WEBDAV_CONTAINER_NAME}" \
+    -p "${WEBDAV_HOST_PORT}:80" \
+    -e AUTH_TYPE=Basic \
+    -e "USERNAME=${WEBDAV_USER}" \
+    -e "PASSWORD=${WEBDAV_PASSWORD}" \
+    "${WEBDAV_IMAGE}" >/dev/null
+
+info "Waiting for WebDAV on http://127.0.0.1:${WEBDAV_HOST_PORT} (up to 60 s)..."
+WEBDAV_READY=0
+for i in $(seq 1 30); do
+    if curl --silent --fail --max-time 2 --user "${WEBDAV_USER}:${WEBDAV_PASSWORD}" \
+        -X PROPFIND -H "Depth: 0" "http://127.0.0.1:${WEBDAV_HOST_PORT}/" >/dev/null 2>&1; then
+        WEBDAV_READY=1; break
+    fi
+    sleep 2
 done
-pass "Swift containers created"
+[[ "${WEBDAV_READY}" -eq 1 ]] || fail "WebDAV server did not become ready in time"
+curl --silent --fail --user "${WEBDAV_USER}:${WEBDAV_PASSWORD}" \
+    -X MKCOL "http://127.0.0.1:${WEBDAV_HOST_PORT}/backups/" >/dev/null \
+    || fail "Failed to create WebDAV collection 'backups'"
+pass "WebDAV server is ready"
 
 ### Step 7: Write backup.toml ################################################
 # No [stores.*_cloud_config] — the tool reads OS_* vars via from_env().
@@ -230,28 +356,77 @@ data_cache = "${CONFIG_DIR}/data_cache.db"
 metadata_cache = "${CONFIG_DIR}/meta_cache.db"
 hmac_secret = "${HMAC_SECRET}"
 encrypting_key_file = "${ENCRYPT_KEY_FILE}"
+exclude = ["*.tmp"]
 
 [[stores]]
 id                 = 1
 container          = "${DATA_CONTAINER}"
 data_prefix        = "data/"
 metadata_prefix    = "meta/"
+# Small enough that large.bin is uploaded as a Static Large Object.
+segment_size       = 1048576
+create_container   = true
+[stores.container_metadata]
+Quota-Bytes        = "10737418240"
 
 [[stores]]
 id                 = 2
+type               = "local"
 local_path         = "${BACKUP_DESTINATION}"
 data_prefix        = "data/"
 metadata_prefix    = "meta/"
 
+[[stores]]
+id                 = 3
+data_prefix        = "data/"
+metadata_prefix    = "meta/"
+
+[stores.s3]
+endpoint          = "http://127.0.0.1:${MINIO_HOST_PORT}"
+bucket            = "${S3_BUCKET}"
+access_key_id     = "${MINIO_USER}"
+secret_access_key = "${MINIO_PASSWORD}"
+path_style        = true
+
+[[stores]]
+id                 = 4
+data_prefix        = "data/"
+metadata_prefix    = "meta/"
+
+[stores.sftp]
+host                 = "127.0.0.1"
+port                 = ${SFTP_HOST_PORT}
+username             = "${SFTP_USER}"
+private_key_file     = "${SFTP_KEY_FILE}"
+host_key_fingerprint = "${SFTP_FINGERPRINT}"
+host_key_algorithm   = "ssh-ed25519"
+root                 = "/upload"
+
+[[stores]]
+id                 = 5
+data_prefix        = "data/"
+metadata_prefix    = "meta/"
+
+[stores.webdav]
+url      = "http://127.0.0.1:${WEBDAV_HOST_PORT}/backups/"
+username = "${WEBDAV_USER}"
+password = "${WEBDAV_PASSWORD}"
+
 TOML
 pass "backup.toml written"
 
 ### Step 8: Backup ###########################################################
 
 info "Running backup..."
-"${BINARY}" --config "${CONFIG_DIR}/backup.toml" backup 2>&1 | grep -v "^$" | head -80 || true
+"${BINARY}" --config "${CONFIG_DIR}/backup.toml" backup --tag integration 2>&1 | grep -v "^$" | head -80 || true
 pass "Backup completed"
 
+HTTP_STATUS=$(curl --silent --output /dev/null --write-out "%{http_code}" \
+    -I "${SWIFT_ACCOUNT_URL}/${DATA_CONTAINER}" \
+    -H "X-Auth-Token: ${SWIFT_TOKEN}") || true
+[[ "${HTTP_STATUS}" =~ ^2 ]] || fail "Swift container '${DATA_CONTAINER}' was not created (HTTP ${HTTP_STATUS})"
+pass "Swift container created by create_container"
+
 ### Step 9: List #############################################################
 
 info "Listing backups..."
@@ -267,6 +442,29 @@ info "Running validate..."
     2>&1 | grep -v "^$" | head -20 || true
 pass "Validate completed"
 
+info "Running validate --read-data..."
+"${BINARY}" --config "${CONFIG_DIR}/backup.toml" validate "${BACKUP_NAME}" --read-data \
+    || fail "validate --read-data found problems"
+pass "Validate with data read-back passed"
+
+# validate only reads the cache, so run from a directory without one it must
+# not leave a cache.db behind.
+info "Running validate from a directory without a cache..."
+NO_CACHE_DIR="${WORK_DIR}/no_cache"
+mkdir -p "${NO_CACHE_DIR}"
+ABSOLUTE_BINARY="$(cd "$(dirname "${BINARY}")" && pwd)/$(basename "${BINARY}")"
+(cd "${NO_CACHE_DIR}" && "${ABSOLUTE_BINARY}" --config "${CONFIG_DIR}/backup.toml" validate "${BACKUP_NAME}") \
+    || fail "validate without a cache found problems"
+[[ ! -e "${NO_CACHE_DIR}/cache.db" ]] || fail "validate created cache.db"
+pass "Validate without a cache left no cache.db"
+
+info "Running prune --dry-run, keeping only tagged backups..."
+PRUNE_OUT=$("${BINARY}" --config "${CONFIG_DIR}/backup.toml" prune --dry-run --keep-tag integration --limit 1 2>&1) \
+    || { echo "${PRUNE_OUT}"; fail "prune --dry-run exited with an error"; }
+echo "${PRUNE_OUT}" | grep -q "keep    ${BACKUP_NAME}  (tag integration)" \
+    || { echo "${PRUNE_OUT}"; fail "prune did not keep the tagged backup"; }
+pass "Prune dry run kept the tagged backup"
+
 ### Step 11: Restore #########################################################
 
 info "Running restore into ${RESTORE_DIR} from store 1..."
@@ -279,13 +477,289 @@ info "Running restore into ${RESTORE_LOCAL_DIR} from store 2..."
     2>&1 | grep -v "^$" | head -80 || true
 pass "Restore completed"
 
+info "Running restore into ${RESTORE_S3_DIR} from store 3..."
+"${BINARY}" --config "${CONFIG_DIR}/backup.toml" restore "${BACKUP_NAME}" "${RESTORE_S3_DIR}" --store-id 3 \
+    2>&1 | grep -v "^$" | head -80 || true
+pass "Restore completed"
+
+info "Running restore into ${RESTORE_SFTP_DIR} from store 4..."
+"${BINARY}" --config "${CONFIG_DIR}/backup.toml" restore "${BACKUP_NAME}" "${RESTORE_SFTP_DIR}" --store-id 4 \
+    2>&1 | grep -v "^$" | head -80 || true
+pass "Restore completed"
+
+# Without a cache every object is uploaded again, so each SFTP upload has to
+# replace an object that is already there.
+info "Backing up to the SFTP store again without a cache..."
+SFTP_AGAIN_DIR="${WORK_DIR}/sftp_again"
+RESTORE_SFTP_AGAIN_DIR="${WORK_DIR}/restore_sftp_again"
+mkdir -p "${SFTP_AGAIN_DIR}"
+sed '/^\[\[stores\]\]/,$d' "${CONFIG_DIR}/backup.toml" > "${CONFIG_DIR}/backup-sftp.toml"
+sed -n '/^id *= 4$/,/^root /p' "${CONFIG_DIR}/backup.toml" | sed '1i [[stores]]' >> "${CONFIG_DIR}/backup-sftp.toml"
+SFTP_AGAIN_OUT=$(cd "${SFTP_AGAIN_DIR}" && "${ABSOLUTE_BINARY}" --config "${CONFIG_DIR}/backup-sftp.toml" backup 2>&1) \
+    || { echo "${SFTP_AGAIN_OUT}"; fail "The second backup to the SFTP store failed"; }
+if echo "${SFTP_AGAIN_OUT}" | grep -q "Failed to upload"; then
+    echo "${SFTP_AGAIN_OUT}"
+    fail "Uploading objects that already exist on the SFTP store failed"
+fi
+PARTIALS=$(docker exec "${SFTP_CONTAINER_NAME}" find "/home/${SFTP_USER}/upload" -name '*.partial' | wc -l | tr -d ' ')
+[[ "${PARTIALS}" -eq 0 ]] || fail "${PARTIALS} .partial files were left on the SFTP store"
+SFTP_AGAIN_NAME=$(cd "${SFTP_AGAIN_DIR}" && "${ABSOLUTE_BINARY}" --config "${CONFIG_DIR}/backup-sftp.toml" list 2>/dev/null | tail -1)
+"${BINARY}" --config "${CONFIG_DIR}/backup-sftp.toml" restore "${SFTP_AGAIN_NAME}" "${RESTORE_SFTP_AGAIN_DIR}" \
+    2>&1 | grep -v "^$" | head -20
+RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete "${RSYNC_EXCLUDES[@]}" \
+    "${SOURCE_DIR}/" "${RESTORE_SFTP_AGAIN_DIR}/" 2>&1) || true
+[[ -z "${RSYNC_OUT}" ]] || { echo "${RSYNC_OUT}"; fail "The restore of the second SFTP backup does not match the source"; }
+pass "Objects already on the SFTP store were replaced"
+
+# A throttled backup to the SFTP store while the server restarts: uploads cut
+# off by the restart are retried over a new connection.
+info "Backing up to the SFTP store while the server restarts..."
+SFTP_RESTART_DIR="${WORK_DIR}/sftp_restart"
+RESTORE_SFTP_RESTART_DIR="${WORK_DIR}/restore_sftp_restart"
+mkdir -p "${SFTP_RESTART_DIR}"
+cat "${CONFIG_DIR}/backup-sftp.toml" - > "${CONFIG_DIR}/backup-sftp-restart.toml" << TOML
+
+[stores.bandwidth]
+upload = 1048576
+
+[stores.retry]
+max_attempts  = 8
+base_delay_ms = 1000
+TOML
+(cd "${SFTP_RESTART_DIR}" && "${ABSOLUTE_BINARY}" --config "${CONFIG_DIR}/backup-sftp-restart.toml" backup \
+    > "${SFTP_RESTART_DIR}/backup.log" 2>&1) &
+SFTP_BACKUP_PID=$!
+sleep 2
+docker restart -t 0 "${SFTP_CONTAINER_NAME}" >/dev/null
+wait "${SFTP_BACKUP_PID}" || { cat "${SFTP_RESTART_DIR}/backup.log"; fail "The backup across an SFTP restart failed"; }
+if grep -q "Failed to upload" "${SFTP_RESTART_DIR}/backup.log"; then
+    cat "${SFTP_RESTART_DIR}/backup.log"
+    fail "Uploads to the SFTP store were not retried after the server restarted"
+fi
+SFTP_RESTART_NAME=$(cd "${SFTP_RESTART_DIR}" && "${ABSOLUTE_BINARY}" --config "${CONFIG_DIR}/backup-sftp.toml" list 2>/dev/null | tail -1)
+"${BINARY}" --config "${CONFIG_DIR}/backup-sftp.toml" restore "${SFTP_RESTART_NAME}" "${RESTORE_SFTP_RESTART_DIR}" \
+    2>&1 | grep -v "^$" | head -20
+RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete "${RSYNC_EXCLUDES[@]}" \
+    "${SOURCE_DIR}/" "${RESTORE_SFTP_RESTART_DIR}/" 2>&1) || true
+[[ -z "${RSYNC_OUT}" ]] || { echo "${RSYNC_OUT}"; fail "The restore of the backup across an SFTP restart does not match the source"; }
+pass "Uploads to the SFTP store reconnected after the server restarted"
+
+info "Running restore into ${RESTORE_WEBDAV_DIR} from store 5..."
+"${BINARY}" --config "${CONFIG_DIR}/backup.toml" restore "${BACKUP_NAME}" "${RESTORE_WEBDAV_DIR}" --store-id 5 \
+    2>&1 | grep -v "^$" | head -80 || true
+pass "Restore completed"
+
+# Remove one data object from the local store so that a restore from store 2
+# has to fall back to store 1 for it.
+RESTORE_FAILOVER_DIR="${WORK_DIR}/restore_failover"
+REMOVED_OBJECT=$(find "${BACKUP_DESTINATION}/data" -type f | head -1)
+[[ -n "${REMOVED_OBJECT}" ]] || fail "No data objects found in the local store"
+rm -f "${REMOVED_OBJECT}"
+
+info "Running restore into ${RESTORE_FAILOVER_DIR} from stores 2,1..."
+FAILOVER_OUT=$("${BINARY}" --config "${CONFIG_DIR}/backup.toml" restore "${BACKUP_NAME}" "${RESTORE_FAILOVER_DIR}" --store-id 2,1 2>&1) \
+    || { echo "${FAILOVER_OUT}"; fail "Restore with failover exited with an error"; }
+echo "${FAILOVER_OUT}" | grep -q "restored from store 1" \
+    || { echo "${FAILOVER_OUT}"; fail "Restore did not report falling back to store 1"; }
+pass "Restore with failover completed"
+
+info "Replicating all backups from store 1 to store 2..."
+"${BINARY}" --config "${CONFIG_DIR}/backup.toml" replicate --all --from 1 --to 2 \
+    2>&1 | grep -v "^$" | head -20
+[[ -f "${REMOVED_OBJECT}" ]] || fail "replicate did not restore the missing object to store 2"
+pass "Replicate restored the missing object"
+
+# An unreferenced object older than the grace period is collected; everything
+# the backup references stays.
+ORPHAN_OBJECT="${BACKUP_DESTINATION}/data/orphan-object"
+echo "not referenced by any backup" > "${ORPHAN_OBJECT}"
+touch -d "2 days ago" "${ORPHAN_OBJECT}"
+info "Running gc on store 2..."
+"${BINARY}" --config "${CONFIG_DIR}/backup.toml" gc --limit 2 \
+    2>&1 | grep -v "^$" | head -20
+[[ ! -e "${ORPHAN_OBJECT}" ]] || fail "gc did not delete the unreferenced object"
+[[ -f "${REMOVED_OBJECT}" ]] || fail "gc deleted a referenced object"
+pass "gc deleted only the unreferenced object"
+
+# A backup of two sources, each under a named root, to a store of its own.
+EXTRA_SOURCE_DIR="${WORK_DIR}/extra_source"
+MULTI_STORE_DIR="${WORK_DIR}/multi_store"
+RESTORE_MULTI_DIR="${WORK_DIR}/restore_multi"
+RESTORE_ROOT_DIR="${WORK_DIR}/restore_root"
+mkdir -p "${EXTRA_SOURCE_DIR}/conf" "${MULTI_STORE_DIR}"
+echo "setting = 1" > "${EXTRA_SOURCE_DIR}/conf/app.conf"
+cat > "${CONFIG_DIR}/backup-multi.toml" << TOML
+data_cache = "${CONFIG_DIR}/data_cache.db"
+metadata_cache = "${CONFIG_DIR}/meta_cache.db"
+hmac_secret = "${HMAC_SECRET}"
+encrypting_key_file = "${ENCRYPT_KEY_FILE}"
+exclude = ["*.tmp"]
+stream_uploads = true
+
+# Small chunks so that large.bin and photo.jpg are chunked.
+[chunking]
+min_size = 65536
+avg_size = 131072
+max_size = 204800
+
+[compression]
+algorithm = "zstd"
+level     = 3
+
+# Files of up to 1 KiB, such as app.conf, go into packs.
+[packing]
+max_file_size = 1024
+pack_size     = 65536
+
+# Little concurrency, as on a small machine.
+[concurrency]
+threads       = 2
+files         = 4
+chunks        = 2
+memory_budget = 8388608
+
+[[sources]]
+root = "main"
+path = "${SOURCE_DIR}"
+
+[[sources]]
+root = "extra"
+path = "${EXTRA_SOURCE_DIR}"
+
+[[stores]]
+id                 = 6
+type               = "local"
+local_path         = "${MULTI_STORE_DIR}"
+data_prefix        = "data/"
+metadata_prefix    = "meta/"
+TOML
+
+info "Running a chunked and packed backup of two sources..."
+"${BINARY}" --config "${CONFIG_DIR}/backup-multi.toml" backup 2>&1 | grep -v "^$" | head -20
+MULTI_BACKUP_NAME=$("${BINARY}" --config "${CONFIG_DIR}/backup-multi.toml" list 2>/dev/null | tail -1)
+CHUNK_OBJECTS=$(find "${MULTI_STORE_DIR}/data" -type f | wc -l | tr -d ' ')
+[[ "${CHUNK_OBJECTS}" -gt 20 ]] || fail "Expected large files to be stored as chunks, found ${CHUNK_OBJECTS} data objects"
+"${BINARY}" --config "${CONFIG_DIR}/backup-multi.toml" validate "${MULTI_BACKUP_NAME}" --read-data \
+    || fail "validate --read-data failed for the chunked and packed backup"
+"${BINARY}" --config "${CONFIG_DIR}/backup-multi.toml" restore "${MULTI_BACKUP_NAME}" "${RESTORE_MULTI_DIR}" --parallel 1 \
+    2>&1 | grep -v "^$" | head -20
+"${BINARY}" --config "${CONFIG_DIR}/backup-multi.toml" restore "${MULTI_BACKUP_NAME}" "${RESTORE_ROOT_DIR}" --root extra \
+    2>&1 | grep -v "^$" | head -20
+for pair in "${SOURCE_DIR}:${RESTORE_MULTI_DIR}/main" "${EXTRA_SOURCE_DIR}:${RESTORE_MULTI_DIR}/extra" \
+            "${EXTRA_SOURCE_DIR}:${RESTORE_ROOT_DIR}"; do
+    RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete "${RSYNC_EXCLUDES[@]}" \
+        "${pair%%:*}/" "${pair#*:}/" 2>&1) || true
+    [[ -z "${RSYNC_OUT}" ]] || { echo "${RSYNC_OUT}"; fail "${pair#*:} does not match ${pair%%:*}"; }
+done
+pass "Both roots restored, together and on their own"
+
+# one_file_system: a tmpfs mounted inside the source is skipped, unless it is
+# listed in include_mounts.
+MOUNTS_SOURCE_DIR="${WORK_DIR}/mounts_source"
+MOUNTS_STORE_DIR="${WORK_DIR}/mounts_store"
+RESTORE_MOUNTS_DIR="${WORK_DIR}/restore_mounts"
+mkdir -p "${MOUNTS_SOURCE_DIR}/skipped" "${MOUNTS_SOURCE_DIR}/included" "${MOUNTS_STORE_DIR}"
+echo "on the source filesystem" > "${MOUNTS_SOURCE_DIR}/top.txt"
+if ${AS_ROOT} mount -t tmpfs -o size=1m tmpfs "${MOUNTS_SOURCE_DIR}/skipped" 2>/dev/null \
+    && MOUNTED+=("${MOUNTS_SOURCE_DIR}/skipped") \
+    && ${AS_ROOT} mount -t tmpfs -o size=1m tmpfs "${MOUNTS_SOURCE_DIR}/included"; then
+    MOUNTED+=("${MOUNTS_SOURCE_DIR}/included")
+    ${AS_ROOT} chmod 777 "${MOUNTS_SOURCE_DIR}/skipped" "${MOUNTS_SOURCE_DIR}/included"
+    echo "on a skipped mount" > "${MOUNTS_SOURCE_DIR}/skipped/inner.txt"
+    echo "on an included mount" > "${MOUNTS_SOURCE_DIR}/included/inner.txt"
+    cat > "${CONFIG_DIR}/backup-mounts.toml" << TOML
+source = "${MOUNTS_SOURCE_DIR}"
+one_file_system = true
+include_mounts = ["${MOUNTS_SOURCE_DIR}/included"]
+data_cache = "${CONFIG_DIR}/data_cache.db"
+metadata_cache = "${CONFIG_DIR}/meta_cache.db"
+hmac_secret = "${HMAC_SECRET}"
+encrypting_key_file = "${ENCRYPT_KEY_FILE}"
+
+[[stores]]
+id                 = 7
+type               = "local"
+local_path         = "${MOUNTS_STORE_DIR}"
+data_prefix        = "data/"
+metadata_prefix    = "meta/"
+TOML
+
+    info "Running a one_file_system backup of a source with two mounts..."
+    MOUNTS_OUT=$("${BINARY}" --config "${CONFIG_DIR}/backup-mounts.toml" backup 2>&1) \
+        || { echo "${MOUNTS_OUT}"; fail "one_file_system backup failed"; }
+    echo "${MOUNTS_OUT}" | grep -q "Skipping mount point.*skipped" \
+        || { echo "${MOUNTS_OUT}"; fail "The mount point was not reported as skipped"; }
+    MOUNTS_BACKUP_NAME=$("${BINARY}" --config "${CONFIG_DIR}/backup-mounts.toml" list 2>/dev/null | tail -1)
+    "${BINARY}" --config "${CONFIG_DIR}/backup-mounts.toml" restore "${MOUNTS_BACKUP_NAME}" "${RESTORE_MOUNTS_DIR}" \
+        2>&1 | grep -v "^$" | head -20
+    [[ -f "${RESTORE_MOUNTS_DIR}/top.txt" ]] || fail "A file on the source filesystem was not restored"
+    [[ ! -e "${RESTORE_MOUNTS_DIR}/skipped" ]] || fail "The mount point not in include_mounts was backed up"
+    cmp -s "${MOUNTS_SOURCE_DIR}/included/inner.txt" "${RESTORE_MOUNTS_DIR}/included/inner.txt" \
+        || fail "The mount point in include_mounts was not backed up"
+    pass "one_file_system skipped a mount point and walked into include_mounts"
+else
+    info "Cannot mount tmpfs (needs root or passwordless sudo); skipping the one_file_system check"
+fi
+
+# Streamed uploads to two stores with upload limits, more files in flight
+# than worker threads or upload slots.
+LIMITED_STORE_DIR="${WORK_DIR}/limited_store"
+UNLIMITED_STORE_DIR="${WORK_DIR}/unlimited_store"
+RESTORE_LIMITED_DIR="${WORK_DIR}/restore_limited"
+mkdir -p "${LIMITED_STORE_DIR}" "${UNLIMITED_STORE_DIR}"
+cat > "${CONFIG_DIR}/backup-limits.toml" << TOML
+source = "${SOURCE_DIR}"
+data_cache = "${CONFIG_DIR}/data_cache.db"
+metadata_cache = "${CONFIG_DIR}/meta_cache.db"
+hmac_secret = "${HMAC_SECRET}"
+encrypting_key_file = "${ENCRYPT_KEY_FILE}"
+exclude = ["*.tmp"]
+stream_uploads = true
+
+[concurrency]
+threads = 1
+files   = 8
+
+[[stores]]
+id                 = 8
+type               = "local"
+local_path         = "${LIMITED_STORE_DIR}"
+data_prefix        = "data/"
+metadata_prefix    = "meta/"
+max_uploads        = 1
+
+[[stores]]
+id                 = 9
+type               = "local"
+local_path         = "${UNLIMITED_STORE_DIR}"
+data_prefix        = "data/"
+metadata_prefix    = "meta/"
+TOML
+
+info "Running a streamed backup to two stores with upload limits..."
+LIMITS_OUT=$(timeout 600 "${BINARY}" --config "${CONFIG_DIR}/backup-limits.toml" backup --uploads-per-store 2 2>&1) \
+    || { echo "${LIMITS_OUT}"; fail "Streamed backup with upload limits failed or hung"; }
+LIMITED_OBJECTS=$(echo "${LIMITS_OUT}" | sed -n 's/^Store 8: uploaded \([0-9]*\) objects.*/\1/p')
+UNLIMITED_OBJECTS=$(echo "${LIMITS_OUT}" | sed -n 's/^Store 9: uploaded \([0-9]*\) objects.*/\1/p')
+[[ -n "${LIMITED_OBJECTS}" && "${LIMITED_OBJECTS}" == "${UNLIMITED_OBJECTS}" ]] \
+    || { echo "${LIMITS_OUT}"; fail "Expected the same upload summary for stores 8 and 9"; }
+[[ $(find "${LIMITED_STORE_DIR}/data" -type f | wc -l) -eq $(find "${UNLIMITED_STORE_DIR}/data" -type f | wc -l) ]] \
+    || fail "Stores 8 and 9 hold different numbers of data objects"
+LIMITS_BACKUP_NAME=$("${BINARY}" --config "${CONFIG_DIR}/backup-limits.toml" list 2>/dev/null | tail -1)
+"${BINARY}" --config "${CONFIG_DIR}/backup-limits.toml" restore "${LIMITS_BACKUP_NAME}" "${RESTORE_LIMITED_DIR}" --store-id 8 \
+    2>&1 | grep -v "^$" | head -20
+RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete "${RSYNC_EXCLUDES[@]}" \
+    "${SOURCE_DIR}/" "${RESTORE_LIMITED_DIR}/" 2>&1) || true
+[[ -z "${RSYNC_OUT}" ]] || { echo "${RSYNC_OUT}"; fail "The restore from store 8 does not match the source"; }
+pass "Streamed ${LIMITED_OBJECTS} objects to a store limited to one upload at a time and to another"
+
 ### Step 12: Verify ##########################################################
 
 info "Verifying restored data for store 1..."
 
 # rsync dry-run covers content (checksum), mtimes, symlink targets,
 # missing files, and extra files in the restore directory in one pass.
-RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete \
+RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete "${RSYNC_EXCLUDES[@]}" \
     "${SOURCE_DIR}/" "${RESTORE_DIR}/" 2>&1) || true
 
 if [[ -z "${RSYNC_OUT}" ]]; then
@@ -296,11 +770,16 @@ else
     fail "${ERRORS} verification difference(s) found for store 1 -- see above"
 fi
 
+for excluded in docs/notes.tmp docs/scratch media/thumbs; do
+    [[ ! -e "${RESTORE_DIR}/${excluded}" ]] || fail "Excluded path ${excluded} was backed up"
+done
+pass "Excluded paths were not backed up"
+
 info "Verifying restored data for store 2..."
 
 # rsync dry-run covers content (checksum), mtimes, symlink targets,
 # missing files, and extra files in the restore directory in one pass.
-RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete \
+RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete "${RSYNC_EXCLUDES[@]}" \
     "${SOURCE_DIR}/" "${RESTORE_LOCAL_DIR}/" 2>&1) || true
 
 if [[ -z "${RSYNC_OUT}" ]]; then
@@ -311,6 +790,58 @@ else
     fail "${ERRORS} verification difference(s) found for store 2 -- see above"
 fi
 
+info "Verifying restored data for store 3..."
+
+RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete "${RSYNC_EXCLUDES[@]}" \
+    "${SOURCE_DIR}/" "${RESTORE_S3_DIR}/" 2>&1) || true
+
+if [[ -z "${RSYNC_OUT}" ]]; then
+    pass "All content, symlinks, and modification times match for store 3"
+else
+    echo "${RSYNC_OUT}"
+    ERRORS=$(echo "${RSYNC_OUT}" | wc -l | tr -d ' ')
+    fail "${ERRORS} verification difference(s) found for store 3 -- see above"
+fi
+
+info "Verifying restored data for store 4..."
+
+RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete "${RSYNC_EXCLUDES[@]}" \
+    "${SOURCE_DIR}/" "${RESTORE_SFTP_DIR}/" 2>&1) || true
+
+if [[ -z "${RSYNC_OUT}" ]]; then
+    pass "All content, symlinks, and modification times match for store 4"
+else
+    echo "${RSYNC_OUT}"
+    ERRORS=$(echo "${RSYNC_OUT}" | wc -l | tr -d ' ')
+    fail "${ERRORS} verification difference(s) found for store 4 -- see above"
+fi
+
+info "Verifying restored data for store 5..."
+
+RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete "${RSYNC_EXCLUDES[@]}" \
+    "${SOURCE_DIR}/" "${RESTORE_WEBDAV_DIR}/" 2>&1) || true
+
+if [[ -z "${RSYNC_OUT}" ]]; then
+    pass "All content, symlinks, and modification times match for store 5"
+else
+    echo "${RSYNC_OUT}"
+    ERRORS=$(echo "${RSYNC_OUT}" | wc -l | tr -d ' ')
+    fail "${ERRORS} verification difference(s) found for store 5 -- see above"
+fi
+
+info "Verifying restored data for stores 2,1 (failover)..."
+
+RSYNC_OUT=$(rsync -an --checksum --itemize-changes --delete "${RSYNC_EXCLUDES[@]}" \
+    "${SOURCE_DIR}/" "${RESTORE_FAILOVER_DIR}/" 2>&1) || true
+
+if [[ -z "${RSYNC_OUT}" ]]; then
+    pass "All content, symlinks, and modification times match after failover"
+else
+    echo "${RSYNC_OUT}"
+    ERRORS=$(echo "${RSYNC_OUT}" | wc -l | tr -d ' ')
+    fail "${ERRORS} verification difference(s) found after failover -- see above"
+fi
+
 echo ""
 echo -e "${GREEN}========================================${NC}"
 echo -e "${GREEN}  Integration test PASSED               ${NC}"
@@ -0,0 +1,295 @@
+//! Checks the retry policy's backoff, jitter and classification of errors,
+//! that `RetryPolicy::run` and `Bucket::delete_many` make the expected
+//! attempts, and that an upload to a WebDAV stand-in answering 503 is
+//! retried until it is stored.  Also checks that a bad WebDAV url is
+//! reported rather than panicking.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use backup_tool::bucket::{Bucket, ObjectEntry, Transfer};
+use backup_tool::retry::{RetryPolicy, Retryable};
+use backup_tool::storage::{ProgressCallback, StorageBackend, StoreError};
+use backup_tool::webdav_bucket::{WebDavBucket, WebDavConfig};
+use reqwest::StatusCode;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+fn policy(base_delay_ms: u64, max_delay_ms: u64, jitter: f64) -> RetryPolicy {
+    RetryPolicy { base_delay_ms, max_delay_ms, jitter, ..RetryPolicy::default() }
+}
+
+fn http(status: u16) -> StoreError {
+    StoreError::http(StatusCode::from_u16(status).unwrap(), format!("HTTP {}", status))
+}
+
+#[test]
+fn delay_doubles_up_to_the_cap() {
+    let policy = policy(100, 1000, 0.0);
+    let delays: Vec<u64> = (1..=6).map(|attempt| policy.delay(attempt).as_millis() as u64).collect();
+    assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+    // Far past the cap the exponent must not overflow.
+    assert_eq!(policy.delay(100), Duration::from_millis(1000));
+}
+
+#[test]
+fn jitter_shortens_delays_by_at_most_its_fraction() {
+    let half = policy(1000, 60_000, 0.5);
+    for _ in 0..200 {
+        let delay = half.delay(2);
+        assert!(delay >= Duration::from_millis(1000) && delay <= Duration::from_millis(2000), "{:?}", delay);
+    }
+    // Out-of-range jitter is clamped to [0, 1].
+    let over = policy(1000, 60_000, 3.0);
+    assert!((0..200).all(|_| over.delay(1) <= Duration::from_millis(1000)));
+    let under = policy(1000, 60_000, -1.0);
+    assert_eq!(under.delay(1), Duration::from_millis(1000));
+}
+
+#[test]
+fn errors_are_classified() {
+    let policy = RetryPolicy::default();
+    for status in [408, 425, 429, 500, 502, 503, 504] {
+        assert!(policy.should_retry(&http(status)), "{} should be retried", status);
+    }
+    for status in [400, 401, 403, 404, 409, 412, 422] {
+        assert!(!policy.should_retry(&http(status)), "{} should not be retried", status);
+    }
+    assert!(policy.should_retry(&StoreError::transient("connection reset")));
+    assert!(!policy.should_retry(&StoreError::permanent("bad request")));
+
+    let custom = RetryPolicy { retryable_statuses: vec![404], ..RetryPolicy::default() };
+    assert!(custom.should_retry(&http(404)));
+    assert!(!custom.should_retry(&http(503)));
+
+    // Downloads and listings fail with io::Error, classified through any
+    // StoreError inside or else by kind.
+    assert!(io::Error::from(http(503)).is_retryable(&policy));
+    assert!(!io::Error::from(http(404)).is_retryable(&policy));
+    assert!(io::Error::from(io::ErrorKind::ConnectionReset).is_retryable(&policy));
+    assert!(io::Error::from(io::ErrorKind::UnexpectedEof).is_retryable(&policy));
+    assert!(!io::Error::from(io::ErrorKind::NotFound).is_retryable(&policy));
+    assert!(!io::Error::from(io::ErrorKind::PermissionDenied).is_retryable(&policy));
+}
+
+/// Runs an operation that fails with each of `failures` in turn and then
+/// succeeds.  Returns its result, the attempts made and the attempts
+/// reported to `on_retry`.
+async fn run_failing(policy: &RetryPolicy, failures: Vec<StoreError>) -> (Result<(), StoreError>, usize, Vec<u32>) {
+    let failures = Mutex::new(failures.into_iter());
+    let attempts = Mutex::new(0);
+    let retried = Mutex::new(Vec::new());
+    let result = policy.run("Test operation", || {
+        *attempts.lock().unwrap() += 1;
+        let outcome = failures.lock().unwrap().next();
+        async move { outcome.map_or(Ok(()), Err) }
+    }, |attempt, _, _| retried.lock().unwrap().push(attempt)).await;
+    let attempts = *attempts.lock().unwrap();
+    (result, attempts, retried.into_inner().unwrap())
+}
+
+#[tokio::test]
+async fn run_makes_the_expected_attempts() {
+    let policy = RetryPolicy { max_attempts: 3, ..policy(1, 1, 0.0) };
+
+    let (result, attempts, retried) = run_failing(&policy, vec![http(503), http(429)]).await;
+    assert!(result.is_ok());
+    assert_eq!((attempts, retried), (3, vec![1, 2]));
+
+    let (result, attempts, retried) = run_failing(&policy, vec![http(403)]).await;
+    assert_eq!(result.unwrap_err().status, Some(403));
+    assert_eq!((attempts, retried), (1, vec![]));
+
+    let (result, attempts, retried) = run_failing(&policy, vec![http(503); 5]).await;
+    assert_eq!(result.unwrap_err().status, Some(503));
+    assert_eq!((attempts, retried), (3, vec![1, 2]));
+
+    let once = RetryPolicy { max_attempts: 1, ..policy.clone() };
+    let (result, attempts, _) = run_failing(&once, vec![http(503)]).await;
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+}
+
+/// A store whose deletes fail with scripted statuses, counting attempts.
+#[derive(Default)]
+struct ScriptedStore {
+    failures: Mutex<HashMap<String, Vec<u16>>>,
+    deletes: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+#[async_trait]
+impl StorageBackend for ScriptedStore {
+    async fn upload(&self, _key: &str, _source: File, _md5: Option<&str>, _callback: ProgressCallback) -> Result<(), StoreError> {
+        unimplemented!("only deletion is scripted")
+    }
+
+    async fn download(&self, _key: &str, _dest: File, _callback: ProgressCallback) -> io::Result<u64> {
+        unimplemented!("only deletion is scripted")
+    }
+
+    async fn list(&self, _prefix: Option<&str>, _marker: Option<&str>) -> io::Result<Vec<ObjectEntry>> {
+        Ok(Vec::new())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        *self.deletes.lock().unwrap().entry(key.to_string()).or_default() += 1;
+        match self.failures.lock().unwrap().get_mut(key).filter(|statuses| !statuses.is_empty()) {
+            Some(statuses) => Err(http(statuses.remove(0))),
+            None => Ok(()),
+        }
+    }
+
+    async fn stat(&self, _key: &str) -> Result<Option<ObjectEntry>, StoreError> {
+        Ok(None)
+    }
+}
+
+#[tokio::test]
+async fn delete_many_retries_only_retryable_keys() {
+    let store = ScriptedStore::default();
+    store.failures.lock().unwrap().extend([
+        ("flaky".to_string(), vec![503, 502]),
+        ("forbidden".to_string(), vec![403, 403, 403]),
+        ("down".to_string(), vec![503; 10]),
+    ]);
+    let deletes = Arc::clone(&store.deletes);
+
+    let retry = RetryPolicy { max_attempts: 4, ..policy(1, 1, 0.0) };
+    let bucket = Bucket::new(1, Box::new(store), retry);
+    let keys: Vec<String> = ["ok", "flaky", "forbidden", "down"].iter().map(|k| k.to_string()).collect();
+    let report = bucket.delete_many(&keys).await;
+
+    assert_eq!(report.deleted, 2);
+    let mut failed: Vec<(&str, Option<u16>)> = report.errors.iter().map(|(key, e)| (key.as_str(), e.status)).collect();
+    failed.sort();
+    assert_eq!(failed, vec![("down", Some(503)), ("forbidden", Some(403))]);
+    let deletes = deletes.lock().unwrap();
+    assert_eq!(deletes["ok"], 1);
+    assert_eq!(deletes["flaky"], 3, "retried until it succeeded");
+    assert_eq!(deletes["forbidden"], 1, "a 403 is not retried");
+    assert_eq!(deletes["down"], 4, "retried up to max_attempts");
+}
+
+/// A WebDAV server that answers the first `failures` PUTs with 503 and
+/// stores the rest.
+#[derive(Default)]
+struct DavState {
+    failures: u32,
+    puts: u32,
+    objects: HashMap<String, Vec<u8>>,
+}
+
+async fn serve_dav(listener: TcpListener, state: Arc<Mutex<DavState>>) {
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.is_err() {
+                return;
+            }
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap_or_default().to_string();
+            let path = parts.next().unwrap_or_default().to_string();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap_or(0);
+                    }
+                }
+            }
+            let mut body = vec![0u8; length];
+            stream.read_exact(&mut body).await.unwrap();
+
+            let (status, body) = {
+                let mut state = state.lock().unwrap();
+                match method.as_str() {
+                    "MKCOL" => (201, Vec::new()),
+                    "PUT" => {
+                        state.puts += 1;
+                        if state.puts <= state.failures {
+                            (503, b"try again later".to_vec())
+                        } else {
+                            state.objects.insert(path, body);
+                            (201, Vec::new())
+                        }
+                    }
+                    "GET" => match state.objects.get(&path) {
+                        Some(object) => (200, object.clone()),
+                        None => (404, Vec::new()),
+                    },
+                    _ => (405, Vec::new()),
+                }
+            };
+            let head = format!("HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+            let stream = stream.get_mut();
+            let _ = stream.write_all(head.as_bytes()).await;
+            let _ = stream.write_all(&body).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+fn temp_file(contents: &[u8]) -> File {
+    let path = std::env::temp_dir().join(format!("retry-test-{}-{}", std::process::id(), rand::random::<u32>()));
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file.write_all(contents).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file
+}
+
+#[tokio::test]
+async fn upload_answered_with_503_is_retried() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let state = Arc::new(Mutex::new(DavState { failures: 2, ..DavState::default() }));
+    tokio::spawn(serve_dav(listener, Arc::clone(&state)));
+
+    let config = WebDavConfig { url: format!("http://{}/backups/", address), username: None, password: None };
+    let retry = RetryPolicy { max_attempts: 3, ..policy(1, 1, 0.0) };
+    let bucket = Bucket::new(1, Box::new(WebDavBucket::new(&config).unwrap()), retry);
+
+    let contents: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let retries = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&retries);
+    bucket.upload_with_progress("data/object", temp_file(&contents), None, move |transfer| {
+        if let Transfer::Retry { attempt, .. } = transfer {
+            seen.lock().unwrap().push(attempt);
+        }
+    }).await.unwrap();
+    assert_eq!(*retries.lock().unwrap(), vec![1, 2]);
+    assert_eq!(state.lock().unwrap().puts, 3);
+
+    let mut dest = temp_file(&[]);
+    bucket.download("data/object", dest.try_clone().unwrap()).await.unwrap();
+    let mut downloaded = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut downloaded).unwrap();
+    assert_eq!(downloaded, contents);
+
+    // Once the attempts run out the last error is returned.
+    state.lock().unwrap().failures = u32::MAX;
+    let error = bucket.upload_with_progress("data/other", temp_file(&contents), None, |_| {}).await.unwrap_err();
+    assert!(error.contains("503"), "{}", error);
+}
+
+#[test]
+fn bad_webdav_url_is_an_error() {
+    let config = |url: &str| WebDavConfig { url: url.to_string(), username: None, password: None };
+    assert!(WebDavBucket::new(&config("dav.example.com/backups")).is_err());
+    assert!(WebDavBucket::new(&config("mailto:backups@example.com")).is_err());
+    assert!(WebDavBucket::new(&config("https://dav.example.com/backups")).is_ok());
+}
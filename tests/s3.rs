@@ -0,0 +1,175 @@
+//! Checks ranged downloads from an S3 stand-in, both when it honours the
+//! `Range` header and when it ignores it and sends the whole object, that
+//! only plain ETags in a listing are taken for MD5s, and that a bad endpoint
+//! is reported rather than panicking.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+use backup_tool::s3_bucket::{S3Bucket, S3Config};
+use backup_tool::storage::StorageBackend;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// A path-style S3 server that serves GETs of `objects` and lists `etags`
+/// without checking signatures.
+#[derive(Default)]
+struct S3State {
+    objects: HashMap<String, Vec<u8>>,
+    /// The listed keys of bucket `backups` and their ETags.
+    etags: Vec<(String, String)>,
+    honour_range: bool,
+    /// The `Range` header of each GET, if it had one.
+    ranges: Vec<Option<String>>,
+}
+
+async fn serve_s3(listener: TcpListener, state: Arc<Mutex<S3State>>) {
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.is_err() {
+                return;
+            }
+            let path = line.split_whitespace().nth(1).unwrap_or_default().to_string();
+            let mut range = None;
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("range") {
+                        range = Some(value.trim().to_string());
+                    }
+                }
+            }
+
+            let (status, body) = {
+                let mut state = state.lock().unwrap();
+                if path.starts_with("/backups/?") {
+                    let contents: String = state.etags.iter().map(|(key, etag)| format!(
+                        "<Contents><Key>{}</Key><LastModified>2024-01-01T00:00:00.000Z</LastModified><ETag>&quot;{}&quot;</ETag><Size>1</Size></Contents>",
+                        key, etag,
+                    )).collect();
+                    (200, format!("<ListBucketResult>{}</ListBucketResult>", contents).into_bytes())
+                } else {
+                    state.ranges.push(range.clone());
+                    match state.objects.get(&path) {
+                        None => (404, Vec::new()),
+                        Some(object) => match range.filter(|_| state.honour_range) {
+                            Some(range) => {
+                                let (first, last) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+                                let first: usize = first.parse().unwrap();
+                                let last: usize = last.parse().unwrap();
+                                (206, object[first.min(object.len())..(last + 1).min(object.len())].to_vec())
+                            }
+                            None => (200, object.clone()),
+                        },
+                    }
+                }
+            };
+            let head = format!("HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+            let stream = stream.get_mut();
+            let _ = stream.write_all(head.as_bytes()).await;
+            let _ = stream.write_all(&body).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+/// Starts a stand-in holding `backups/data/object` and returns a store on it.
+async fn start(honour_range: bool) -> (S3Bucket, Arc<Mutex<S3State>>, Vec<u8>) {
+    let contents: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let mut state = S3State { honour_range, ..S3State::default() };
+    state.objects.insert("/backups/data/object".to_string(), contents.clone());
+    let state = Arc::new(Mutex::new(state));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = config(&format!("http://{}", listener.local_addr().unwrap()), true);
+    tokio::spawn(serve_s3(listener, Arc::clone(&state)));
+    (S3Bucket::new(&config).unwrap(), state, contents)
+}
+
+fn config(endpoint: &str, path_style: bool) -> S3Config {
+    S3Config {
+        endpoint: endpoint.to_string(),
+        region: "us-east-1".to_string(),
+        bucket: "backups".to_string(),
+        access_key_id: "key".to_string(),
+        secret_access_key: "secret".to_string(),
+        session_token: None,
+        path_style,
+    }
+}
+
+fn temp_file() -> File {
+    let path = std::env::temp_dir().join(format!("s3-test-{}-{}", std::process::id(), rand::random::<u32>()));
+    let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file
+}
+
+/// Downloads a range into a fresh file and returns the bytes written.
+async fn download_range(store: &S3Bucket, offset: u64, length: u64) -> Vec<u8> {
+    let mut dest = temp_file();
+    let written = store.download_range("data/object", offset, length, dest.try_clone().unwrap()).await.unwrap();
+    let mut downloaded = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut downloaded).unwrap();
+    assert_eq!(written, downloaded.len() as u64);
+    downloaded
+}
+
+#[tokio::test]
+async fn ranged_download_asks_for_the_window() {
+    let (store, state, contents) = start(true).await;
+    assert_eq!(download_range(&store, 5, 10).await, &contents[5..15]);
+    assert_eq!(download_range(&store, 9_990, 100).await, &contents[9_990..]);
+    assert_eq!(state.lock().unwrap().ranges, vec![Some("bytes=5-14".to_string()), Some("bytes=9990-10089".to_string())]);
+}
+
+#[tokio::test]
+async fn ranged_download_trims_a_whole_object() {
+    let (store, _, contents) = start(false).await;
+    assert_eq!(download_range(&store, 5, 10).await, &contents[5..15]);
+    assert_eq!(download_range(&store, 9_990, 100).await, &contents[9_990..]);
+    assert!(download_range(&store, 20_000, 10).await.is_empty());
+}
+
+#[tokio::test]
+async fn empty_range_sends_no_request() {
+    let (store, state, _) = start(true).await;
+    assert!(download_range(&store, 5, 0).await.is_empty());
+    assert!(state.lock().unwrap().ranges.is_empty());
+
+    let mut dest = temp_file();
+    let error = store.download_range("data/missing", 0, 10, dest.try_clone().unwrap()).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(dest.read(&mut [0u8; 1]).unwrap(), 0);
+}
+
+#[test]
+fn bad_endpoint_is_an_error() {
+    assert!(S3Bucket::new(&config("s3.example.com", true)).is_err());
+    assert!(S3Bucket::new(&config("unix:/var/run/s3", true)).is_err());
+    assert!(S3Bucket::new(&config("http://[::1]:9000", false)).is_err());
+    assert!(S3Bucket::new(&config("http://[::1]:9000", true)).is_ok());
+}
+
+#[tokio::test]
+async fn multipart_etags_are_not_md5s() {
+    let (store, state, _) = start(true).await;
+    state.lock().unwrap().etags = vec![
+        ("data/single".to_string(), "9e107d9d372bb6826bd81d3542a419d6".to_string()),
+        ("data/multipart".to_string(), "d41d8cd98f00b204e9800998ecf8427e-3".to_string()),
+    ];
+    let listed = store.list(Some("data/"), None).await.unwrap();
+    let md5s: Vec<(&str, bool)> = listed.iter().map(|e| (e.name.as_str(), store.hash_is_md5(e))).collect();
+    assert_eq!(md5s, vec![("data/single", true), ("data/multipart", false)]);
+}
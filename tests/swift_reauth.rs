@@ -0,0 +1,365 @@
+//! Runs `SwiftBucket` against a stand-in Keystone and Swift whose tokens are
+//! only honoured for a few requests, as if they expired during a long backup,
+//! and checks that uploads (streamed and segmented) and downloads carry on
+//! with a fresh token.  The same stand-in checks how large objects are
+//! deleted together with their segments.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use backup_tool::bucket::Bucket;
+use backup_tool::retry::RetryPolicy;
+use backup_tool::storage::StorageBackend;
+use backup_tool::swift::SwiftBucket;
+use backup_tool::utils::uri_decode;
+use md5::{Digest, Md5};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+const ACCOUNT: &str = "/v1/AUTH_test/";
+const CONTAINER: &str = "backups";
+
+#[derive(Default)]
+struct State {
+    tokens_issued: u32,
+    /// Swift requests each token is accepted for before it "expires".
+    token_lifetime: u32,
+    uses: HashMap<String, u32>,
+    expired: HashSet<String>,
+    rejected: u32,
+    objects: HashMap<String, Vec<u8>>,
+    /// Manifest path to the paths of its segments.
+    manifests: HashMap<String, Vec<String>>,
+    /// Container listings served.
+    listings: u32,
+    /// Objects that bulk delete fails to remove, with 503, and how many more
+    /// times it does.
+    failing_deletes: HashMap<String, u32>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16) -> Response {
+        Response { status, headers: Vec::new(), body: Vec::new() }
+    }
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> std::io::Result<Request> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (path, query) = (path.to_string(), query.to_string());
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let mut body = Vec::new();
+    if headers.get("transfer-encoding").is_some_and(|t| t.eq_ignore_ascii_case("chunked")) {
+        loop {
+            let mut size = String::new();
+            stream.read_line(&mut size).await?;
+            let size = usize::from_str_radix(size.trim(), 16).unwrap_or(0);
+            let mut chunk = vec![0u8; size + 2];
+            stream.read_exact(&mut chunk).await?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = headers.get("content-length").and_then(|l| l.parse::<usize>().ok()) {
+        body.resize(length, 0);
+        stream.read_exact(&mut body).await?;
+    }
+    Ok(Request { method, path, query, headers, body })
+}
+
+fn keystone(request: &Request, state: &mut State, address: SocketAddr) -> Response {
+    if request.method != "POST" || request.path != "/v3/auth/tokens" {
+        return Response::new(404);
+    }
+    state.tokens_issued += 1;
+    let token = format!("token-{}", state.tokens_issued);
+    let expires_at = (chrono::Utc::now() + chrono::Duration::hours(12)).format("%Y-%m-%dT%H:%M:%S%.6fZ");
+    let body = format!(
+        r#"{{"token": {{
+            "methods": ["password"],
+            "expires_at": "{expires_at}",
+            "user": {{"id": "u1", "name": "test", "domain": {{"id": "default", "name": "Default"}}}},
+            "project": {{"id": "p1", "name": "test", "domain": {{"id": "default", "name": "Default"}}}},
+            "roles": [{{"id": "r1", "name": "member"}}],
+            "catalog": [{{
+                "id": "s1", "type": "object-store", "name": "swift",
+                "endpoints": [{{"id": "e1", "interface": "public", "region": "RegionOne", "region_id": "RegionOne",
+                               "url": "http://{address}/v1/AUTH_test"}}]
+            }}]
+        }}}}"#
+    );
+    let mut response = Response::new(201);
+    response.headers.push(("X-Subject-Token", token));
+    response.headers.push(("Content-Type", "application/json".to_string()));
+    response.body = body.into_bytes();
+    response
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').filter_map(|pair| pair.split_once('=')).find(|(k, _)| *k == name).map(|(_, v)| v)
+}
+
+fn list(request: &Request, state: &mut State) -> Response {
+    state.listings += 1;
+    let prefix = query_param(&request.query, "prefix").map(uri_decode).unwrap_or_default();
+    let marker = query_param(&request.query, "marker").map(uri_decode).unwrap_or_default();
+    let container_path = format!("{}/", CONTAINER);
+    let mut names: Vec<&str> = state.objects.keys().chain(state.manifests.keys())
+        .filter_map(|path| path.strip_prefix(&container_path))
+        .filter(|name| name.starts_with(&prefix) && *name > marker.as_str())
+        .collect();
+    names.sort();
+    let entries: Vec<String> = names.iter().take(100).map(|name| format!(
+        r#"{{"name": "{}", "bytes": 0, "hash": "", "last_modified": "2026-01-01T00:00:00.000000", "content_type": "application/octet-stream"}}"#,
+        name
+    )).collect();
+    let mut response = Response::new(200);
+    response.headers.push(("Content-Type", "application/json".to_string()));
+    response.body = format!("[{}]", entries.join(",")).into_bytes();
+    response
+}
+
+fn bulk_delete(request: &Request, state: &mut State) -> Response {
+    let (mut deleted, mut not_found, mut errors) = (0, 0, Vec::new());
+    for line in String::from_utf8_lossy(&request.body).lines() {
+        // Paths are /<container>/<key>, as stored.
+        let name = uri_decode(line.trim_start_matches('/'));
+        if let Some(failures) = state.failing_deletes.get_mut(&name).filter(|failures| **failures > 0) {
+            *failures -= 1;
+            errors.push(format!(r#"["{}", "503 Service Unavailable"]"#, line));
+        } else if state.objects.remove(&name).is_some() || state.manifests.remove(&name).is_some() {
+            deleted += 1;
+        } else {
+            not_found += 1;
+        }
+    }
+    let status = if errors.is_empty() { "200 OK" } else { "400 Bad Request" };
+    let mut response = Response::new(200);
+    response.headers.push(("Content-Type", "application/json".to_string()));
+    response.body = format!(
+        r#"{{"Number Deleted": {}, "Number Not Found": {}, "Response Status": "{}", "Response Body": "", "Errors": [{}]}}"#,
+        deleted, not_found, status, errors.join(",")
+    ).into_bytes();
+    response
+}
+
+fn swift(request: &Request, state: &mut State) -> Response {
+    let token = request.headers.get("x-auth-token").cloned().unwrap_or_default();
+    let uses = state.uses.entry(token.clone()).or_default();
+    *uses += 1;
+    if *uses > state.token_lifetime {
+        state.expired.insert(token.clone());
+    }
+    if token.is_empty() || state.expired.contains(&token) {
+        state.rejected += 1;
+        return Response::new(401);
+    }
+
+    let name = request.path.strip_prefix(ACCOUNT).unwrap_or_default().to_string();
+    match request.method.as_str() {
+        "HEAD" if name == CONTAINER => Response::new(204),
+        "GET" if name == CONTAINER => list(request, state),
+        "POST" if request.query.contains("bulk-delete") => bulk_delete(request, state),
+        "DELETE" => {
+            // multipart-manifest=delete removes a manifest's segments too.
+            if let Some(paths) = state.manifests.remove(&name) {
+                for path in paths {
+                    state.objects.remove(&path);
+                }
+            } else {
+                state.objects.remove(&name);
+            }
+            Response::new(204)
+        }
+        "PUT" if request.query.contains("multipart-manifest=put") => {
+            // Segment paths are all the manifest is needed for here.
+            let manifest = String::from_utf8_lossy(&request.body);
+            let paths = manifest.split("\"path\"").skip(1)
+                .filter_map(|rest| rest.split('"').nth(1))
+                .map(|path| path.trim_start_matches('/').to_string())
+                .collect();
+            state.manifests.insert(name, paths);
+            Response::new(201)
+        }
+        "PUT" => {
+            let etag = format!("{:x}", Md5::digest(&request.body));
+            if request.headers.get("etag").is_some_and(|expected| *expected != etag) {
+                return Response::new(422);
+            }
+            state.objects.insert(name, request.body.clone());
+            let mut response = Response::new(201);
+            response.headers.push(("ETag", etag));
+            response
+        }
+        "GET" => {
+            let body = match state.manifests.get(&name) {
+                Some(paths) => paths.iter().flat_map(|p| state.objects[p].clone()).collect(),
+                None => match state.objects.get(&name) {
+                    Some(body) => body.clone(),
+                    None => return Response::new(404),
+                },
+            };
+            let mut response = Response::new(200);
+            response.body = body;
+            response
+        }
+        _ => Response::new(405),
+    }
+}
+
+async fn serve(listener: TcpListener, state: Arc<Mutex<State>>) {
+    let address = listener.local_addr().unwrap();
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let Ok(request) = read_request(&mut stream).await else { return };
+            let response = {
+                let mut state = state.lock().unwrap();
+                if request.path.starts_with("/v3") {
+                    keystone(&request, &mut state, address)
+                } else {
+                    swift(&request, &mut state)
+                }
+            };
+            let mut head = format!("HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, response.body.len());
+            for (name, value) in &response.headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str("\r\n");
+            let stream = stream.get_mut();
+            let _ = stream.write_all(head.as_bytes()).await;
+            if request.method != "HEAD" {
+                let _ = stream.write_all(&response.body).await;
+            }
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+fn temp_file(contents: &[u8]) -> std::fs::File {
+    let path = std::env::temp_dir().join(format!("swift-reauth-{}-{}", std::process::id(), rand::random::<u32>()));
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file.write_all(contents).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file
+}
+
+async fn download(bucket: &SwiftBucket, key: &str) -> Vec<u8> {
+    let mut dest = temp_file(&[]);
+    bucket.download(key, dest.try_clone().unwrap(), Box::new(|_| {})).await.unwrap();
+    let mut contents = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut contents).unwrap();
+    contents
+}
+
+/// A bucket on the stand-in at `address`, with 64 KiB segments.  The
+/// session is configured through the environment, which the tests share.
+async fn connect(address: SocketAddr) -> SwiftBucket {
+    static ENV: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let _env = ENV.lock().await;
+    std::env::set_var("OS_AUTH_URL", format!("http://{}/v3", address));
+    std::env::set_var("OS_USERNAME", "test");
+    std::env::set_var("OS_PASSWORD", "secret");
+    std::env::set_var("OS_PROJECT_NAME", "test");
+    std::env::set_var("OS_USER_DOMAIN_NAME", "Default");
+    std::env::set_var("OS_PROJECT_DOMAIN_NAME", "Default");
+    std::env::set_var("OS_REGION_NAME", "RegionOne");
+    let session = osauth::Session::from_env().await.unwrap();
+    SwiftBucket::new(session, CONTAINER, 64 * 1024, "segments/")
+}
+
+#[tokio::test]
+async fn expired_tokens_are_renewed() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let state = Arc::new(Mutex::new(State { token_lifetime: 2, ..State::default() }));
+    tokio::spawn(serve(listener, Arc::clone(&state)));
+
+    let bucket = connect(address).await;
+
+    // Small enough for a single streamed PUT.
+    let small: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+    let md5 = format!("{:x}", Md5::digest(&small));
+    bucket.upload("data/small", temp_file(&small), Some(&md5), Box::new(|_| {})).await.unwrap();
+    // Four segments and a manifest: the token expires part-way through.
+    let large: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+    bucket.upload("data/large", temp_file(&large), None, Box::new(|_| {})).await.unwrap();
+
+    assert_eq!(download(&bucket, "data/small").await, small);
+    assert_eq!(download(&bucket, "data/large").await, large);
+
+    let state = state.lock().unwrap();
+    assert!(state.rejected > 0, "no token expired during the test");
+    assert_eq!(state.tokens_issued, state.rejected + 1, "each rejection should cost exactly one new token");
+}
+
+#[tokio::test]
+async fn large_objects_are_deleted_with_their_segments() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let state = Arc::new(Mutex::new(State { token_lifetime: u32::MAX, ..State::default() }));
+    tokio::spawn(serve(listener, Arc::clone(&state)));
+    let swift = connect(address).await;
+
+    let small: Vec<u8> = (0..1_000u32).map(|i| (i % 251) as u8).collect();
+    swift.upload("data/small", temp_file(&small), None, Box::new(|_| {})).await.unwrap();
+    let large: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+    swift.upload("data/large", temp_file(&large), None, Box::new(|_| {})).await.unwrap();
+    {
+        let mut state = state.lock().unwrap();
+        let segment = state.manifests[&format!("{}/data/large", CONTAINER)][1].clone();
+        state.failing_deletes.insert(segment, 1);
+        state.listings = 0;
+    }
+
+    // The first bulk delete leaves one segment, so the manifest is kept and
+    // the failure reported against it.  The retry deletes the manifest with
+    // its remaining segments, without listing them again.
+    let retry = RetryPolicy { base_delay_ms: 1, jitter: 0.0, ..RetryPolicy::default() };
+    let bucket = Bucket::new(1, Box::new(swift), retry);
+    let report = bucket.delete_many(&["data/small".to_string(), "data/large".to_string()]).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.deleted, 2, "only the keys asked for should be counted");
+
+    let state = state.lock().unwrap();
+    assert!(state.objects.is_empty() && state.manifests.is_empty(), "left behind: {:?}", state.objects.keys().collect::<Vec<_>>());
+    assert_eq!(state.listings, 1, "the segments should be listed once");
+}
@@ -0,0 +1,98 @@
+//! Checks which bandwidth limit is in force at a given time of day, including
+//! schedule windows that wrap past midnight, and how the token bucket refills
+//! and caps the allowance.
+
+use std::time::{Duration, Instant};
+
+use backup_tool::throttle::{BandwidthLimit, BandwidthWindow, Direction, Limiter};
+use chrono::NaiveTime;
+
+fn at(hour: u32, minute: u32, second: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, minute, second).unwrap()
+}
+
+fn window(start: &str, end: &str, upload: Option<u64>, download: Option<u64>) -> BandwidthWindow {
+    BandwidthWindow { start: start.to_string(), end: end.to_string(), upload, download }
+}
+
+fn limiter(upload: Option<u64>, download: Option<u64>, schedule: Vec<BandwidthWindow>) -> Limiter {
+    Limiter::new(&BandwidthLimit { upload, download, schedule }).unwrap()
+}
+
+#[test]
+fn window_includes_its_start_and_excludes_its_end() {
+    let limiter = limiter(Some(1000), None, vec![window("09:00", "17:00", Some(100), None)]);
+    assert_eq!(limiter.rate(Direction::Upload, at(8, 59, 59)), Some(1000));
+    assert_eq!(limiter.rate(Direction::Upload, at(9, 0, 0)), Some(100));
+    assert_eq!(limiter.rate(Direction::Upload, at(16, 59, 59)), Some(100));
+    assert_eq!(limiter.rate(Direction::Upload, at(17, 0, 0)), Some(1000));
+}
+
+#[test]
+fn window_wraps_past_midnight() {
+    let limiter = limiter(Some(1000), None, vec![window("22:00", "06:00", Some(100), None)]);
+    assert_eq!(limiter.rate(Direction::Upload, at(21, 59, 59)), Some(1000));
+    assert_eq!(limiter.rate(Direction::Upload, at(22, 0, 0)), Some(100));
+    assert_eq!(limiter.rate(Direction::Upload, at(23, 59, 59)), Some(100));
+    assert_eq!(limiter.rate(Direction::Upload, at(0, 0, 0)), Some(100));
+    assert_eq!(limiter.rate(Direction::Upload, at(5, 59, 59)), Some(100));
+    assert_eq!(limiter.rate(Direction::Upload, at(6, 0, 0)), Some(1000));
+    assert_eq!(limiter.rate(Direction::Upload, at(12, 0, 0)), Some(1000));
+}
+
+#[test]
+fn window_replaces_both_limits() {
+    // Inside the window its own limits apply, and a limit it leaves out is
+    // lifted; the first matching window wins.
+    let limiter = limiter(Some(1000), Some(2000), vec![
+        window("01:00", "05:00", None, Some(500)),
+        window("00:00", "12:00", Some(10), Some(20)),
+    ]);
+    assert_eq!(limiter.rate(Direction::Upload, at(2, 0, 0)), None);
+    assert_eq!(limiter.rate(Direction::Download, at(2, 0, 0)), Some(500));
+    assert_eq!(limiter.rate(Direction::Upload, at(6, 0, 0)), Some(10));
+    assert_eq!(limiter.rate(Direction::Download, at(13, 0, 0)), Some(2000));
+}
+
+#[test]
+fn empty_window_and_zero_rate_do_not_limit() {
+    let limiter = limiter(Some(0), Some(1000), vec![window("08:00", "08:00", Some(1), Some(1))]);
+    assert_eq!(limiter.rate(Direction::Upload, at(8, 0, 0)), None);
+    assert_eq!(limiter.rate(Direction::Download, at(8, 0, 0)), Some(1000));
+}
+
+#[test]
+fn invalid_schedule_times_are_rejected() {
+    let limit = BandwidthLimit { upload: None, download: None, schedule: vec![window("25:00", "06:00", None, None)] };
+    assert!(Limiter::new(&limit).is_err());
+    let limit = BandwidthLimit { upload: None, download: None, schedule: vec![window("9am", "06:00", None, None)] };
+    assert!(Limiter::new(&limit).is_err());
+}
+
+#[test]
+fn allowance_refills_at_the_rate() {
+    let limiter = limiter(Some(1000), None, Vec::new());
+    let noon = at(12, 0, 0);
+    let start = Instant::now();
+    // The bucket starts full with one second's worth.
+    assert_eq!(limiter.reserve_at(Direction::Upload, 1000, noon, start), Duration::ZERO);
+    // Going 500 bytes over costs half a second.
+    assert_eq!(limiter.reserve_at(Direction::Upload, 500, noon, start), Duration::from_millis(500));
+    // A second later 1000 bytes have come back, paying off the debt first.
+    let later = start + Duration::from_secs(1);
+    assert_eq!(limiter.reserve_at(Direction::Upload, 500, noon, later), Duration::ZERO);
+    assert_eq!(limiter.reserve_at(Direction::Upload, 250, noon, later), Duration::from_millis(250));
+    // The other direction is unlimited and untouched.
+    assert_eq!(limiter.reserve_at(Direction::Download, 1 << 30, noon, later), Duration::ZERO);
+}
+
+#[test]
+fn allowance_is_capped_at_one_second() {
+    let limiter = limiter(Some(1000), None, Vec::new());
+    let noon = at(12, 0, 0);
+    let start = Instant::now();
+    assert_eq!(limiter.reserve_at(Direction::Upload, 1000, noon, start), Duration::ZERO);
+    // An idle minute only refills one second's worth.
+    let idle = start + Duration::from_secs(60);
+    assert_eq!(limiter.reserve_at(Direction::Upload, 3000, noon, idle), Duration::from_secs(2));
+}
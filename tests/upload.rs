@@ -0,0 +1,218 @@
+//! Checks that each store's upload limit is honoured without a slow store
+//! holding back a fast one, that the upload statistics add up, and that
+//! streaming more objects than there are threads and upload slots to stores
+//! listed in either order does not deadlock.  Also checks that a local
+//! store does not list the `.partial` file of an interrupted stream.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use backup_tool::bucket::{Bucket, ObjectEntry};
+use backup_tool::compression::Compression;
+use backup_tool::datastore::DataStore;
+use backup_tool::local_bucket::LocalBucket;
+use backup_tool::retry::RetryPolicy;
+use backup_tool::storage::{ProgressCallback, StorageBackend, StoreError, UploadStream};
+use backup_tool::upload_worker::{self, UploadRequest};
+use futures::StreamExt;
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use sequoia_openpgp::cert::CertBuilder;
+
+/// What a [`Paced`] store has seen.
+#[derive(Default)]
+struct Activity {
+    active: AtomicUsize,
+    peak: AtomicUsize,
+    finished: Mutex<Vec<Instant>>,
+    received: Mutex<HashMap<String, usize>>,
+}
+
+impl Activity {
+    fn start(&self) {
+        let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(active, Ordering::SeqCst);
+    }
+
+    fn end(&self, key: &str, bytes: usize) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        self.finished.lock().unwrap().push(Instant::now());
+        self.received.lock().unwrap().insert(key.to_string(), bytes);
+    }
+}
+
+/// A store that takes `delay` over every upload and records how many run at
+/// once.
+struct Paced {
+    delay: Duration,
+    activity: Arc<Activity>,
+}
+
+#[async_trait]
+impl StorageBackend for Paced {
+    async fn upload(&self, key: &str, mut source: File, _md5: Option<&str>, callback: ProgressCallback) -> Result<(), StoreError> {
+        self.activity.start();
+        let mut contents = Vec::new();
+        let read = source.read_to_end(&mut contents);
+        tokio::time::sleep(self.delay).await;
+        callback(contents.len());
+        self.activity.end(key, contents.len());
+        read.map(|_| ()).map_err(|e| StoreError::permanent(e.to_string()))
+    }
+
+    fn can_stream(&self, _length: u64) -> bool {
+        true
+    }
+
+    async fn upload_stream(&self, key: &str, mut source: UploadStream, callback: ProgressCallback) -> Result<(), StoreError> {
+        self.activity.start();
+        // Start late, so that the encryptor fills the queue and waits.
+        tokio::time::sleep(self.delay).await;
+        let mut bytes = 0;
+        let mut result = Ok(());
+        while let Some(piece) = source.next().await {
+            match piece {
+                Ok(piece) => {
+                    bytes += piece.len();
+                    callback(piece.len());
+                }
+                Err(e) => {
+                    result = Err(StoreError::permanent(e.to_string()));
+                    break;
+                }
+            }
+        }
+        self.activity.end(key, bytes);
+        result
+    }
+
+    async fn download(&self, _key: &str, _dest: File, _callback: ProgressCallback) -> io::Result<u64> {
+        unimplemented!("only uploads are paced")
+    }
+
+    async fn list(&self, _prefix: Option<&str>, _marker: Option<&str>) -> io::Result<Vec<ObjectEntry>> {
+        Ok(Vec::new())
+    }
+
+    async fn delete(&self, _key: &str) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn stat(&self, _key: &str) -> Result<Option<ObjectEntry>, StoreError> {
+        Ok(None)
+    }
+}
+
+/// A store `id` whose uploads take `delay`, at most `limit` at once.
+fn paced_store(id: i32, delay: Duration, limit: usize) -> ((DataStore, Bucket), Arc<Activity>) {
+    let store: DataStore = toml::from_str(&format!(
+        "id = {}\ndata_prefix = \"data/\"\nmetadata_prefix = \"metadata/\"\n", id,
+    )).unwrap();
+    let activity = Arc::new(Activity::default());
+    let backend = Paced { delay, activity: Arc::clone(&activity) };
+    let bucket = Bucket::new(id, Box::new(backend), RetryPolicy::default()).with_upload_limit(limit);
+    ((store, bucket), activity)
+}
+
+/// Writes `count` files of `size` bytes into a fresh directory and returns
+/// it with upload requests for them.
+fn requests(name: &str, count: usize, size: usize) -> (PathBuf, Vec<UploadRequest>) {
+    let dir = std::env::temp_dir().join(format!("upload-test-{}-{}-{}", name, std::process::id(), rand::random::<u32>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let requests = (0..count).map(|i| {
+        let filename: PathBuf = dir.join(i.to_string());
+        let contents: Vec<u8> = (0..size).map(|_| rand::random::<u8>()).collect();
+        File::create(&filename).unwrap().write_all(&contents).unwrap();
+        UploadRequest { filename, data_hash: format!("{:064x}", i), encrypted_md5: None, range: None, sizes: None }
+    }).collect();
+    (dir, requests)
+}
+
+fn hidden_progress() -> MultiProgress {
+    MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn slow_store_does_not_hold_back_a_fast_one() {
+    let (fast, fast_activity) = paced_store(1, Duration::from_millis(50), 2);
+    let (slow, slow_activity) = paced_store(2, Duration::from_millis(300), 1);
+    let stores = vec![&fast, &slow];
+    let (dir, requests) = requests("paced", 4, 1000);
+    let mp = hidden_progress();
+
+    let start = Instant::now();
+    let reports = futures::future::join_all(requests.into_iter().map(|request| upload_worker::upload(request, &stores, &mp))).await;
+    std::fs::remove_dir_all(dir).unwrap();
+    for report in &reports {
+        assert_eq!(report.store_ids, vec![1, 2]);
+    }
+
+    // The fast store takes two uploads at a time and is done long before
+    // the slow one has finished its second.
+    assert_eq!(fast_activity.peak.load(Ordering::SeqCst), 2);
+    assert_eq!(slow_activity.peak.load(Ordering::SeqCst), 1);
+    let fast_done = *fast_activity.finished.lock().unwrap().iter().max().unwrap();
+    assert!(fast_done - start < Duration::from_millis(600), "fast store finished after {:?}", fast_done - start);
+    assert!(start.elapsed() >= Duration::from_millis(1200), "the slow store uploads one at a time");
+
+    let (fast_stats, slow_stats) = (fast.1.upload_stats(), slow.1.upload_stats());
+    assert_eq!((fast_stats.objects, fast_stats.bytes), (4, 4000));
+    assert_eq!((slow_stats.objects, slow_stats.bytes), (4, 4000));
+    assert!(slow_stats.busy >= Duration::from_millis(1200), "busy for {:?}", slow_stats.busy);
+    assert!(fast_stats.busy < slow_stats.busy);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn streams_more_objects_than_threads_and_slots() {
+    let (first, first_activity) = paced_store(1, Duration::from_millis(20), 1);
+    let (second, second_activity) = paced_store(2, Duration::from_millis(20), 1);
+    let (key, _) = CertBuilder::general_purpose(None, Some("upload test")).generate().unwrap();
+    // Each object is more than the queues hold, so the encryptor waits for
+    // the stores while it streams it.
+    let count = rayon::current_num_threads() + 4;
+    let (dir, requests) = requests("stream", count, 3 * 1024 * 1024);
+    let mp = hidden_progress();
+
+    // Half of the objects list the stores the other way round.
+    let forwards = vec![&first, &second];
+    let backwards = vec![&second, &first];
+    let uploads = requests.into_iter().enumerate().map(|(i, request)| {
+        let stores = if i % 2 == 0 { &forwards } else { &backwards };
+        upload_worker::stream_upload(request, &key, Compression::None, stores, &mp)
+    });
+    let reports = tokio::time::timeout(Duration::from_secs(120), futures::future::join_all(uploads))
+        .await
+        .expect("streaming uploads deadlocked");
+    std::fs::remove_dir_all(dir).unwrap();
+
+    for report in &reports {
+        let mut store_ids = report.store_ids.clone();
+        store_ids.sort();
+        assert_eq!(store_ids, vec![1, 2], "{} was not streamed to both stores", report.data_hash);
+    }
+    for (bucket, activity) in [(&first.1, &first_activity), (&second.1, &second_activity)] {
+        assert_eq!(activity.peak.load(Ordering::SeqCst), 1);
+        assert_eq!(bucket.upload_stats().objects, count as u64);
+        let received = activity.received.lock().unwrap();
+        let sent: u64 = received.values().map(|&bytes| bytes as u64).sum();
+        assert_eq!(bucket.upload_stats().bytes, sent);
+    }
+}
+
+#[tokio::test]
+async fn interrupted_streams_are_not_listed() {
+    let root = std::env::temp_dir().join(format!("upload-test-partial-{}-{}", std::process::id(), rand::random::<u32>()));
+    std::fs::create_dir_all(root.join("data")).unwrap();
+    std::fs::write(root.join("data/stored"), b"whole object").unwrap();
+    std::fs::write(root.join("data/interrupted.partial"), b"half an obj").unwrap();
+
+    let store = LocalBucket::new(&root);
+    let names: Vec<String> = store.list(Some("data/"), None).await.unwrap().into_iter().map(|e| e.name).collect();
+    std::fs::remove_dir_all(&root).unwrap();
+    assert_eq!(names, vec!["data/stored"]);
+}